@@ -0,0 +1,542 @@
+//! Administration commands.
+//!
+//! Run instead of the bot to inspect and fix stored data without
+//! `redis-cli`, e.g. `tg-media-bot 127.0.0.1 aliases list -100123`.
+
+use crate::alias::{self, Target};
+use crate::cache::{self, CacheError, MediaCache};
+use crate::db::{RedisStorageError, Storage};
+use crate::settings::ChatSettings;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// Names of all administration commands.
+///
+/// Used to tell them apart from the Redis address argument.
+pub const COMMANDS: [&str; 6] = [
+    "aliases",
+    "export",
+    "import",
+    "purge-chat",
+    "dialogues",
+    "stats",
+];
+
+/// Usage of administration commands.
+pub const USAGE: &str = "\
+Administration commands (run instead of the bot):
+  aliases list <chat>                 print aliases and their stored targets
+  aliases remove <chat> <alias>...    remove aliases
+  export <chat>                       print aliases, settings and cached media as JSON
+  import <chat> [file]                add aliases and settings from JSON export (stdin by default)
+  purge-chat <chat>                   remove all data of the chat
+  dialogues reset <chat> [user]       reset dialogues of the user or all users in the chat
+  stats                               print numbers of chats, aliases and dialogues";
+
+#[derive(PartialEq, Debug)]
+pub enum AdminCommand {
+    ListAliases {
+        chat_id: i64,
+    },
+    RemoveAliases {
+        chat_id: i64,
+        aliases: Vec<String>,
+    },
+    Export {
+        chat_id: i64,
+    },
+    /// Read the export from the file (stdin if `None`).
+    Import {
+        chat_id: i64,
+        path: Option<String>,
+    },
+    PurgeChat {
+        chat_id: i64,
+    },
+    ResetDialogues {
+        chat_id: i64,
+        user_id: Option<i64>,
+    },
+    Stats,
+}
+
+impl AdminCommand {
+    /// Parse command from arguments following the Redis address.
+    pub fn parse(args: &[String]) -> Result<AdminCommand, String> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let command = match args.as_slice() {
+            ["aliases", "list", chat] => AdminCommand::ListAliases {
+                chat_id: parse_id(chat)?,
+            },
+            ["aliases", "remove", chat, aliases @ ..] if !aliases.is_empty() => {
+                AdminCommand::RemoveAliases {
+                    chat_id: parse_id(chat)?,
+                    aliases: aliases.iter().map(|a| a.to_string()).collect(),
+                }
+            }
+            ["export", chat] => AdminCommand::Export {
+                chat_id: parse_id(chat)?,
+            },
+            ["import", chat] => AdminCommand::Import {
+                chat_id: parse_id(chat)?,
+                path: None,
+            },
+            ["import", chat, path] => AdminCommand::Import {
+                chat_id: parse_id(chat)?,
+                path: Some(path.to_string()),
+            },
+            ["purge-chat", chat] => AdminCommand::PurgeChat {
+                chat_id: parse_id(chat)?,
+            },
+            ["dialogues", "reset", chat] => AdminCommand::ResetDialogues {
+                chat_id: parse_id(chat)?,
+                user_id: None,
+            },
+            ["dialogues", "reset", chat, user] => AdminCommand::ResetDialogues {
+                chat_id: parse_id(chat)?,
+                user_id: Some(parse_id(user)?),
+            },
+            ["stats"] => AdminCommand::Stats,
+            _ => return Err(format!("Unknown command: {}", args.join(" "))),
+        };
+        Ok(command)
+    }
+}
+
+fn parse_id(id: &str) -> Result<i64, String> {
+    id.parse().map_err(|_| format!("Invalid id: {}", id))
+}
+
+/// Chat data written by `export` and read by `import`.
+///
+/// Targets are stored in the database format. Files of media in the media
+/// cache are included, so another bot can upload them after the import
+/// when the exported file ids don't work for it.
+#[derive(Serialize, Deserialize, Default, PartialEq, Debug)]
+pub struct Export {
+    #[serde(default)]
+    pub aliases: BTreeMap<String, String>,
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+    /// File unique ids of media aliases.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub unique_ids: BTreeMap<String, String>,
+    /// Base64 encoded cached files by file unique ids.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<String, String>,
+}
+
+/// An error returned from `execute`.
+#[derive(Debug)]
+pub enum AdminError {
+    Storage(RedisStorageError),
+    /// Aliases could not be scanned (the reason is logged).
+    ScanFailed,
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Cache(CacheError),
+}
+
+impl std::fmt::Display for AdminError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminError::Storage(e) => write!(f, "storage error: {}", e),
+            AdminError::ScanFailed => write!(f, "could not scan aliases"),
+            AdminError::Io(e) => write!(f, "io error: {}", e),
+            AdminError::Json(e) => write!(f, "invalid json: {}", e),
+            AdminError::Cache(e) => write!(f, "media cache error: {}", e),
+        }
+    }
+}
+
+impl From<RedisStorageError> for AdminError {
+    fn from(e: RedisStorageError) -> Self {
+        AdminError::Storage(e)
+    }
+}
+
+/// Run the command and return its output.
+pub async fn execute(command: AdminCommand, db: &mut dyn Storage) -> Result<String, AdminError> {
+    let mut output = String::new();
+    match command {
+        AdminCommand::ListAliases { chat_id } => {
+            let mut pairs = scan_aliases(db, chat_id).await?;
+            pairs.sort();
+            for (alias, value) in pairs {
+                output.push_str(&format!("{}\t{}\n", alias, value));
+            }
+        }
+        AdminCommand::RemoveAliases { chat_id, aliases } => {
+            for alias in aliases {
+                match db.remove_alias(chat_id, &alias).await {
+                    Ok(()) => output.push_str(&format!("Removed '{}'\n", alias)),
+                    Err(RedisStorageError::AliasNotFound) => {
+                        output.push_str(&format!("'{}' is not assigned\n", alias))
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        AdminCommand::Export { chat_id } => {
+            let export = export(db, chat_id, cache::get()).await?;
+            output = serde_json::to_string_pretty(&export).map_err(AdminError::Json)?;
+            output.push('\n');
+        }
+        AdminCommand::Import { chat_id, path } => {
+            let data = match path {
+                Some(path) => std::fs::read_to_string(path),
+                None => std::io::read_to_string(std::io::stdin()),
+            }
+            .map_err(AdminError::Io)?;
+            let export: Export = serde_json::from_str(&data).map_err(AdminError::Json)?;
+            output = import(db, chat_id, export, cache::get()).await?;
+        }
+        AdminCommand::PurgeChat { chat_id } => {
+            db.purge_chat(chat_id).await?;
+            output.push_str(&format!("Removed all data of chat {}\n", chat_id));
+        }
+        AdminCommand::ResetDialogues { chat_id, user_id } => {
+            let removed = match user_id {
+                Some(user_id) => match db.remove_dialogue(chat_id, Some(user_id)).await {
+                    Ok(()) => 1,
+                    Err(RedisStorageError::DialogueNotFound) => 0,
+                    Err(e) => return Err(e.into()),
+                },
+                None => db.remove_chat_dialogues(chat_id).await?,
+            };
+            output.push_str(&format!("Reset {} dialogues\n", removed));
+        }
+        AdminCommand::Stats => output = stats(db).await?,
+    }
+    Ok(output)
+}
+
+/// Get pairs of alias and stored target, failing if the storage failed.
+async fn scan_aliases(
+    db: &mut dyn Storage,
+    chat_id: i64,
+) -> Result<Vec<(String, String)>, AdminError> {
+    db.scan_aliases(chat_id).await.ok_or(AdminError::ScanFailed)
+}
+
+/// Get aliases and settings of the chat with cached files of its media.
+async fn export(
+    db: &mut dyn Storage,
+    chat_id: i64,
+    cache: Option<&MediaCache>,
+) -> Result<Export, AdminError> {
+    let mut export = Export {
+        aliases: scan_aliases(db, chat_id).await?.into_iter().collect(),
+        settings: db.get_settings(chat_id).await?.into_iter().collect(),
+        ..Export::default()
+    };
+    for name in export.aliases.keys() {
+        let target = db.get_target(chat_id, name).await;
+        let unique_id = match target.as_ref().and_then(Target::unique_id) {
+            Some(unique_id) => unique_id.to_owned(),
+            None => continue,
+        };
+        if let Some(cache) = cache {
+            if !export.files.contains_key(&unique_id) {
+                if let Some(data) = cache.read(&unique_id).await.map_err(AdminError::Cache)? {
+                    export.files.insert(unique_id.clone(), base64::encode(data));
+                }
+            }
+        }
+        export.unique_ids.insert(name.clone(), unique_id);
+    }
+    Ok(export)
+}
+
+/// Save aliases and settings of `export` to the chat and its files to the
+/// media cache.
+///
+/// Existing aliases with the same names are overwritten, invalid aliases
+/// and settings are skipped.
+async fn import(
+    db: &mut dyn Storage,
+    chat_id: i64,
+    export: Export,
+    cache: Option<&MediaCache>,
+) -> Result<String, AdminError> {
+    let mut output = String::new();
+    let (mut imported, mut settings, mut files) = (0, 0, 0);
+    for (name, value) in &export.aliases {
+        if let Err(e) = alias::validate(name) {
+            output.push_str(&format!("Skipped '{}': {}\n", name, e));
+            continue;
+        }
+        let target = Target::decode(value).with_unique_id(export.unique_ids.get(name).cloned());
+        db.set_alias(chat_id, name, &target).await?;
+        imported += 1;
+    }
+    match cache {
+        Some(cache) => {
+            for (unique_id, data) in &export.files {
+                let data = match base64::decode(data) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        output.push_str(&format!("Skipped file '{}': {}\n", unique_id, e));
+                        continue;
+                    }
+                };
+                cache
+                    .write(unique_id, &data)
+                    .await
+                    .map_err(AdminError::Cache)?;
+                files += 1;
+            }
+        }
+        None if !export.files.is_empty() => output.push_str(&format!(
+            "Skipped {} files: {} is not set\n",
+            export.files.len(),
+            cache::DIR_VAR
+        )),
+        None => {}
+    }
+    for (name, value) in &export.settings {
+        if ChatSettings::default().set(name, value).is_err() {
+            output.push_str(&format!("Skipped setting {}={}\n", name, value));
+            continue;
+        }
+        db.set_setting(chat_id, name, value).await?;
+        settings += 1;
+    }
+    output.push_str(&format!(
+        "Imported {} aliases and {} settings\n",
+        imported, settings
+    ));
+    if files > 0 {
+        output.push_str(&format!("Saved {} files to the media cache\n", files));
+    }
+    Ok(output)
+}
+
+async fn stats(db: &mut dyn Storage) -> Result<String, AdminError> {
+    let chats = db.scan_chat_ids().await?;
+    let mut aliases = 0;
+    let mut chats_with_aliases = 0;
+    for chat_id in &chats {
+        let n = scan_aliases(db, *chat_id).await?.len();
+        aliases += n;
+        if n > 0 {
+            chats_with_aliases += 1;
+        }
+    }
+    let values = db.scan_dialogue_values().await?;
+    let mut states: HashMap<&str, usize> = HashMap::new();
+    let mut unreadable = 0;
+    for value in &values {
+        match crate::dialogue::stored::deserialize(value) {
+            Ok(dialogue) => *states.entry(dialogue.state_name()).or_default() += 1,
+            Err(_) => unreadable += 1,
+        }
+    }
+    let mut states: Vec<_> = states.into_iter().collect();
+    states.sort();
+
+    let mut output = format!(
+        "Chats: {}\nChats with aliases: {}\nAliases: {}\nDialogues: {}\n",
+        chats.len(),
+        chats_with_aliases,
+        aliases,
+        values.len()
+    );
+    for (state, n) in states {
+        output.push_str(&format!("  {}: {}\n", state, n));
+    }
+    if unreadable > 0 {
+        output.push_str(&format!("  unreadable: {}\n", unreadable));
+    }
+    Ok(output)
+}
+
+/// Connect to Redis, run the command and print its output.
+///
+/// Exits with non-zero code if the command failed.
+pub async fn run(redis_ip: &str, command: AdminCommand) {
+    let mut db = match crate::db::RedisConnection::new(redis_ip).await {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Could not connect to Redis: {}", e);
+            std::process::exit(1);
+        }
+    };
+    match execute(command, &mut db).await {
+        Ok(output) => print!("{}", output),
+        Err(e) => {
+            eprintln!("Command failed: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::MemoryStorage;
+    use crate::media::MediaType;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            AdminCommand::parse(&args("aliases list -100")),
+            Ok(AdminCommand::ListAliases { chat_id: -100 })
+        );
+        assert_eq!(
+            AdminCommand::parse(&args("aliases remove 1 a b")),
+            Ok(AdminCommand::RemoveAliases {
+                chat_id: 1,
+                aliases: vec!["a".to_owned(), "b".to_owned()]
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse(&args("import 1 chat.json")),
+            Ok(AdminCommand::Import {
+                chat_id: 1,
+                path: Some("chat.json".to_owned())
+            })
+        );
+        assert_eq!(
+            AdminCommand::parse(&args("dialogues reset 1 2")),
+            Ok(AdminCommand::ResetDialogues {
+                chat_id: 1,
+                user_id: Some(2)
+            })
+        );
+        assert_eq!(AdminCommand::parse(&args("stats")), Ok(AdminCommand::Stats));
+        assert!(AdminCommand::parse(&args("aliases remove 1")).is_err());
+        assert!(AdminCommand::parse(&args("export chat")).is_err());
+        assert!(AdminCommand::parse(&args("stats 1")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let mut db = MemoryStorage::default();
+        let sticker = Target::Media {
+            media_type: MediaType::Sticker,
+            file_id: "file".to_owned(),
+            unique_id: Some("unique".to_owned()),
+        };
+        db.set_alias(1, "cry", &sticker).await.unwrap();
+        db.set_alias(1, "hi", &Target::Text("hello".to_owned()))
+            .await
+            .unwrap();
+        db.set_setting(1, "matching", "ignorecase").await.unwrap();
+
+        let json = execute(AdminCommand::Export { chat_id: 1 }, &mut db)
+            .await
+            .unwrap();
+        let mut export: Export = serde_json::from_str(&json).unwrap();
+        assert_eq!(export.aliases["cry"], "file");
+        assert_eq!(export.aliases["hi"], "text:hello");
+        assert_eq!(export.settings["matching"], "ignorecase");
+
+        export
+            .aliases
+            .insert("bad alias".to_owned(), "file".to_owned());
+        export
+            .settings
+            .insert("max_media".to_owned(), "1000".to_owned());
+        export
+            .settings
+            .insert("unknown".to_owned(), "on".to_owned());
+        let output = import(&mut db, 2, export, None).await.unwrap();
+        assert!(output.contains("Skipped 'bad alias'"));
+        assert!(output.contains("Skipped setting max_media=1000"));
+        assert!(output.contains("Skipped setting unknown=on"));
+        assert!(output.contains("Imported 2 aliases and 1 settings"));
+        assert_eq!(
+            db.get_settings(2).await.unwrap(),
+            vec![("matching".to_owned(), "ignorecase".to_owned())]
+        );
+        let list = execute(AdminCommand::ListAliases { chat_id: 2 }, &mut db)
+            .await
+            .unwrap();
+        assert_eq!(list, "cry\tfile\nhi\ttext:hello\n");
+        assert_eq!(db.get_target(2, "cry").await, Some(sticker));
+    }
+
+    #[tokio::test]
+    async fn test_export_import_files() {
+        let dir = std::env::temp_dir().join(format!("tg-media-bot-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (old_cache, new_cache) = (
+            MediaCache::new(dir.join("old")),
+            MediaCache::new(dir.join("new")),
+        );
+        let mut db = MemoryStorage::default();
+        let sticker = |file_id: &str, unique_id: &str| Target::Media {
+            media_type: MediaType::Sticker,
+            file_id: file_id.to_owned(),
+            unique_id: Some(unique_id.to_owned()),
+        };
+        db.set_alias(1, "cry", &sticker("file", "unique"))
+            .await
+            .unwrap();
+        db.set_alias(1, "sob", &sticker("file", "unique"))
+            .await
+            .unwrap();
+        db.set_alias(1, "hi", &sticker("other", "other"))
+            .await
+            .unwrap();
+        old_cache.write("unique", b"sticker data").await.unwrap();
+
+        let export = export(&mut db, 1, Some(&old_cache)).await.unwrap();
+        assert_eq!(export.unique_ids.len(), 3);
+        assert_eq!(export.files.keys().collect::<Vec<_>>(), vec!["unique"]);
+
+        let json = serde_json::to_string(&export).unwrap();
+        let export: Export = serde_json::from_str(&json).unwrap();
+        let output = import(&mut db, 2, export, None).await.unwrap();
+        assert!(output.contains("Skipped 1 files"));
+        let export: Export = serde_json::from_str(&json).unwrap();
+        let output = import(&mut db, 2, export, Some(&new_cache)).await.unwrap();
+        assert!(output.contains("Saved 1 files"));
+        assert_eq!(
+            new_cache.read("unique").await.unwrap(),
+            Some(b"sticker data".to_vec())
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_purge_and_reset() {
+        let mut db = MemoryStorage::default();
+        db.set_alias(1, "hi", &Target::Text("hello".to_owned()))
+            .await
+            .unwrap();
+        db.set_dialogue_value(1, Some(10), "{}".to_owned())
+            .await
+            .unwrap();
+        db.set_dialogue_value(1, Some(11), "{}".to_owned())
+            .await
+            .unwrap();
+        db.set_dialogue_value(2, Some(10), "{}".to_owned())
+            .await
+            .unwrap();
+
+        let stats = execute(AdminCommand::Stats, &mut db).await.unwrap();
+        assert!(stats.contains("Chats: 2\n"));
+        assert!(stats.contains("Aliases: 1\n"));
+        assert!(stats.contains("unreadable: 3"));
+
+        let reset = AdminCommand::ResetDialogues {
+            chat_id: 1,
+            user_id: None,
+        };
+        assert_eq!(
+            execute(reset, &mut db).await.unwrap(),
+            "Reset 2 dialogues\n"
+        );
+
+        execute(AdminCommand::PurgeChat { chat_id: 1 }, &mut db)
+            .await
+            .unwrap();
+        assert_eq!(db.scan_chat_ids().await.unwrap(), vec![2]);
+    }
+}
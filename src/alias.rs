@@ -0,0 +1,432 @@
+//! Alias names.
+//!
+//! Validation and normalization rules for alias names.
+
+use crate::media::MediaType;
+use serde::{Deserialize, Serialize};
+use teloxide::types::{BotCommandScope, MediaKind, Message, MessageCommon, MessageKind};
+use unicode_normalization::UnicodeNormalization;
+
+/// Maximum length of alias in characters.
+pub const MAX_ALIAS_LENGTH: usize = 64;
+
+/// Reason why a name can't be used as an alias.
+#[derive(PartialEq, Debug)]
+pub enum AliasError {
+    TooLong,
+    /// Contains a character that can't appear inside `:alias:`.
+    InvalidChar(char),
+    /// Name of a bot command.
+    Reserved,
+}
+
+impl std::fmt::Display for AliasError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AliasError::TooLong => write!(f, "longer than {} characters", MAX_ALIAS_LENGTH),
+            AliasError::InvalidChar(c) => write!(f, "contains forbidden character '{}'", c),
+            AliasError::Reserved => write!(f, "reserved for a bot command"),
+        }
+    }
+}
+
+/// Characters used by any `Delimiter`.
+const DELIMITER_CHARS: [char; 4] = [':', ';', '[', ']'];
+
+/// Check whether `alias` can be assigned.
+///
+/// Alias must be matchable with any `Delimiter` (the chat may change
+/// it later), so delimiter characters, whitespace and control
+/// characters are forbidden.
+pub fn validate(alias: &str) -> Result<(), AliasError> {
+    if alias.chars().count() > MAX_ALIAS_LENGTH {
+        return Err(AliasError::TooLong);
+    }
+    if let Some(c) = alias
+        .chars()
+        .find(|c| DELIMITER_CHARS.contains(c) || c.is_whitespace() || c.is_control())
+    {
+        return Err(AliasError::InvalidChar(c));
+    }
+    let is_command = crate::commands::menu_commands(&BotCommandScope::Default)
+        .iter()
+        .any(|c| c.command.eq_ignore_ascii_case(alias));
+    if is_command {
+        return Err(AliasError::Reserved);
+    }
+    Ok(())
+}
+
+/// Bring `alias` to the stored form according to `matching` and validate it.
+pub fn prepare(alias: &str, matching: Matching) -> Result<String, AliasError> {
+    let alias = matching.normalize(alias);
+    validate(&alias)?;
+    Ok(alias)
+}
+
+/// Prefix of stored text snippets.
+const TEXT_PREFIX: &str = "text";
+
+/// What an alias expands to.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Target {
+    /// Media with given file id. File unique id is used for the reverse
+    /// index and may be unknown for aliases saved before it existed.
+    Media {
+        media_type: MediaType,
+        file_id: String,
+        unique_id: Option<String>,
+    },
+    /// Text snippet, possibly with template placeholders.
+    Text(String),
+}
+
+impl Target {
+    /// Create target from media of the message (if it has supported one).
+    pub fn from_message(message: &Message) -> Option<Self> {
+        let (media_type, file_id, unique_id) = match message.kind {
+            MessageKind::Common(MessageCommon {
+                media_kind: ref media,
+                ..
+            }) => match media {
+                MediaKind::Sticker(m) => (
+                    MediaType::Sticker,
+                    &m.sticker.file_id,
+                    &m.sticker.file_unique_id,
+                ),
+                // The last size is the largest one.
+                MediaKind::Photo(m) => {
+                    let photo = m.photo.last()?;
+                    (MediaType::Photo, &photo.file_id, &photo.file_unique_id)
+                }
+                MediaKind::Video(m) => {
+                    (MediaType::Video, &m.video.file_id, &m.video.file_unique_id)
+                }
+                MediaKind::Document(m) => (
+                    MediaType::Document,
+                    &m.document.file_id,
+                    &m.document.file_unique_id,
+                ),
+                MediaKind::Animation(m) => (
+                    MediaType::Animation,
+                    &m.animation.file_id,
+                    &m.animation.file_unique_id,
+                ),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(Target::Media {
+            media_type,
+            file_id: file_id.clone(),
+            unique_id: Some(unique_id.clone()),
+        })
+    }
+
+    /// Get the value stored in the database for the target.
+    ///
+    /// Values are "<type>:<content>", except for stickers which are
+    /// stored as plain file ids (as they were before other types existed).
+    /// Telegram file ids never contain colons, so there is no ambiguity.
+    pub fn encode(&self) -> String {
+        match self {
+            Target::Media {
+                media_type: MediaType::Sticker,
+                file_id,
+                ..
+            } => file_id.clone(),
+            Target::Media {
+                media_type,
+                file_id,
+                ..
+            } => format!("{}:{}", media_type.as_str(), file_id),
+            Target::Text(text) => format!("{}:{}", TEXT_PREFIX, text),
+        }
+    }
+
+    /// Get the target from a value stored in the database.
+    pub fn decode(value: &str) -> Self {
+        let media = |media_type, file_id: &str| Target::Media {
+            media_type,
+            file_id: file_id.to_owned(),
+            unique_id: None,
+        };
+        match value.split_once(':') {
+            Some((TEXT_PREFIX, text)) => Target::Text(text.to_owned()),
+            Some((name, file_id)) => match MediaType::from_name(name) {
+                Some(media_type) => media(media_type, file_id),
+                None => media(MediaType::Sticker, value),
+            },
+            None => media(MediaType::Sticker, value),
+        }
+    }
+
+    /// File unique id for the reverse index (if any).
+    pub fn unique_id(&self) -> Option<&str> {
+        match self {
+            Target::Media { unique_id, .. } => unique_id.as_deref(),
+            Target::Text(_) => None,
+        }
+    }
+
+    /// Set file unique id of the media target, which is stored apart
+    /// from the encoded value.
+    pub fn with_unique_id(self, unique_id: Option<String>) -> Self {
+        match self {
+            Target::Media {
+                media_type,
+                file_id,
+                ..
+            } => Target::Media {
+                media_type,
+                file_id,
+                unique_id,
+            },
+            text => text,
+        }
+    }
+
+    /// Key identifying the media while its file id changes.
+    ///
+    /// It's the file unique id, or the file id itself if the unique id
+    /// is unknown.
+    pub fn media_key(&self) -> Option<&str> {
+        match self {
+            Target::Media {
+                unique_id: Some(unique_id),
+                ..
+            } => Some(unique_id),
+            Target::Media { file_id, .. } => Some(file_id),
+            Target::Text(_) => None,
+        }
+    }
+
+    /// Media type of the target (if it is media).
+    pub fn media_type(&self) -> Option<MediaType> {
+        match self {
+            Target::Media { media_type, .. } => Some(*media_type),
+            Target::Text(_) => None,
+        }
+    }
+
+    /// Short description of the target for lists.
+    pub fn describe(&self) -> String {
+        match self {
+            Target::Media { media_type, .. } => media_type.as_str().to_owned(),
+            Target::Text(text) => format!("text: {}", preview(text)),
+        }
+    }
+}
+
+/// Maximum length of text snippet preview in characters.
+const PREVIEW_LENGTH: usize = 30;
+
+/// Shorten text snippet for showing in lists.
+fn preview(text: &str) -> String {
+    let mut chars = text.chars();
+    let mut preview: String = chars.by_ref().take(PREVIEW_LENGTH).collect();
+    if chars.next().is_some() {
+        preview.push('…');
+    }
+    preview
+}
+
+/// How aliases are compared in the chat.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Matching {
+    /// Aliases match only if they are the same.
+    #[default]
+    Exact,
+    /// Letter case is ignored.
+    IgnoreCase,
+    /// Letter case is ignored and Unicode compatibility characters
+    /// are replaced (NFKC), so "𝓬𝓻𝔂" and "CRY" match "cry".
+    Normalized,
+}
+
+impl Matching {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Matching::Exact => "exact",
+            Matching::IgnoreCase => "ignorecase",
+            Matching::Normalized => "normalized",
+        }
+    }
+
+    /// Bring `alias` to the form it's stored and looked up in.
+    pub fn normalize(&self, alias: &str) -> String {
+        match self {
+            Matching::Exact => alias.to_owned(),
+            Matching::IgnoreCase => alias.to_lowercase(),
+            Matching::Normalized => alias.nfkc().collect::<String>().to_lowercase(),
+        }
+    }
+}
+
+impl std::str::FromStr for Matching {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "exact" => Ok(Matching::Exact),
+            "ignorecase" => Ok(Matching::IgnoreCase),
+            "normalized" => Ok(Matching::Normalized),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How aliases are marked in messages.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Delimiter {
+    /// `:alias:`
+    #[default]
+    Colon,
+    /// `;alias;`
+    Semicolon,
+    /// `[alias]`
+    Bracket,
+}
+
+impl Delimiter {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Delimiter::Colon => "colon",
+            Delimiter::Semicolon => "semicolon",
+            Delimiter::Bracket => "bracket",
+        }
+    }
+
+    /// Pattern of alias usage in text, the alias is the first group.
+    pub fn pattern(&self) -> &'static str {
+        match self {
+            Delimiter::Colon => ":([^:\\s]+):",
+            Delimiter::Semicolon => ";([^;\\s]+);",
+            Delimiter::Bracket => "\\[([^\\[\\]\\s]+)\\]",
+        }
+    }
+
+    /// Mark `alias` the way it's used in messages.
+    pub fn wrap(&self, alias: &str) -> String {
+        match self {
+            Delimiter::Colon => format!(":{}:", alias),
+            Delimiter::Semicolon => format!(";{};", alias),
+            Delimiter::Bracket => format!("[{}]", alias),
+        }
+    }
+}
+
+impl std::str::FromStr for Delimiter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "colon" => Ok(Delimiter::Colon),
+            "semicolon" => Ok(Delimiter::Semicolon),
+            "bracket" => Ok(Delimiter::Bracket),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let cases = vec![
+            ("cry", Ok(())),
+            ("😭", Ok(())),
+            ("not:cry", Err(AliasError::InvalidChar(':'))),
+            ("not;cry", Err(AliasError::InvalidChar(';'))),
+            ("[cry", Err(AliasError::InvalidChar('['))),
+            ("tab\tcry", Err(AliasError::InvalidChar('\t'))),
+            ("help", Err(AliasError::Reserved)),
+            ("HELP", Err(AliasError::Reserved)),
+        ];
+        for (alias, target) in cases {
+            assert_eq!(validate(alias), target, "{}", alias);
+        }
+        assert_eq!(validate(&"a".repeat(MAX_ALIAS_LENGTH)), Ok(()));
+        assert_eq!(
+            validate(&"a".repeat(MAX_ALIAS_LENGTH + 1)),
+            Err(AliasError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_prepare() {
+        assert_eq!(prepare("Cry", Matching::IgnoreCase), Ok("cry".to_owned()));
+        // Presentation form of colon becomes a regular one.
+        assert_eq!(
+            prepare("a\u{FE13}b", Matching::Normalized),
+            Err(AliasError::InvalidChar(':'))
+        );
+        assert_eq!(
+            prepare("a\u{FE13}b", Matching::Exact),
+            Ok("a\u{FE13}b".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_target_encoding() {
+        let media = |media_type, file_id: &str| Target::Media {
+            media_type,
+            file_id: file_id.to_owned(),
+            unique_id: None,
+        };
+        let cases = vec![
+            (media(MediaType::Sticker, "CAACAgIAAxk"), "CAACAgIAAxk"),
+            (media(MediaType::Photo, "AgACAgIAAxk"), "photo:AgACAgIAAxk"),
+            (
+                media(MediaType::Animation, "CgACAgIAAxk"),
+                "animation:CgACAgIAAxk",
+            ),
+            (
+                Target::Text("Good morning!".to_owned()),
+                "text:Good morning!",
+            ),
+            (Target::Text("text: nested".to_owned()), "text:text: nested"),
+        ];
+        for (target, value) in cases {
+            assert_eq!(target.encode(), value);
+            assert_eq!(Target::decode(value), target);
+        }
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview("short"), "short");
+        assert_eq!(
+            preview(&"a".repeat(PREVIEW_LENGTH)),
+            "a".repeat(PREVIEW_LENGTH)
+        );
+        assert_eq!(
+            preview(&"😭".repeat(PREVIEW_LENGTH + 1)),
+            "😭".repeat(PREVIEW_LENGTH) + "…"
+        );
+    }
+
+    #[test]
+    fn test_normalize() {
+        let cases = vec![
+            (Matching::Exact, "Cry", "Cry"),
+            (Matching::IgnoreCase, "Cry", "cry"),
+            (Matching::IgnoreCase, "𝓬𝓻𝔂", "𝓬𝓻𝔂"),
+            (Matching::Normalized, "𝓬𝓻𝔂", "cry"),
+            (Matching::Normalized, "ＣＲＹ", "cry"),
+        ];
+        for (matching, alias, target) in cases {
+            assert_eq!(matching.normalize(alias), target);
+        }
+    }
+
+    #[test]
+    fn test_matching_from_str() {
+        for matching in [Matching::Exact, Matching::IgnoreCase, Matching::Normalized] {
+            assert_eq!(matching.as_str().parse(), Ok(matching));
+        }
+        assert_eq!("fuzzy".parse::<Matching>(), Err(()));
+    }
+}
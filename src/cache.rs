@@ -0,0 +1,313 @@
+//! Local media cache.
+//!
+//! Enabled by `MEDIA_CACHE_DIR`: media is downloaded to the directory when
+//! aliases are assigned to it, one file per file unique id. Unique ids are
+//! the same for all bots, so cached media can be uploaded again when its
+//! file id stops working or the bot runs with another token, and exports
+//! can include the files themselves.
+
+use crate::alias::Target;
+use crate::db::{RedisStorageError, Storage};
+use crate::media::send_file;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use teloxide::net::Download;
+use teloxide::prelude::*;
+use teloxide::types::InputFile;
+use teloxide::{DownloadError, RequestError};
+use tracing::Instrument;
+
+/// Environment variable with the cache directory (disabled if not set).
+pub const DIR_VAR: &str = "MEDIA_CACHE_DIR";
+
+static CACHE: Lazy<Option<MediaCache>> = Lazy::new(|| {
+    let dir = std::env::var_os(DIR_VAR).filter(|dir| !dir.is_empty())?;
+    Some(MediaCache::new(dir))
+});
+
+/// Cache configured by `MEDIA_CACHE_DIR` (if enabled).
+pub fn get() -> Option<&'static MediaCache> {
+    CACHE.as_ref()
+}
+
+/// Unique ids of media being downloaded by `store_later`.
+static PENDING: Lazy<std::sync::Mutex<HashSet<String>>> = Lazy::new(Default::default);
+
+/// Download media of the target in the background if the cache is enabled.
+///
+/// Does nothing if the media is already being downloaded.
+pub fn store_later(bot: AutoSend<Bot>, target: Target) {
+    let cache = match get() {
+        Some(cache) => cache,
+        None => return,
+    };
+    let unique_id = match target.unique_id() {
+        Some(unique_id) => unique_id.to_owned(),
+        None => return,
+    };
+    if !PENDING.lock().unwrap().insert(unique_id.clone()) {
+        return;
+    }
+    tokio::spawn(
+        async move {
+            match cache.store(&bot, &target).await {
+                Ok(true) => tracing::info!("Cached media"),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("Failed to cache media: {}", e),
+            }
+            PENDING.lock().unwrap().remove(&unique_id);
+        }
+        .in_current_span(),
+    );
+}
+
+/// Assign the alias to the target and cache its media in the background.
+///
+/// Aliases are assigned by the bot with this, so media of every alias
+/// gets cached.
+pub async fn set_alias(
+    bot: &AutoSend<Bot>,
+    db: &mut dyn Storage,
+    chat_id: i64,
+    alias: &str,
+    target: &Target,
+) -> Result<(), RedisStorageError> {
+    db.set_alias(chat_id, alias, target).await?;
+    store_later(bot.clone(), target.clone());
+    Ok(())
+}
+
+/// An error returned from `MediaCache` methods.
+#[derive(Debug)]
+pub enum CacheError {
+    /// File unique id can't be used as a file name.
+    InvalidId(String),
+    Request(RequestError),
+    Download(DownloadError),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheError::InvalidId(id) => write!(f, "invalid file unique id '{}'", id),
+            CacheError::Request(e) => write!(f, "request error: {}", e),
+            CacheError::Download(e) => write!(f, "download error: {}", e),
+            CacheError::Io(e) => write!(f, "io error: {}", e),
+        }
+    }
+}
+
+impl From<std::io::Error> for CacheError {
+    fn from(e: std::io::Error) -> Self {
+        CacheError::Io(e)
+    }
+}
+
+/// Directory with media files named by their file unique ids.
+pub struct MediaCache {
+    dir: PathBuf,
+}
+
+impl MediaCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        MediaCache { dir: dir.into() }
+    }
+
+    /// Path of the cached file of media with the unique id.
+    fn path(&self, unique_id: &str) -> Result<PathBuf, CacheError> {
+        // Unique ids are url-safe base64, anything else could point
+        // outside of the directory.
+        let valid = !unique_id.is_empty()
+            && unique_id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(CacheError::InvalidId(unique_id.to_owned()));
+        }
+        Ok(self.dir.join(unique_id))
+    }
+
+    /// Download media of the target unless it's already cached.
+    ///
+    /// Returns whether the file was downloaded. Text snippets and media
+    /// without unique id are skipped. Telegram only allows bots to
+    /// download files up to 20 MB.
+    pub async fn store(&self, bot: &AutoSend<Bot>, target: &Target) -> Result<bool, CacheError> {
+        let (file_id, unique_id) = match target {
+            Target::Media {
+                file_id,
+                unique_id: Some(unique_id),
+                ..
+            } => (file_id, unique_id),
+            _ => return Ok(false),
+        };
+        let path = self.path(unique_id)?;
+        if path.is_file() {
+            return Ok(false);
+        }
+        let file = bot.get_file(file_id).await.map_err(CacheError::Request)?;
+        let mut stream = bot.download_file_stream(&file.file_path);
+        let mut data = Vec::with_capacity(file.file_size as usize);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| CacheError::Download(DownloadError::NetworkError(e)))?;
+            data.extend_from_slice(&chunk);
+        }
+        self.write(unique_id, &data).await?;
+        Ok(true)
+    }
+
+    /// Contents of the cached file of media with the unique id (`None` if
+    /// it's not cached).
+    pub async fn read(&self, unique_id: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        match tokio::fs::read(self.path(unique_id)?).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Save contents of the file of media with the unique id.
+    pub async fn write(&self, unique_id: &str, data: &[u8]) -> Result<(), CacheError> {
+        let path = self.path(unique_id)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        // Written under another name first, so a partially written file
+        // is never uploaded.
+        let partial = path.with_extension("part");
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(())
+    }
+
+    /// Send cached media of the target to the chat by uploading the file.
+    ///
+    /// The media is sent as a reply to message `reply_to` (if any). Returns
+    /// the target with file id of the uploaded file (`None` if the media is
+    /// not cached). The unique id is kept, so the cached file is still
+    /// found by it.
+    pub async fn upload(
+        &self,
+        bot: &AutoSend<Bot>,
+        chat_id: i64,
+        target: &Target,
+        reply_to: Option<i32>,
+    ) -> Result<Option<Target>, CacheError> {
+        let (media_type, file_id, unique_id) = match target {
+            Target::Media {
+                media_type,
+                file_id,
+                unique_id: Some(unique_id),
+            } => (*media_type, file_id, unique_id),
+            _ => return Ok(None),
+        };
+        let path = self.path(unique_id)?;
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let message = send_file(bot, chat_id, media_type, InputFile::File(path), reply_to)
+            .await
+            .map_err(CacheError::Request)?;
+        let file_id = match Target::from_message(&message) {
+            Some(Target::Media { file_id, .. }) => file_id,
+            // Telegram sent it as another type of media.
+            _ => file_id.clone(),
+        };
+        Ok(Some(Target::Media {
+            media_type,
+            file_id,
+            unique_id: Some(unique_id.clone()),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::media::MediaType;
+    use crate::testing::*;
+
+    fn sticker(file_id: &str, unique_id: &str) -> Target {
+        Target::Media {
+            media_type: MediaType::Sticker,
+            file_id: file_id.to_owned(),
+            unique_id: Some(unique_id.to_owned()),
+        }
+    }
+
+    fn temp_cache(name: &str) -> MediaCache {
+        let dir = std::env::temp_dir().join(format!(
+            "tg-media-bot-cache-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        MediaCache::new(dir)
+    }
+
+    #[tokio::test]
+    async fn test_store_and_read() {
+        let api = FakeApi::start().await;
+        let bot = api.bot();
+        let cache = temp_cache("store");
+
+        assert!(cache.store(&bot, &sticker("file", "unique")).await.unwrap());
+        assert_eq!(
+            cache.read("unique").await.unwrap(),
+            Some(file_content("file").into_bytes())
+        );
+        // Already cached media is not downloaded again.
+        assert!(!cache.store(&bot, &sticker("file", "unique")).await.unwrap());
+        assert!(!cache
+            .store(&bot, &Target::Text("text".to_owned()))
+            .await
+            .unwrap());
+        assert_eq!(methods(&api.take_calls()), vec!["getFile"]);
+
+        assert_eq!(cache.read("missing").await.unwrap(), None);
+        assert!(matches!(
+            cache.read("../unique").await,
+            Err(CacheError::InvalidId(_))
+        ));
+        let _ = std::fs::remove_dir_all(&cache.dir);
+    }
+
+    #[tokio::test]
+    async fn test_upload() {
+        let h = Harness::new().await;
+        let cache = temp_cache("upload");
+        let target = sticker("dead-file", "unique");
+        assert_eq!(
+            cache.upload(&h.bot, CHAT_ID, &target, None).await.unwrap(),
+            None
+        );
+
+        cache.write("unique", b"sticker data").await.unwrap();
+        let uploaded = cache
+            .upload(&h.bot, CHAT_ID, &target, Some(1))
+            .await
+            .unwrap()
+            .unwrap();
+        // The unique id is kept to find the cached file.
+        assert_eq!(uploaded, sticker(UPLOADED_FILE_ID, "unique"));
+        let calls = h.api.take_calls();
+        assert_eq!(methods(&calls), vec!["sendSticker"]);
+        // The file is attached as another part of the request.
+        assert!(calls[0]
+            .params
+            .values()
+            .any(|value| value == "sticker data"));
+
+        // The new file id replaces the broken one like after sending.
+        let mut db = h.db.lock().await;
+        db.set_alias(CHAT_ID, "cry", &target).await.unwrap();
+        crate::media::refresh(&h.bot, &mut *db, CHAT_ID, &uploaded).await;
+        assert_eq!(db.get_target(CHAT_ID, "cry").await, Some(uploaded));
+        assert_eq!(
+            db.get_sticker_aliases(CHAT_ID, "unique").await,
+            Some(vec!["cry".to_owned()])
+        );
+        let _ = std::fs::remove_dir_all(&cache.dir);
+    }
+}
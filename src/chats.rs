@@ -0,0 +1,304 @@
+//! Chat lifecycle.
+//!
+//! Data of a group upgraded to a supergroup is moved to the new chat id.
+//! Data of chats the bot was removed from is kept for the retention
+//! period (`REMOVED_CHAT_RETENTION_DAYS`), then removed.
+
+use crate::db::{RedisStorageError, Storage};
+use crate::metrics;
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::types::{ChatMemberUpdated, MessageKind, MessageMigrate};
+use tokio::sync::Mutex;
+use tracing::Instrument;
+
+/// Environment variable with the number of days data of chats the bot
+/// was removed from is kept (0 - removed at the next cleanup).
+pub const RETENTION_VAR: &str = "REMOVED_CHAT_RETENTION_DAYS";
+
+const DEFAULT_RETENTION_DAYS: i64 = 30;
+
+/// Interval between cleanups of chats the bot was removed from and of
+/// stale dialogues.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Retention period in seconds.
+static RETENTION: Lazy<i64> = Lazy::new(|| {
+    let days = match std::env::var(RETENTION_VAR) {
+        Ok(days) => days.trim().parse().unwrap_or_else(|_| {
+            tracing::warn!("Invalid {} '{}'", RETENTION_VAR, days);
+            DEFAULT_RETENTION_DAYS
+        }),
+        Err(_) => DEFAULT_RETENTION_DAYS,
+    };
+    days.max(0) * 24 * 60 * 60
+});
+
+/// Move data of the chat if the message says it was migrated.
+///
+/// Returns whether it was a migration message.
+pub async fn handle_migration(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> bool {
+    // Both the old and the new chat get the message, the second one
+    // finds nothing to move.
+    let (from, to) = match &cx.update.kind {
+        MessageKind::Migrate(MessageMigrate {
+            migrate_from_chat_id,
+            migrate_to_chat_id,
+        }) => (*migrate_from_chat_id, *migrate_to_chat_id),
+        _ => return false,
+    };
+    tracing::info!("Chat {} was migrated to {}", from, to);
+    if let Err(e) = migrate(&mut *db.lock().await, from, to).await {
+        tracing::error!("Failed to migrate chat data: {}", e);
+    }
+    true
+}
+
+/// Move data, ban and removal time of chat `from` to chat `to`.
+pub async fn migrate(db: &mut dyn Storage, from: i64, to: i64) -> Result<(), RedisStorageError> {
+    if from == to {
+        return Ok(());
+    }
+    db.migrate_chat(from, to).await?;
+    if db.is_banned(from).await? {
+        db.set_banned(to, true).await?;
+        db.set_banned(from, false).await?;
+    }
+    let removed_at = db
+        .scan_removed()
+        .await?
+        .into_iter()
+        .find_map(|(id, time)| (id == from).then_some(time));
+    if removed_at.is_some() {
+        db.set_removed(to, removed_at).await?;
+        db.set_removed(from, None).await?;
+    }
+    Ok(())
+}
+
+/// Handle change of the bot's status in a chat.
+///
+/// Removal time is remembered when the bot is removed (or blocked in
+/// a private chat) and forgotten when it's added back.
+pub async fn handle_my_chat_member(
+    cx: UpdateWithCx<AutoSend<Bot>, ChatMemberUpdated>,
+    db: Arc<Mutex<dyn Storage>>,
+) {
+    crate::health::mark_update();
+    crate::metrics::UPDATES
+        .with_label_values(&["my_chat_member"])
+        .inc();
+    let update = cx.update;
+    let span =
+        crate::logging::update_span("my_chat_member", update.chat.id, Some(update.from.id), None);
+    async move {
+        let removed_at = match (
+            update.old_chat_member.kind.is_present(),
+            update.new_chat_member.kind.is_present(),
+        ) {
+            (true, false) => {
+                tracing::info!("Bot was removed from the chat");
+                Some(update.date)
+            }
+            (false, true) => {
+                tracing::info!("Bot was added to the chat");
+                None
+            }
+            _ => return,
+        };
+        let mut db = db.lock().await;
+        if let Err(e) = db.set_removed(update.chat.id, removed_at).await {
+            tracing::error!("Failed to save chat removal: {}", e);
+        }
+    }
+    .instrument(span)
+    .await
+}
+
+/// Remove data of chats the bot was removed from before `now - RETENTION`.
+///
+/// Returns number of removed chats.
+pub async fn cleanup(db: &mut dyn Storage, now: i64) -> Result<usize, RedisStorageError> {
+    let mut removed = 0;
+    for (chat_id, removed_at) in db.scan_removed().await? {
+        if removed_at + *RETENTION > now {
+            continue;
+        }
+        tracing::info!("Removing data of chat {} the bot was removed from", chat_id);
+        db.purge_chat(chat_id).await?;
+        db.set_removed(chat_id, None).await?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Count dialogues in progress in all chats.
+///
+/// The storage is locked for each chat separately.
+pub async fn count_active_dialogues(
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<usize, RedisStorageError> {
+    let chat_ids = db.lock().await.scan_chat_ids().await?;
+    let mut active = 0;
+    for chat_id in chat_ids {
+        let dialogues = db.lock().await.get_chat_dialogues(chat_id).await?;
+        active += dialogues.iter().filter(|d| d.is_active()).count();
+    }
+    Ok(active)
+}
+
+/// Start periodic cleanup of chats the bot was removed from and of
+/// dialogues not changed for `DIALOGUE_TTL_DAYS`.
+///
+/// `metrics::ACTIVE_DIALOGUES` is counted at the start and after each
+/// cleanup.
+pub fn start_cleanup(db: Arc<Mutex<dyn Storage>>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = chrono::Utc::now().timestamp();
+            match cleanup(&mut *db.lock().await, now).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Removed data of {} chats", n),
+                Err(e) => tracing::error!("Failed to clean up removed chats: {}", e),
+            }
+            match db.lock().await.remove_stale_dialogues(now).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Removed {} stale dialogues", n),
+                Err(e) => tracing::error!("Failed to remove stale dialogues: {}", e),
+            }
+            // Also corrects the count after dialogues were changed by
+            // other processes, e.g. the admin commands.
+            match count_active_dialogues(&db).await {
+                Ok(n) => metrics::ACTIVE_DIALOGUES.set(n as i64),
+                Err(e) => tracing::error!("Failed to count active dialogues: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alias::Target;
+    use crate::testing::*;
+    use serde_json::json;
+
+    const SUPERGROUP_ID: i64 = -1000000000100;
+
+    fn member_update(old_status: &str, new_status: &str) -> ChatMemberUpdated {
+        let bot = json!({"id": 1, "is_bot": true, "first_name": "Bot"});
+        serde_json::from_value(json!({
+            "chat": {"id": GROUP_ID, "type": "group", "title": "Group"},
+            "from": {"id": USER_ID, "is_bot": false, "first_name": "User"},
+            "date": 1000,
+            "old_chat_member": {"user": bot, "status": old_status},
+            "new_chat_member": {"user": bot, "status": new_status},
+        }))
+        .expect("chat member update json is valid")
+    }
+
+    #[tokio::test]
+    async fn test_migration_message() {
+        let h = Harness::new().await;
+        {
+            let mut db = h.db.lock().await;
+            let target = Target::Text("sad".to_owned());
+            db.set_alias(GROUP_ID, "cry", &target).await.unwrap();
+            db.set_setting(GROUP_ID, "reply", "on").await.unwrap();
+            db.set_banned(GROUP_ID, true).await.unwrap();
+        }
+        let mut message = message_json(
+            1,
+            json!({"migrate_to_chat_id": SUPERGROUP_ID, "migrate_from_chat_id": GROUP_ID}),
+        );
+        message["chat"] = json!({"id": GROUP_ID, "type": "group", "title": "Group"});
+        let calls = h.send(serde_json::from_value(message).unwrap()).await;
+        assert!(calls.is_empty());
+
+        let mut db = h.db.lock().await;
+        assert!(db.get_target(GROUP_ID, "cry").await.is_none());
+        assert!(db.get_target(SUPERGROUP_ID, "cry").await.is_some());
+        assert_eq!(
+            db.get_settings(SUPERGROUP_ID).await.unwrap(),
+            vec![("reply".to_owned(), "on".to_owned())]
+        );
+        assert!(db.is_banned(SUPERGROUP_ID).await.unwrap());
+        assert!(!db.is_banned(GROUP_ID).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_keeps_new_data() {
+        let h = Harness::new().await;
+        let mut db = h.db.lock().await;
+        let old = Target::Text("old".to_owned());
+        let new = Target::Text("new".to_owned());
+        db.set_alias(GROUP_ID, "a", &old).await.unwrap();
+        db.set_alias(GROUP_ID, "b", &old).await.unwrap();
+        db.set_alias(SUPERGROUP_ID, "a", &new).await.unwrap();
+        migrate(&mut *db, GROUP_ID, SUPERGROUP_ID).await.unwrap();
+        assert_eq!(db.get_target(SUPERGROUP_ID, "a").await, Some(new));
+        assert_eq!(db.get_target(SUPERGROUP_ID, "b").await, Some(old));
+        assert_eq!(db.scan_chat_ids().await.unwrap(), vec![SUPERGROUP_ID]);
+    }
+
+    #[tokio::test]
+    async fn test_removal_cleanup() {
+        let h = Harness::new().await;
+        h.db.lock()
+            .await
+            .set_alias(GROUP_ID, "cry", &Target::Text("sad".to_owned()))
+            .await
+            .unwrap();
+        let update = |old, new| UpdateWithCx {
+            requester: h.bot.clone(),
+            update: member_update(old, new),
+        };
+
+        handle_my_chat_member(update("member", "left"), h.db.clone()).await;
+        let mut db = h.db.lock().await;
+        assert_eq!(db.scan_removed().await.unwrap(), vec![(GROUP_ID, 1000)]);
+        assert_eq!(cleanup(&mut *db, 1000 + *RETENTION - 1).await.unwrap(), 0);
+        drop(db);
+
+        // Added back before the retention period ended.
+        handle_my_chat_member(update("left", "member"), h.db.clone()).await;
+        let mut db = h.db.lock().await;
+        assert!(db.scan_removed().await.unwrap().is_empty());
+        drop(db);
+
+        handle_my_chat_member(update("member", "left"), h.db.clone()).await;
+        let mut db = h.db.lock().await;
+        assert_eq!(cleanup(&mut *db, 1000 + *RETENTION).await.unwrap(), 1);
+        assert!(db.get_target(GROUP_ID, "cry").await.is_none());
+        assert!(db.scan_removed().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stale_dialogues() {
+        let h = Harness::new().await;
+        h.set_state(crate::dialogue::Dialogue::default()).await;
+        let now = chrono::Utc::now().timestamp();
+        let mut db = h.db.lock().await;
+        assert_eq!(db.remove_stale_dialogues(now).await.unwrap(), 0);
+        let later = now + 365 * 24 * 60 * 60;
+        assert_eq!(db.remove_stale_dialogues(later).await.unwrap(), 1);
+        drop(db);
+        assert_eq!(h.state().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_count_active_dialogues() {
+        let h = Harness::new().await;
+        h.set_state(crate::dialogue::Dialogue::default()).await;
+        assert_eq!(count_active_dialogues(&h.db).await.unwrap(), 0);
+        h.send_text("/add").await;
+        assert_eq!(count_active_dialogues(&h.db).await.unwrap(), 1);
+    }
+}
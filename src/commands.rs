@@ -1,19 +1,184 @@
 //! Telegram commands.
 //!
 //! Defines all available commands and gives implementations for some of them.
+use crate::alias::{self, Matching, Target};
+use crate::cache;
+use crate::db::{RedisStorageError, Storage};
+use crate::media::{send_media, MediaType};
+use crate::schedule::{handle_schedule, handle_schedules};
+use crate::search::SuggestionMode;
+use crate::settings::{self, handle_settings, ChatSettings};
+use crate::trigger::{handle_trigger, handle_triggers};
 use std::collections::HashMap;
-use teloxide::prelude::{AutoSend, Bot, Message, UpdateWithCx};
+use std::sync::Arc;
+use teloxide::payloads::setters::*;
+use teloxide::prelude::{AutoSend, Bot, GetChatId, Message, Requester, UpdateWithCx};
+use teloxide::types::BotCommandScope;
 use teloxide::utils::command::BotCommand;
+use tokio::sync::Mutex;
 
 #[derive(BotCommand, Debug)]
-#[command(rename = "lowercase")]
+#[command(rename = "lowercase", description = "Commands:")]
 pub enum Command {
+    #[command(description = "show start message")]
     Start,
+    #[command(description = "show this message")]
     Help,
+    #[command(description = "list assigned aliases")]
     List,
+    #[command(description = "find aliases by partial name")]
+    Find(String),
+    #[command(description = "hints for unknown aliases: off, chat or private")]
+    Suggestions(String),
+    #[command(description = "alias matching: exact, ignorecase or normalized")]
+    Matching(String),
+    #[command(description = "repost messages with text aliases substituted: on or off")]
+    Rewrite(String),
+    #[command(description = "show and change chat settings")]
+    Settings,
+    #[command(description = "add new alias to media or text")]
     Add,
+    #[command(description = "remove aliases")]
     Remove,
+    #[command(
+        description = "reply to media to see its aliases: /aliases [add|remove <aliases...>]"
+    )]
+    Aliases(String),
+    #[command(description = "rename alias: /rename <old> <new>")]
+    Rename(String),
+    #[command(description = "point aliases to one sticker: /merge <alias> <aliases...>")]
+    Merge(String),
+    #[command(
+        description = "post aliases at a time or on a cron schedule (UTC): /schedule <time> <aliases...>"
+    )]
+    Schedule(String),
+    #[command(description = "list and cancel scheduled posts")]
+    Schedules,
+    #[command(
+        description = "send alias when a message has a word, phrase or /regex/: /trigger <pattern> <alias> [chance%] [cooldown]"
+    )]
+    Trigger(String),
+    #[command(description = "list and delete triggers")]
+    Triggers,
+    #[command(description = "cancel addition or removal process")]
     Cancel,
+    #[command(
+        description = "delete your unfinished actions in all chats and data of our private chat"
+    )]
+    ForgetMe,
+    #[command(description = "delete all aliases and settings of the chat: /purge confirm")]
+    Purge(String),
+}
+
+impl Command {
+    /// Lowercase name of the command without arguments.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Start => "start",
+            Command::Help => "help",
+            Command::List => "list",
+            Command::Find(_) => "find",
+            Command::Suggestions(_) => "suggestions",
+            Command::Matching(_) => "matching",
+            Command::Rewrite(_) => "rewrite",
+            Command::Settings => "settings",
+            Command::Add => "add",
+            Command::Remove => "remove",
+            Command::Aliases(_) => "aliases",
+            Command::Rename(_) => "rename",
+            Command::Merge(_) => "merge",
+            Command::Schedule(_) => "schedule",
+            Command::Schedules => "schedules",
+            Command::Trigger(_) => "trigger",
+            Command::Triggers => "triggers",
+            Command::Cancel => "cancel",
+            Command::ForgetMe => "forgetme",
+            Command::Purge(_) => "purge",
+        }
+    }
+
+    /// Whether the command changes aliases or settings of the chat.
+    ///
+    /// Such commands are subject to the `permissions` setting.
+    pub fn changes_chat(&self) -> bool {
+        match self {
+            Command::Add
+            | Command::Remove
+            | Command::Rename(_)
+            | Command::Merge(_)
+            | Command::Schedule(_)
+            | Command::Trigger(_) => true,
+            // /suggestions, /matching and /rewrite check it themselves
+            // before saving.
+            Command::Aliases(arg) => !arg.trim().is_empty(),
+            _ => false,
+        }
+    }
+}
+
+/// Scopes the command menus are published for.
+///
+/// Listed from the most general to the most specific one, so the
+/// registration order matches Telegram's lookup fallback.
+const MENU_SCOPES: [BotCommandScope; 4] = [
+    BotCommandScope::Default,
+    BotCommandScope::AllPrivateChats,
+    BotCommandScope::AllGroupChats,
+    BotCommandScope::AllChatAdministrators,
+];
+
+/// Whether the command is shown in the command menu of given scope.
+///
+/// Only affects the menu, every command is still handled everywhere.
+fn shown_in(command: &str, scope: &BotCommandScope) -> bool {
+    match scope {
+        // Start message is meant for the first private conversation.
+        BotCommandScope::AllGroupChats => !matches!(
+            command,
+            "start"
+                | "remove"
+                | "suggestions"
+                | "matching"
+                | "rewrite"
+                | "settings"
+                | "schedule"
+                | "schedules"
+                | "trigger"
+                | "triggers"
+                | "forgetme"
+                | "purge"
+        ),
+        BotCommandScope::AllChatAdministrators => !matches!(command, "start" | "forgetme"),
+        _ => true,
+    }
+}
+
+/// Get commands with their descriptions for the menu of given scope.
+///
+/// The list is built from `Command::descriptions()`, so the menu and
+/// /help message can't diverge.
+pub fn menu_commands(scope: &BotCommandScope) -> Vec<teloxide::types::BotCommand> {
+    Command::descriptions()
+        .lines()
+        .filter_map(|line| line.strip_prefix('/'))
+        .filter_map(|line| line.split_once(" - "))
+        .filter(|(command, _)| shown_in(command, scope))
+        .map(|(command, description)| teloxide::types::BotCommand::new(command, description))
+        .collect()
+}
+
+/// Publish command menus for every scope in `MENU_SCOPES`.
+///
+/// Failures are only logged, the bot is usable without the menu.
+pub async fn register_commands(bot: &AutoSend<Bot>) {
+    for scope in MENU_SCOPES {
+        let commands = menu_commands(&scope);
+        let result = bot.set_my_commands(commands).scope(scope.clone()).await;
+        match result {
+            Ok(_) => tracing::info!("Registered commands for {:?}", scope),
+            Err(e) => tracing::error!("Failed to register commands for {:?}: {}", scope, e),
+        }
+    }
 }
 
 /// Write start message in given context.
@@ -21,11 +186,14 @@ pub async fn handle_start(
     cx: &UpdateWithCx<AutoSend<Bot>, Message>,
 ) -> Result<(), teloxide::RequestError> {
     cx.answer(
-        "Hello, I send stickers when I see their specified \
+        "Hello, I send stickers and other media when I see their specified \
     names in messages.\n\
-    To assign an alias to the sticker write /add and follow instructions. \n\
+    To assign an alias to the sticker (or photo, video, GIF, file) write /add \
+    and follow instructions. \n\
     Then put an alias inside colons  (:alias:) inside a message and bot will\
-    send associated sticker.\n\
+    send associated media.\n\
+    Aliases can also be assigned to text snippets, which may contain \
+    {sender}, {date} and {reply} placeholders.\n\
     For more info and commands see /help. \n\n\
     Note: I can properly work in groups only if given admin permissions, \
     otherwise I can't see most messages (apart from bot commands, mentions\
@@ -39,16 +207,417 @@ pub async fn handle_start(
 pub async fn handle_help(
     cx: &UpdateWithCx<AutoSend<Bot>, Message>,
 ) -> Result<(), teloxide::RequestError> {
-    cx.answer(
-        "Commands:\n\
-    /add - add new alias to sticker\n\
-    /remove - remove aliases\n\
-    /cancel - cancel addition process\n\
-    /start - show start message\n\
-    /help - show this message\n\
-    /list - list assigned aliases",
-    )
-    .await?;
+    cx.answer(Command::descriptions()).await?;
+    Ok(())
+}
+
+/// Maximum number of media sent in response to /find.
+const MAX_FOUND_MEDIA: usize = 5;
+
+/// Write aliases matching `query` and send their media.
+///
+/// `aliases` are alias-sticker pairs of the chat.
+pub async fn handle_find(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    query: &str,
+    aliases: Vec<(String, String)>,
+) -> Result<(), teloxide::RequestError> {
+    let query = query.trim();
+    if query.is_empty() {
+        cx.answer("Specify text to search for: /find <text>")
+            .await?;
+        return Ok(());
+    }
+    let found = crate::search::find(query, &aliases, |(alias, _)| alias);
+    if found.is_empty() {
+        cx.answer(format!("No aliases similar to '{}' were found.", query))
+            .await?;
+        return Ok(());
+    }
+
+    let mut message = String::from("Found aliases:\n");
+    let mut media: Vec<(MediaType, String)> = vec![];
+    for (alias, value) in found {
+        let target = Target::decode(value);
+        message.push_str(&format!("{} ({})\n", alias, target.describe()));
+        if let Target::Media {
+            media_type,
+            file_id,
+            ..
+        } = target
+        {
+            if media.len() < MAX_FOUND_MEDIA && !media.iter().any(|(_, id)| *id == file_id) {
+                media.push((media_type, file_id));
+            }
+        }
+    }
+    cx.answer(message).await?;
+    for (media_type, file_id) in media {
+        send_media(&cx.requester, cx.chat_id(), media_type, &file_id, None).await?;
+    }
+    Ok(())
+}
+
+/// Check whether the sender may change settings of the chat, notify them
+/// if not.
+async fn permitted(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<bool, teloxide::RequestError> {
+    let from_id = cx.update.from().map(|u| u.id);
+    if settings::can_change(&cx.requester, &cx.update.chat, from_id, db).await {
+        return Ok(true);
+    }
+    tracing::info!("Command is not permitted");
+    cx.answer(settings::PERMISSION_NOTICE).await?;
+    Ok(false)
+}
+
+/// Name of the chat setting storing `SuggestionMode`.
+pub const SUGGESTIONS_SETTING: &str = "suggestions";
+
+/// Show or change suggestion mode of the chat.
+///
+/// Empty `arg` shows the current mode, otherwise it's parsed as a new one.
+pub async fn handle_suggestions(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    arg: &str,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let chat_id = cx.chat_id();
+    if arg.trim().is_empty() {
+        let mode = get_suggestion_mode(&mut *db.lock().await, chat_id).await;
+        cx.answer(format!(
+            "Suggestions for unknown aliases: {}. \
+            Use /suggestions off|chat|private to change.",
+            mode.as_str()
+        ))
+        .await?;
+        return Ok(());
+    }
+    if !permitted(cx, db).await? {
+        return Ok(());
+    }
+    match arg.parse::<SuggestionMode>() {
+        Ok(mode) => match db
+            .lock()
+            .await
+            .set_setting(chat_id, SUGGESTIONS_SETTING, mode.as_str())
+            .await
+        {
+            Ok(()) => {
+                cx.answer(format!("Suggestions mode is set to {}.", mode.as_str()))
+                    .await?;
+            }
+            Err(_) => {
+                cx.answer("Failed to save the mode, try again later.")
+                    .await?;
+            }
+        },
+        Err(()) => {
+            cx.answer("Unknown mode. Use one of: off, chat, private.")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Get suggestion mode of the chat, falling back to the default one.
+pub async fn get_suggestion_mode(db: &mut dyn Storage, chat_id: i64) -> SuggestionMode {
+    ChatSettings::load(db, chat_id).await.suggestions
+}
+
+/// Name of the chat setting storing alias `Matching`.
+pub const MATCHING_SETTING: &str = "matching";
+
+/// Show or change alias matching of the chat.
+///
+/// Empty `arg` shows the current matching, otherwise it's parsed as a new one.
+pub async fn handle_matching(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    arg: &str,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let chat_id = cx.chat_id();
+    if arg.trim().is_empty() {
+        let matching = get_matching(&mut *db.lock().await, chat_id).await;
+        cx.answer(format!(
+            "Alias matching: {}. \
+            Use /matching exact|ignorecase|normalized to change.",
+            matching.as_str()
+        ))
+        .await?;
+        return Ok(());
+    }
+    if !permitted(cx, db).await? {
+        return Ok(());
+    }
+    match arg.parse::<Matching>() {
+        Ok(matching) => match db
+            .lock()
+            .await
+            .set_setting(chat_id, MATCHING_SETTING, matching.as_str())
+            .await
+        {
+            Ok(()) => {
+                cx.answer(format!(
+                    "Alias matching is set to {}. It applies to aliases added from now on.",
+                    matching.as_str()
+                ))
+                .await?;
+            }
+            Err(_) => {
+                cx.answer("Failed to save the matching, try again later.")
+                    .await?;
+            }
+        },
+        Err(()) => {
+            cx.answer("Unknown matching. Use one of: exact, ignorecase, normalized.")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Get alias matching of the chat, falling back to the default one.
+pub async fn get_matching(db: &mut dyn Storage, chat_id: i64) -> Matching {
+    ChatSettings::load(db, chat_id).await.matching
+}
+
+/// Stored name of the existing alias the user typed as `alias`.
+///
+/// Aliases added before matching was changed are stored as is, so `alias`
+/// is kept if it's found and normalized according to `matching` otherwise.
+pub async fn stored_alias(
+    db: &mut dyn Storage,
+    chat_id: i64,
+    alias: &str,
+    matching: Matching,
+) -> String {
+    if db.get_target(chat_id, alias).await.is_some() {
+        alias.to_owned()
+    } else {
+        matching.normalize(alias)
+    }
+}
+
+/// Name of the chat setting storing whether messages are rewritten.
+pub const REWRITE_SETTING: &str = "rewrite";
+
+/// Show or change whether messages with text aliases are rewritten.
+///
+/// Empty `arg` shows the current value, otherwise it's parsed as "on" or "off".
+pub async fn handle_rewrite(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    arg: &str,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let chat_id = cx.chat_id();
+    let on_off = |rewrite: bool| if rewrite { "on" } else { "off" };
+    let rewrite = match arg.trim().to_lowercase().as_str() {
+        "" => {
+            let rewrite = get_rewrite(&mut *db.lock().await, chat_id).await;
+            cx.answer(format!(
+                "Rewriting messages with text aliases: {}. \
+                Use /rewrite on|off to change.",
+                on_off(rewrite)
+            ))
+            .await?;
+            return Ok(());
+        }
+        "on" => true,
+        "off" => false,
+        _ => {
+            cx.answer("Unknown value. Use one of: on, off.").await?;
+            return Ok(());
+        }
+    };
+    if !permitted(cx, db).await? {
+        return Ok(());
+    }
+    match db
+        .lock()
+        .await
+        .set_setting(chat_id, REWRITE_SETTING, on_off(rewrite))
+        .await
+    {
+        Ok(()) if rewrite => {
+            cx.answer(
+                "Messages with text aliases will be reposted with the aliases substituted. \
+                It works only if I'm allowed to delete messages. Only text snippets are \
+                substituted in plain text messages, formatted messages and captions are \
+                answered as usual and custom emoji are not supported.",
+            )
+            .await?;
+        }
+        Ok(()) => {
+            cx.answer("Messages won't be rewritten.").await?;
+        }
+        Err(_) => {
+            cx.answer("Failed to save the setting, try again later.")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Get whether messages with text aliases are rewritten in the chat.
+pub async fn get_rewrite(db: &mut dyn Storage, chat_id: i64) -> bool {
+    ChatSettings::load(db, chat_id).await.rewrite
+}
+
+/// Show or edit aliases of the media the command replies to.
+///
+/// `args` are either empty (show), "add <aliases...>" or
+/// "remove <aliases...>". Aliases of other stickers are not removed.
+pub async fn handle_aliases(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    args: &str,
+    db: &mut dyn Storage,
+) -> Result<(), teloxide::RequestError> {
+    let chat_id = cx.chat_id();
+    let target = match cx.update.reply_to_message().and_then(Target::from_message) {
+        Some(target) => target,
+        None => {
+            cx.answer("Reply to a sticker or other media with /aliases to see its aliases.")
+                .await?;
+            return Ok(());
+        }
+    };
+    let unique_id = match target.unique_id() {
+        Some(unique_id) => unique_id,
+        None => return Ok(()),
+    };
+
+    let mut words = args.split_whitespace();
+    match words.next() {
+        None => {}
+        Some("add") => {
+            let matching = get_matching(db, chat_id).await;
+            let mut errors = String::new();
+            for alias in words {
+                match alias::prepare(alias, matching) {
+                    // Failures are already logged, the result is shown below.
+                    Ok(alias) => {
+                        let _ = cache::set_alias(&cx.requester, db, chat_id, &alias, &target).await;
+                    }
+                    Err(e) => errors.push_str(&format!("'{}' is not added: {}\n", alias, e)),
+                }
+            }
+            if !errors.is_empty() {
+                cx.answer(errors).await?;
+            }
+        }
+        Some("remove") => {
+            let matching = get_matching(db, chat_id).await;
+            let current = db
+                .get_sticker_aliases(chat_id, unique_id)
+                .await
+                .unwrap_or_default();
+            for alias in words {
+                let alias = stored_alias(db, chat_id, alias, matching).await;
+                if current.contains(&alias) {
+                    // Not found alias is already logged and is not important here.
+                    let _ = db.remove_alias(chat_id, &alias).await;
+                }
+            }
+        }
+        Some(_) => {
+            cx.answer("Usage: /aliases [add|remove <aliases...>]")
+                .await?;
+            return Ok(());
+        }
+    }
+
+    match db.get_sticker_aliases(chat_id, unique_id).await {
+        Some(aliases) if aliases.is_empty() => {
+            cx.answer("The media has no aliases. Add them with /aliases add <aliases...>")
+                .await?;
+        }
+        Some(aliases) => {
+            cx.answer(format!("Media aliases: {}", aliases.join(" ")))
+                .await?;
+        }
+        None => {
+            cx.answer("Failed to get the aliases, try again later.")
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Rename an alias given `args` in form "<old> <new>".
+pub async fn handle_rename(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    args: &str,
+    db: &mut dyn Storage,
+) -> Result<(), teloxide::RequestError> {
+    let (old, new) = match args.split_whitespace().collect::<Vec<&str>>()[..] {
+        [old, new] => (old, new),
+        _ => {
+            cx.answer("Usage: /rename <old> <new>").await?;
+            return Ok(());
+        }
+    };
+    let matching = get_matching(db, cx.chat_id()).await;
+    let old = stored_alias(db, cx.chat_id(), old, matching).await;
+    let new = match alias::prepare(new, matching) {
+        Ok(new) => new,
+        Err(e) => {
+            cx.answer(format!("'{}' can't be an alias: {}", new, e))
+                .await?;
+            return Ok(());
+        }
+    };
+    let message = match db.rename_alias(cx.chat_id(), &old, &new).await {
+        Ok(()) => format!("Renamed '{}' to '{}'.", old, new),
+        Err(RedisStorageError::AliasNotFound) => format!("Alias '{}' was not found.", old),
+        Err(RedisStorageError::AliasExists) => format!(
+            "Alias '{}' already exists. Remove it first if you want to replace it.",
+            new
+        ),
+        Err(_) => String::from("Failed to rename the alias, try again later."),
+    };
+    cx.answer(message).await?;
+    Ok(())
+}
+
+/// Point aliases to one sticker given `args` in form "<alias> <aliases...>".
+///
+/// All aliases receive the sticker of the first one.
+pub async fn handle_merge(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    args: &str,
+    db: &mut dyn Storage,
+) -> Result<(), teloxide::RequestError> {
+    let (target, aliases) = match args.split_whitespace().collect::<Vec<&str>>()[..] {
+        [target, ref aliases @ ..] if !aliases.is_empty() => (target, aliases.to_vec()),
+        _ => {
+            cx.answer("Usage: /merge <alias> <aliases...>").await?;
+            return Ok(());
+        }
+    };
+    let matching = get_matching(db, cx.chat_id()).await;
+    let target = stored_alias(db, cx.chat_id(), target, matching).await;
+    let aliases: Vec<String> = match aliases
+        .iter()
+        .map(|a| alias::prepare(a, matching).map_err(|e| (a, e)))
+        .collect()
+    {
+        Ok(aliases) => aliases,
+        Err((alias, e)) => {
+            cx.answer(format!("'{}' can't be an alias: {}", alias, e))
+                .await?;
+            return Ok(());
+        }
+    };
+    let aliases: Vec<&str> = aliases.iter().map(String::as_str).collect();
+    let message = match db.merge_aliases(cx.chat_id(), &target, &aliases).await {
+        Ok(n) => format!("{} aliases now point to the sticker of '{}'.", n, target),
+        Err(RedisStorageError::AliasNotFound) => format!("Alias '{}' was not found.", target),
+        Err(_) => String::from("Failed to merge the aliases, try again later."),
+    };
+    cx.answer(message).await?;
     Ok(())
 }
 
@@ -62,11 +631,11 @@ pub async fn handle_list<T: AsRef<str>>(
         message.push_str("No aliases were found.");
     } else {
         message.push_str(
-            "Aliases for each sticker are \
+            "Aliases for each media or text are \
         in separate lines starting with \
         \">\". Currently assigned aliases:\n",
         );
-        for (_sticker, aliases) in stickers_aliases {
+        for (target, aliases) in stickers_aliases {
             let mut next_line = String::new();
             message.push_str("> ");
 
@@ -74,6 +643,7 @@ pub async fn handle_list<T: AsRef<str>>(
                 next_line.push_str(alias.as_ref());
                 next_line.push(' ');
             }
+            next_line.push_str(&format!("({})", Target::decode(target.as_ref()).describe()));
 
             message.push_str(&next_line);
             message.push('\n');
@@ -82,3 +652,251 @@ pub async fn handle_list<T: AsRef<str>>(
     cx.answer(message).await?;
     Ok(())
 }
+
+/// Delete personal data of the user who sent the message.
+///
+/// Removes dialogues of the user in every chat and everything stored
+/// for the private chat with the user.
+pub async fn handle_forget_me(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    db: &mut dyn Storage,
+) -> Result<(), teloxide::RequestError> {
+    let user_id = match cx.update.from() {
+        Some(user) => user.id,
+        None => {
+            cx.answer("Can't tell who sent the command.").await?;
+            return Ok(());
+        }
+    };
+    let result = async {
+        db.remove_user_dialogues(user_id).await?;
+        // Id of the private chat is the user id.
+        db.purge_chat(user_id).await?;
+        db.set_removed(user_id, None).await
+    };
+    let message = match result.await {
+        Ok(()) => {
+            "Your unfinished actions in all chats and aliases and settings \
+        of our private chat are deleted. Aliases you added to group chats \
+        belong to these chats and are kept."
+        }
+        Err(e) => {
+            tracing::error!("Failed to forget user: {}", e);
+            "Failed to delete your data, try again later."
+        }
+    };
+    cx.answer(message).await?;
+    Ok(())
+}
+
+/// Delete everything stored for the chat if `arg` is "confirm".
+///
+/// Only administrators (and bot owners) can purge group chats.
+pub async fn handle_purge(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    arg: &str,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let from_id = cx.update.from().map(|u| u.id);
+    let allowed = cx.update.chat.is_private()
+        || crate::owner::is_owner(from_id)
+        || match from_id {
+            Some(id) => crate::settings::is_admin(&cx.requester, cx.chat_id(), id).await,
+            None => false,
+        };
+    if !allowed {
+        cx.answer("Only administrators can purge the chat.").await?;
+        return Ok(());
+    }
+    if arg.trim() != "confirm" {
+        cx.answer(
+            "This deletes all aliases, settings and unfinished actions of the chat. \
+            Send /purge confirm to proceed.",
+        )
+        .await?;
+        return Ok(());
+    }
+    let message = match db.lock().await.purge_chat(cx.chat_id()).await {
+        Ok(()) => "All data of the chat is deleted.",
+        Err(_) => "Failed to purge the chat, try again later.",
+    };
+    cx.answer(message).await?;
+    Ok(())
+}
+
+/// Respond to a command that works the same in every dialogue state.
+///
+/// /add, /remove and /cancel depend on the state and are ignored here.
+pub async fn handle_command(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    cmd: &Command,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    match cmd {
+        Command::Start => {
+            tracing::info!("Printing start message");
+            handle_start(cx).await?;
+        }
+        Command::Help => {
+            tracing::info!("Printing help message");
+            handle_help(cx).await?;
+        }
+        Command::List => {
+            tracing::info!("Listing aliases");
+
+            let mut db = db.lock().await;
+            if let Some(aliases) = db.get_aliases(cx.chat_id()).await {
+                handle_list(cx, aliases).await?;
+            }
+
+            tracing::info!("Finished listing");
+        }
+        Command::Find(query) => {
+            tracing::info!("Searching aliases");
+
+            let mut db = db.lock().await;
+            if let Some(aliases) = db.scan_aliases(cx.chat_id()).await {
+                handle_find(cx, query, aliases).await?;
+            }
+
+            tracing::info!("Finished searching");
+        }
+        Command::Suggestions(mode) => {
+            tracing::info!("Handling suggestions mode");
+            handle_suggestions(cx, mode, db).await?;
+        }
+        Command::Aliases(args) => {
+            tracing::info!("Handling sticker aliases");
+            let mut db = db.lock().await;
+            handle_aliases(cx, args, &mut *db).await?;
+        }
+        Command::Matching(matching) => {
+            tracing::info!("Handling alias matching");
+            handle_matching(cx, matching, db).await?;
+        }
+        Command::Rewrite(rewrite) => {
+            tracing::info!("Handling rewriting");
+            handle_rewrite(cx, rewrite, db).await?;
+        }
+        Command::Rename(args) => {
+            tracing::info!("Renaming alias");
+            let mut db = db.lock().await;
+            handle_rename(cx, args, &mut *db).await?;
+        }
+        Command::Merge(args) => {
+            tracing::info!("Merging aliases");
+            let mut db = db.lock().await;
+            handle_merge(cx, args, &mut *db).await?;
+        }
+        Command::Settings => {
+            tracing::info!("Showing settings");
+            let mut db = db.lock().await;
+            handle_settings(cx, &mut *db).await?;
+        }
+        Command::Schedule(arg) => {
+            tracing::info!("Scheduling a post");
+            handle_schedule(cx, arg, db).await?;
+        }
+        Command::Schedules => {
+            tracing::info!("Listing scheduled posts");
+            let mut db = db.lock().await;
+            handle_schedules(cx, &mut *db).await?;
+        }
+        Command::Trigger(arg) => {
+            tracing::info!("Adding a trigger");
+            handle_trigger(cx, arg, db).await?;
+        }
+        Command::Triggers => {
+            tracing::info!("Listing triggers");
+            let mut db = db.lock().await;
+            handle_triggers(cx, &mut *db).await?;
+        }
+        Command::ForgetMe => {
+            tracing::info!("Forgetting the user");
+            let mut db = db.lock().await;
+            handle_forget_me(cx, &mut *db).await?;
+        }
+        Command::Purge(arg) => {
+            tracing::info!("Handling chat purge");
+            handle_purge(cx, arg, db).await?;
+        }
+        Command::Add | Command::Remove | Command::Cancel => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_name() {
+        assert_eq!(Command::List.name(), "list");
+        assert_eq!(Command::Find("cry".to_owned()).name(), "find");
+    }
+
+    #[test]
+    fn test_menu_commands() {
+        let names = |scope| -> Vec<String> {
+            menu_commands(&scope)
+                .into_iter()
+                .map(|c| c.command)
+                .collect()
+        };
+        assert_eq!(
+            names(BotCommandScope::AllPrivateChats),
+            vec![
+                "start",
+                "help",
+                "list",
+                "find",
+                "suggestions",
+                "matching",
+                "rewrite",
+                "settings",
+                "add",
+                "remove",
+                "aliases",
+                "rename",
+                "merge",
+                "schedule",
+                "schedules",
+                "trigger",
+                "triggers",
+                "cancel",
+                "forgetme",
+                "purge"
+            ]
+        );
+        assert_eq!(
+            names(BotCommandScope::AllGroupChats),
+            vec!["help", "list", "find", "add", "aliases", "rename", "merge", "cancel"]
+        );
+        assert_eq!(
+            names(BotCommandScope::AllChatAdministrators),
+            vec![
+                "help",
+                "list",
+                "find",
+                "suggestions",
+                "matching",
+                "rewrite",
+                "settings",
+                "add",
+                "remove",
+                "aliases",
+                "rename",
+                "merge",
+                "schedule",
+                "schedules",
+                "trigger",
+                "triggers",
+                "cancel",
+                "purge"
+            ]
+        );
+        assert!(menu_commands(&BotCommandScope::Default)
+            .iter()
+            .all(|c| !c.description.is_empty()));
+    }
+}
@@ -0,0 +1,1286 @@
+//! Redis storage.
+//!
+//! Keeps everything in hashes and sets keyed by chat id.
+
+use super::{uncount_dialogues, RedisStorageError, Storage, DIALOGUE_TTL};
+use crate::alias::Target;
+use crate::metrics;
+use async_trait::async_trait;
+use redis::AsyncCommands;
+use redis::RedisResult;
+
+/// Redis connection representation.
+///
+/// Implements `Storage` on top of a Redis server.
+pub struct RedisConnection {
+    connection: redis::aio::Connection,
+}
+
+// General implementation
+impl RedisConnection {
+    /// Create new connection to redis server in specified ip.
+    ///
+    /// IP should be formatted according to `redis` crate requirements
+    /// (currently similar to `redis://127.0.0.1/`)
+    pub async fn new(redis_ip: &str) -> redis::RedisResult<RedisConnection> {
+        let client = redis::Client::open(redis_ip)?;
+        let con = client.get_async_connection().await?;
+        Ok(RedisConnection { connection: con })
+    }
+
+    /// Get redis key for chat given its identifier.
+    fn get_chat_key(chat_id: i64) -> String {
+        format!("chat:{}", chat_id)
+    }
+
+    /// Get pattern matching redis keys of all chats.
+    fn get_chat_key_pattern() -> String {
+        String::from("chat:*")
+    }
+
+    /// Get chat identifier from redis key of any chat storage.
+    fn parse_chat_id(key: &str) -> Option<i64> {
+        let rest = key.strip_prefix("chat:")?;
+        let end = rest
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && c == '-')))
+            .map_or(rest.len(), |(i, _)| i);
+        rest[..end].parse().ok()
+    }
+}
+
+impl RedisConnection {
+    /// Get redis key for aliases storage.
+    fn get_aliases_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "aliases"
+    }
+
+    /// Get redis key for alias to sticker unique id mapping.
+    ///
+    /// Used to keep the reverse index consistent when an alias changes.
+    fn get_alias_uids_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "alias_uids"
+    }
+
+    /// Get prefix of redis keys for sets of aliases of each sticker
+    /// (reverse index). The full key is the prefix followed by file
+    /// unique id of the sticker.
+    fn get_sticker_aliases_prefix(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "sticker:"
+    }
+
+    /// Get redis key for set of aliases of the sticker with given unique
+    /// id. An empty id gives a key that is never written.
+    fn get_sticker_aliases_key(chat_id: i64, unique_id: &str) -> String {
+        RedisConnection::get_sticker_aliases_prefix(chat_id) + unique_id
+    }
+
+    /// Get sticker unique ids of the aliases (empty for aliases without it).
+    ///
+    /// Scripts updating the reverse index need the keys of the sets they
+    /// change in advance, so they check that these ids weren't changed
+    /// in between and are retried otherwise (see `INDEX_ATTEMPTS`).
+    async fn get_alias_uids(&mut self, chat_id: i64, aliases: &[&str]) -> RedisResult<Vec<String>> {
+        let uids: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(RedisConnection::get_alias_uids_key(chat_id))
+            .arg(aliases)
+            .query_async(&mut self.connection)
+            .await?;
+        Ok(uids.into_iter().map(Option::unwrap_or_default).collect())
+    }
+}
+
+/// Number of attempts to run a script updating the reverse index when
+/// unique ids of the aliases change concurrently.
+const INDEX_ATTEMPTS: usize = 3;
+
+/// Error after `INDEX_ATTEMPTS` failed attempts.
+fn index_changed() -> redis::RedisError {
+    (
+        redis::ErrorKind::TryAgain,
+        "aliases were changed concurrently",
+    )
+        .into()
+}
+
+/// Storage of media that can't be sent anymore.
+impl RedisConnection {
+    /// Get redis key for times media of the chat was found broken,
+    /// by media key (see `Target::media_key`).
+    fn get_broken_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "broken"
+    }
+}
+
+/// Scheduled jobs storage.
+impl RedisConnection {
+    /// Get redis key for scheduled jobs of the chat by their ids.
+    fn get_schedules_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "schedules"
+    }
+
+    /// Get pattern matching redis keys of scheduled jobs of all chats.
+    fn get_schedules_key_pattern() -> String {
+        RedisConnection::get_chat_key_pattern() + "schedules"
+    }
+}
+
+/// Keyword triggers storage.
+impl RedisConnection {
+    /// Get redis key for keyword triggers of the chat by their ids.
+    fn get_triggers_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "triggers"
+    }
+
+    /// Get redis key for times triggers of the chat last fired, by their ids.
+    fn get_trigger_fired_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "trigger_fired"
+    }
+}
+
+/// Chat settings storage.
+impl RedisConnection {
+    /// Get redis key for settings storage for given chat id.
+    fn get_settings_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "settings"
+    }
+}
+
+/// Bot-wide storage (not related to any chat).
+///
+/// Keys don't start with the chat key prefix, so they are never
+/// considered to be a chat.
+impl RedisConnection {
+    /// Get redis key for the set of banned chat and user ids.
+    fn get_banned_key() -> String {
+        String::from("bot:banned")
+    }
+
+    /// Get redis key for bot-wide settings storage.
+    fn get_bot_settings_key() -> String {
+        String::from("bot:settings")
+    }
+
+    /// Get redis key for removal times of chats the bot was removed from.
+    fn get_removed_key() -> String {
+        String::from("bot:removed")
+    }
+
+    /// Get redis key for the id of the last scheduled job.
+    fn get_schedule_id_key() -> String {
+        String::from("bot:schedule_id")
+    }
+
+    /// Get redis key for the id of the last keyword trigger.
+    fn get_trigger_id_key() -> String {
+        String::from("bot:trigger_id")
+    }
+}
+
+/// How long aliases resolved in a message are remembered, in seconds.
+///
+/// Edits of older messages may send the same targets again.
+const RESOLVED_TTL: usize = 2 * 24 * 60 * 60;
+
+/// Storage of aliases already resolved in messages (for edits).
+impl RedisConnection {
+    /// Get prefix of redis keys for aliases resolved in messages.
+    fn get_resolved_prefix(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "resolved:"
+    }
+
+    /// Get redis key for aliases resolved in the message.
+    fn get_resolved_key(chat_id: i64, message_id: i32) -> String {
+        RedisConnection::get_resolved_prefix(chat_id) + &message_id.to_string()
+    }
+}
+
+/// Dialogue storage.
+///
+/// Similar to `teloxide::dispatching::dialogue::Storage`,
+/// but with different dialogue for each user in the chat.
+impl RedisConnection {
+    /// Get redis key for dialogues storage for given chat id.
+    fn get_dialogues_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "dialogues"
+    }
+
+    /// Get redis key for times of the last changes of dialogues of the
+    /// chat (unix timestamps), by the same fields as dialogues.
+    fn get_dialogue_times_key(chat_id: i64) -> String {
+        RedisConnection::get_chat_key(chat_id) + "dialogue_times"
+    }
+
+    /// Get pattern matching redis keys of dialogue storages of all chats.
+    fn get_dialogues_key_pattern() -> String {
+        RedisConnection::get_chat_key_pattern() + "dialogues"
+    }
+
+    /// Get redis keys of dialogue storages of all chats.
+    async fn scan_dialogues_keys(&mut self) -> RedisResult<Vec<String>> {
+        let pattern = RedisConnection::get_dialogues_key_pattern();
+        let mut keys: Vec<String> = vec![];
+        let mut iter: redis::AsyncIter<String> = self.connection.scan_match(pattern).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    /// Get field name for given from_id (can be empty).
+    fn get_from_field(from_id: Option<i64>) -> String {
+        from_id
+            .map(|x| x.to_string())
+            .unwrap_or_else(|| "NO_ID".to_owned())
+    }
+}
+
+#[async_trait]
+impl Storage for RedisConnection {
+    async fn set_alias(
+        &mut self,
+        chat_id: i64,
+        alias: &str,
+        target: &Target,
+    ) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("set_alias");
+        let script = redis::Script::new(
+            r"
+            local old = redis.call('HGET', KEYS[2], ARGV[1]) or ''
+            if old ~= ARGV[4] then return 0 end
+            if old ~= '' then redis.call('SREM', KEYS[3], ARGV[1]) end
+            redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+            if ARGV[3] == '' then
+                redis.call('HDEL', KEYS[2], ARGV[1])
+            else
+                redis.call('HSET', KEYS[2], ARGV[1], ARGV[3])
+                redis.call('SADD', KEYS[4], ARGV[1])
+            end
+            return 1
+            ",
+        );
+        let value = target.encode();
+        let unique_id = target.unique_id().unwrap_or_default();
+        let mut set_result: RedisResult<bool> = Ok(false);
+        for _ in 0..INDEX_ATTEMPTS {
+            set_result = match self.get_alias_uids(chat_id, &[alias]).await {
+                Ok(old) => {
+                    script
+                        .key(RedisConnection::get_aliases_key(chat_id))
+                        .key(RedisConnection::get_alias_uids_key(chat_id))
+                        .key(RedisConnection::get_sticker_aliases_key(chat_id, &old[0]))
+                        .key(RedisConnection::get_sticker_aliases_key(chat_id, unique_id))
+                        .arg(alias)
+                        .arg(&value)
+                        .arg(unique_id)
+                        .arg(&old[0])
+                        .invoke_async(&mut self.connection)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if !matches!(set_result, Ok(false)) {
+                break;
+            }
+        }
+        let set_result = set_result.and_then(|saved| saved.then_some(()).ok_or_else(index_changed));
+        match &set_result {
+            Ok(_) => {
+                tracing::info!("Saved alias '{a}' for '{v}'", a = alias, v = value);
+            }
+            Err(e) => {
+                tracing::error!("Failed to save alias to DB: {}", e);
+            }
+        }
+        set_result.map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_target(&mut self, chat_id: i64, alias: &str) -> Option<Target> {
+        let _timer = metrics::redis_timer("get_target");
+        let get_result: RedisResult<(String, Option<String>)> = redis::pipe()
+            .hget(RedisConnection::get_aliases_key(chat_id), alias)
+            .hget(RedisConnection::get_alias_uids_key(chat_id), alias)
+            .query_async(&mut self.connection)
+            .await;
+        match get_result {
+            Ok((value, unique_id)) => {
+                tracing::debug!("Retrieved '{v}' by alias '{a}'", a = alias, v = value);
+                Some(Target::decode(&value).with_unique_id(unique_id))
+            }
+            Err(e) => {
+                tracing::debug!("Alias '{a}' not found: {}", e, a = alias);
+                None
+            }
+        }
+    }
+
+    async fn remove_alias(&mut self, chat_id: i64, alias: &str) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("remove_alias");
+        let script = redis::Script::new(
+            r"
+            local uid = redis.call('HGET', KEYS[2], ARGV[1]) or ''
+            if uid ~= ARGV[2] then return -1 end
+            if uid ~= '' then
+                redis.call('SREM', KEYS[3], ARGV[1])
+                redis.call('HDEL', KEYS[2], ARGV[1])
+            end
+            return redis.call('HDEL', KEYS[1], ARGV[1])
+            ",
+        );
+        let mut remove_result: RedisResult<i64> = Ok(-1);
+        for _ in 0..INDEX_ATTEMPTS {
+            remove_result = match self.get_alias_uids(chat_id, &[alias]).await {
+                Ok(uid) => {
+                    script
+                        .key(RedisConnection::get_aliases_key(chat_id))
+                        .key(RedisConnection::get_alias_uids_key(chat_id))
+                        .key(RedisConnection::get_sticker_aliases_key(chat_id, &uid[0]))
+                        .arg(alias)
+                        .arg(&uid[0])
+                        .invoke_async(&mut self.connection)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if !matches!(remove_result, Ok(-1)) {
+                break;
+            }
+        }
+        let n_removed: i64 = remove_result
+            .and_then(|n| if n < 0 { Err(index_changed()) } else { Ok(n) })
+            .map_err(|e| {
+                tracing::error!("Failed to remove alias from DB: {}", e);
+                RedisStorageError::RedisError(e)
+            })?;
+        // Log and form result
+        match n_removed {
+            0 => {
+                tracing::info!("Alias '{a}' was not found", a = alias);
+                Err(RedisStorageError::AliasNotFound)
+            }
+            1 => {
+                tracing::info!("Removed alias '{a}'", a = alias);
+                Ok(())
+            }
+            n_unexpected => {
+                tracing::warn!(
+                    "'{a}' removal returned unexpected number: {n}",
+                    a = alias,
+                    n = n_unexpected
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn rename_alias(
+        &mut self,
+        chat_id: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("rename_alias");
+        let script = redis::Script::new(
+            r"
+            local uid = redis.call('HGET', KEYS[2], ARGV[1]) or ''
+            if uid ~= ARGV[3] then return -2 end
+            local sticker = redis.call('HGET', KEYS[1], ARGV[1])
+            if not sticker then return 0 end
+            if redis.call('HEXISTS', KEYS[1], ARGV[2]) == 1 then return -1 end
+            redis.call('HSET', KEYS[1], ARGV[2], sticker)
+            redis.call('HDEL', KEYS[1], ARGV[1])
+            if uid ~= '' then
+                redis.call('HSET', KEYS[2], ARGV[2], uid)
+                redis.call('HDEL', KEYS[2], ARGV[1])
+                redis.call('SREM', KEYS[3], ARGV[1])
+                redis.call('SADD', KEYS[3], ARGV[2])
+            end
+            return 1
+            ",
+        );
+        let mut result: RedisResult<i64> = Ok(-2);
+        for _ in 0..INDEX_ATTEMPTS {
+            result = match self.get_alias_uids(chat_id, &[old]).await {
+                Ok(uid) => {
+                    script
+                        .key(RedisConnection::get_aliases_key(chat_id))
+                        .key(RedisConnection::get_alias_uids_key(chat_id))
+                        .key(RedisConnection::get_sticker_aliases_key(chat_id, &uid[0]))
+                        .arg(old)
+                        .arg(new)
+                        .arg(&uid[0])
+                        .invoke_async(&mut self.connection)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if !matches!(result, Ok(-2)) {
+                break;
+            }
+        }
+        let result: i64 = result
+            .and_then(|n| if n == -2 { Err(index_changed()) } else { Ok(n) })
+            .map_err(|e| {
+                tracing::error!("Failed to rename alias: {}", e);
+                RedisStorageError::RedisError(e)
+            })?;
+        match result {
+            1 => {
+                tracing::info!("Renamed alias '{o}' to '{n}'", o = old, n = new);
+                Ok(())
+            }
+            0 => Err(RedisStorageError::AliasNotFound),
+            _ => Err(RedisStorageError::AliasExists),
+        }
+    }
+
+    async fn merge_aliases(
+        &mut self,
+        chat_id: i64,
+        target: &str,
+        aliases: &[&str],
+    ) -> Result<i64, RedisStorageError> {
+        let _timer = metrics::redis_timer("merge_aliases");
+        // KEYS[3] is the set of the target, followed by the sets of
+        // `aliases`. ARGV has each alias followed by its expected unique id.
+        let script = redis::Script::new(
+            r"
+            for i = 1, #ARGV, 2 do
+                if (redis.call('HGET', KEYS[2], ARGV[i]) or '') ~= ARGV[i + 1] then
+                    return -2
+                end
+            end
+            local sticker = redis.call('HGET', KEYS[1], ARGV[1])
+            if not sticker then return -1 end
+            local target_uid = ARGV[2]
+            local changed = 0
+            for i = 3, #ARGV, 2 do
+                local alias, uid = ARGV[i], ARGV[i + 1]
+                if redis.call('HGET', KEYS[1], alias) ~= sticker then
+                    redis.call('HSET', KEYS[1], alias, sticker)
+                    changed = changed + 1
+                end
+                if uid ~= '' then redis.call('SREM', KEYS[3 + (i - 1) / 2], alias) end
+                if target_uid ~= '' then
+                    redis.call('HSET', KEYS[2], alias, target_uid)
+                    redis.call('SADD', KEYS[3], alias)
+                else
+                    redis.call('HDEL', KEYS[2], alias)
+                end
+            end
+            return changed
+            ",
+        );
+        let names: Vec<&str> = std::iter::once(target)
+            .chain(aliases.iter().copied())
+            .collect();
+        let mut result: RedisResult<i64> = Ok(-2);
+        for _ in 0..INDEX_ATTEMPTS {
+            result = match self.get_alias_uids(chat_id, &names).await {
+                Ok(uids) => {
+                    let mut invocation = script.prepare_invoke();
+                    invocation
+                        .key(RedisConnection::get_aliases_key(chat_id))
+                        .key(RedisConnection::get_alias_uids_key(chat_id));
+                    for (name, uid) in names.iter().zip(&uids) {
+                        invocation
+                            .key(RedisConnection::get_sticker_aliases_key(chat_id, uid))
+                            .arg(name)
+                            .arg(uid);
+                    }
+                    invocation.invoke_async(&mut self.connection).await
+                }
+                Err(e) => Err(e),
+            };
+            if !matches!(result, Ok(-2)) {
+                break;
+            }
+        }
+        let result: i64 = result
+            .and_then(|n| if n == -2 { Err(index_changed()) } else { Ok(n) })
+            .map_err(|e| {
+                tracing::error!("Failed to merge aliases: {}", e);
+                RedisStorageError::RedisError(e)
+            })?;
+        if result < 0 {
+            return Err(RedisStorageError::AliasNotFound);
+        }
+        tracing::info!("Merged {n} aliases into '{t}'", n = result, t = target);
+        Ok(result)
+    }
+
+    async fn get_sticker_aliases(&mut self, chat_id: i64, unique_id: &str) -> Option<Vec<String>> {
+        let _timer = metrics::redis_timer("get_sticker_aliases");
+        let key = RedisConnection::get_sticker_aliases_key(chat_id, unique_id);
+        let members_result: RedisResult<Vec<String>> = self.connection.smembers(key).await;
+        match members_result {
+            Ok(mut aliases) => {
+                aliases.sort();
+                Some(aliases)
+            }
+            Err(e) => {
+                tracing::error!("Failed to get sticker aliases: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn get_targets(&mut self, chat_id: i64, aliases: &[&str]) -> Option<Vec<Option<Target>>> {
+        let _timer = metrics::redis_timer("get_targets");
+        if aliases.is_empty() {
+            return Some(vec![]);
+        }
+        let get_result: RedisResult<(Vec<Option<String>>, Vec<Option<String>>)> = redis::pipe()
+            .cmd("HMGET")
+            .arg(RedisConnection::get_aliases_key(chat_id))
+            .arg(aliases)
+            .cmd("HMGET")
+            .arg(RedisConnection::get_alias_uids_key(chat_id))
+            .arg(aliases)
+            .query_async(&mut self.connection)
+            .await;
+        match get_result {
+            Ok((values, unique_ids)) => Some(
+                values
+                    .into_iter()
+                    .zip(unique_ids)
+                    .map(|(value, unique_id)| {
+                        value.map(|value| Target::decode(&value).with_unique_id(unique_id))
+                    })
+                    .collect(),
+            ),
+            Err(e) => {
+                tracing::error!("Failed to get targets: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn scan_aliases(&mut self, chat_id: i64) -> Option<Vec<(String, String)>> {
+        let _timer = metrics::redis_timer("scan_aliases");
+        let key: String = RedisConnection::get_aliases_key(chat_id);
+        let mut pairs: Vec<(String, String)> = Vec::new();
+        let scan_result: RedisResult<redis::AsyncIter<(String, String)>> =
+            self.connection.hscan(key).await;
+        match scan_result {
+            Ok(mut iter) => {
+                while let Some(pair) = iter.next_item().await {
+                    pairs.push(pair);
+                }
+                Some(pairs)
+            }
+            Err(e) => {
+                tracing::error!("Failed to scan aliases: {}", e);
+                None
+            }
+        }
+    }
+
+    async fn count_aliases(&mut self, chat_id: i64) -> Result<usize, RedisStorageError> {
+        let _timer = metrics::redis_timer("count_aliases");
+        self.connection
+            .hlen(RedisConnection::get_aliases_key(chat_id))
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn set_broken(
+        &mut self,
+        chat_id: i64,
+        media_key: &str,
+        broken_at: Option<i64>,
+    ) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("set_broken");
+        let key = RedisConnection::get_broken_key(chat_id);
+        let result: RedisResult<()> = match broken_at {
+            Some(time) => self.connection.hset(key, media_key, time).await,
+            None => self.connection.hdel(key, media_key).await,
+        };
+        if let Err(e) = &result {
+            tracing::error!("Failed to save broken media to DB: {}", e);
+        }
+        result.map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_broken(&mut self, chat_id: i64) -> Result<Vec<String>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_broken");
+        self.connection
+            .hkeys(RedisConnection::get_broken_key(chat_id))
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_broken_at(
+        &mut self,
+        chat_id: i64,
+        media_key: &str,
+    ) -> Result<Option<i64>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_broken_at");
+        self.connection
+            .hget(RedisConnection::get_broken_key(chat_id), media_key)
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn set_setting(
+        &mut self,
+        chat_id: i64,
+        name: &str,
+        value: &str,
+    ) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("set_setting");
+        let key: String = RedisConnection::get_settings_key(chat_id);
+        let set_result: RedisResult<()> = self.connection.hset(key, name, value).await;
+        match &set_result {
+            Ok(_) => {
+                tracing::info!("Set '{n}' setting to '{v}'", n = name, v = value);
+            }
+            Err(e) => {
+                tracing::error!("Failed to save setting to DB: {}", e);
+            }
+        }
+        set_result.map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_settings(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(String, String)>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_settings");
+        let key: String = RedisConnection::get_settings_key(chat_id);
+        self.connection
+            .hgetall(key)
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn mark_resolved(
+        &mut self,
+        chat_id: i64,
+        message_id: i32,
+        aliases: &[&str],
+    ) -> Result<Vec<String>, RedisStorageError> {
+        let _timer = metrics::redis_timer("mark_resolved");
+        if aliases.is_empty() {
+            return Ok(vec![]);
+        }
+        let script = redis::Script::new(
+            r"
+            local new = {}
+            for i = 2, #ARGV do
+                if redis.call('SADD', KEYS[1], ARGV[i]) == 1 then
+                    table.insert(new, ARGV[i])
+                end
+            end
+            redis.call('EXPIRE', KEYS[1], ARGV[1])
+            return new
+            ",
+        );
+        script
+            .key(RedisConnection::get_resolved_key(chat_id, message_id))
+            .arg(RESOLVED_TTL)
+            .arg(aliases)
+            .invoke_async(&mut self.connection)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to mark resolved aliases: {}", e);
+                RedisStorageError::RedisError(e)
+            })
+    }
+
+    async fn remove_dialogue(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+    ) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("remove_dialogue");
+        let key: String = RedisConnection::get_dialogues_key(chat_id);
+        let field: String = RedisConnection::get_from_field(from_id);
+
+        let del_res: RedisResult<(i64, i64)> = redis::pipe()
+            .atomic()
+            .hdel(key, &field)
+            .hdel(RedisConnection::get_dialogue_times_key(chat_id), &field)
+            .query_async(&mut self.connection)
+            .await;
+        match del_res {
+            Ok((0, _)) => Err(RedisStorageError::DialogueNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(RedisStorageError::RedisError(e)),
+        }
+    }
+
+    async fn remove_chat_dialogues(&mut self, chat_id: i64) -> Result<usize, RedisStorageError> {
+        let _timer = metrics::redis_timer("remove_chat_dialogues");
+        let key: String = RedisConnection::get_dialogues_key(chat_id);
+        let (values, _): (Vec<String>, i64) = redis::pipe()
+            .atomic()
+            .hvals(&key)
+            .del(&[
+                key.clone(),
+                RedisConnection::get_dialogue_times_key(chat_id),
+            ])
+            .query_async(&mut self.connection)
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        uncount_dialogues(&values);
+        Ok(values.len())
+    }
+
+    async fn migrate_chat(&mut self, from: i64, to: i64) -> Result<usize, RedisStorageError> {
+        let _timer = metrics::redis_timer("migrate_chat");
+        let old_prefix = RedisConnection::get_chat_key(from);
+        let new_prefix = RedisConnection::get_chat_key(to);
+        let mut keys: Vec<String> = vec![];
+        let mut iter: redis::AsyncIter<String> = self
+            .connection
+            .scan_match(old_prefix.clone() + "*")
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        while let Some(key) = iter.next_item().await {
+            // The pattern also matches chats with longer ids.
+            if RedisConnection::parse_chat_id(&key) == Some(from) {
+                keys.push(key);
+            }
+        }
+        drop(iter);
+        if keys.is_empty() {
+            return Ok(0);
+        }
+
+        // Keys are renamed, or merged into existing keys of the new chat
+        // without overwriting their fields.
+        let script = redis::Script::new(
+            r"
+            for i = 1, #KEYS, 2 do
+                local old, new = KEYS[i], KEYS[i + 1]
+                if redis.call('EXISTS', new) == 0 then
+                    redis.call('RENAME', old, new)
+                else
+                    local kind = redis.call('TYPE', old)['ok']
+                    if kind == 'hash' then
+                        local fields = redis.call('HGETALL', old)
+                        for j = 1, #fields, 2 do
+                            redis.call('HSETNX', new, fields[j], fields[j + 1])
+                        end
+                    elseif kind == 'set' then
+                        for _, member in ipairs(redis.call('SMEMBERS', old)) do
+                            redis.call('SADD', new, member)
+                        end
+                    end
+                    redis.call('DEL', old)
+                end
+            end
+            ",
+        );
+        let mut invocation = script.prepare_invoke();
+        for key in &keys {
+            let new_key = new_prefix.clone() + &key[old_prefix.len()..];
+            invocation.key(key).key(new_key);
+        }
+        let result: RedisResult<()> = invocation.invoke_async(&mut self.connection).await;
+        match &result {
+            Ok(_) => tracing::info!("Migrated chat {} to {} ({} keys)", from, to, keys.len()),
+            Err(e) => tracing::error!("Failed to migrate chat: {}", e),
+        }
+        result
+            .map(|_| keys.len())
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn set_removed(
+        &mut self,
+        chat_id: i64,
+        removed_at: Option<i64>,
+    ) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("set_removed");
+        let key = RedisConnection::get_removed_key();
+        let result: RedisResult<()> = match removed_at {
+            Some(time) => self.connection.hset(key, chat_id, time).await,
+            None => self.connection.hdel(key, chat_id).await,
+        };
+        if let Err(e) = &result {
+            tracing::error!("Failed to save chat removal to DB: {}", e);
+        }
+        result.map_err(RedisStorageError::RedisError)
+    }
+
+    async fn scan_removed(&mut self) -> Result<Vec<(i64, i64)>, RedisStorageError> {
+        let _timer = metrics::redis_timer("scan_removed");
+        self.connection
+            .hgetall(RedisConnection::get_removed_key())
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn remove_user_dialogues(&mut self, user_id: i64) -> Result<usize, RedisStorageError> {
+        let _timer = metrics::redis_timer("remove_user_dialogues");
+        let field = RedisConnection::get_from_field(Some(user_id));
+        let keys = self
+            .scan_dialogues_keys()
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+
+        let mut removed: Vec<String> = vec![];
+        for key in keys {
+            let chat_id = match RedisConnection::parse_chat_id(&key) {
+                Some(chat_id) => chat_id,
+                None => continue,
+            };
+            let (value, _, _): (Option<String>, (), ()) = redis::pipe()
+                .atomic()
+                .hget(&key, &field)
+                .hdel(&key, &field)
+                .hdel(RedisConnection::get_dialogue_times_key(chat_id), &field)
+                .query_async(&mut self.connection)
+                .await
+                .map_err(RedisStorageError::RedisError)?;
+            removed.extend(value);
+        }
+        uncount_dialogues(&removed);
+        tracing::info!("Removed {} dialogues of user {}", removed.len(), user_id);
+        Ok(removed.len())
+    }
+
+    async fn remove_stale_dialogues(&mut self, now: i64) -> Result<usize, RedisStorageError> {
+        let ttl = match *DIALOGUE_TTL {
+            Some(ttl) => ttl as i64,
+            None => return Ok(0),
+        };
+        let _timer = metrics::redis_timer("remove_stale_dialogues");
+        let keys = self
+            .scan_dialogues_keys()
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+
+        let mut removed: Vec<String> = vec![];
+        for key in keys {
+            let chat_id = match RedisConnection::parse_chat_id(&key) {
+                Some(chat_id) => chat_id,
+                None => continue,
+            };
+            let times_key = RedisConnection::get_dialogue_times_key(chat_id);
+            let fields: Vec<String> = self
+                .connection
+                .hkeys(&key)
+                .await
+                .map_err(RedisStorageError::RedisError)?;
+            if fields.is_empty() {
+                continue;
+            }
+            let times: Vec<Option<i64>> = redis::cmd("HMGET")
+                .arg(&times_key)
+                .arg(&fields)
+                .query_async(&mut self.connection)
+                .await
+                .map_err(RedisStorageError::RedisError)?;
+            let mut stale: Vec<&str> = vec![];
+            let mut unknown: Vec<(&str, i64)> = vec![];
+            for (field, time) in fields.iter().zip(times) {
+                match time {
+                    Some(time) if time + ttl <= now => stale.push(field),
+                    Some(_) => {}
+                    None => unknown.push((field, now)),
+                }
+            }
+            if !unknown.is_empty() {
+                // Saved by older versions, which set expiration of the
+                // whole key instead.
+                let _: () = redis::pipe()
+                    .atomic()
+                    .hset_multiple(&times_key, &unknown)
+                    .persist(&key)
+                    .query_async(&mut self.connection)
+                    .await
+                    .map_err(RedisStorageError::RedisError)?;
+            }
+            if !stale.is_empty() {
+                let (values, _, _): (Vec<Option<String>>, (), ()) = redis::pipe()
+                    .atomic()
+                    .cmd("HMGET")
+                    .arg(&key)
+                    .arg(&stale)
+                    .hdel(&key, &stale)
+                    .hdel(&times_key, &stale)
+                    .query_async(&mut self.connection)
+                    .await
+                    .map_err(RedisStorageError::RedisError)?;
+                removed.extend(values.into_iter().flatten());
+            }
+        }
+        uncount_dialogues(&removed);
+        Ok(removed.len())
+    }
+
+    async fn add_schedule(&mut self, chat_id: i64, job: &str) -> Result<u64, RedisStorageError> {
+        let _timer = metrics::redis_timer("add_schedule");
+        let id: u64 = self
+            .connection
+            .incr(RedisConnection::get_schedule_id_key(), 1)
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        let result: RedisResult<()> = self
+            .connection
+            .hset(RedisConnection::get_schedules_key(chat_id), id, job)
+            .await;
+        match &result {
+            Ok(()) => tracing::info!("Saved scheduled job {}", id),
+            Err(e) => tracing::error!("Failed to save scheduled job to DB: {}", e),
+        }
+        result.map(|_| id).map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_schedules(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(u64, String)>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_schedules");
+        let mut jobs: Vec<(u64, String)> = self
+            .connection
+            .hgetall(RedisConnection::get_schedules_key(chat_id))
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        jobs.sort_unstable();
+        Ok(jobs)
+    }
+
+    async fn remove_schedule(&mut self, chat_id: i64, id: u64) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("remove_schedule");
+        let key = RedisConnection::get_schedules_key(chat_id);
+        let del_res: RedisResult<i64> = self.connection.hdel(key, id).await;
+        match del_res {
+            Ok(0) => Err(RedisStorageError::ScheduleNotFound),
+            Ok(_) => Ok(()),
+            Err(e) => Err(RedisStorageError::RedisError(e)),
+        }
+    }
+
+    async fn scan_schedules(&mut self) -> Result<Vec<(i64, u64, String)>, RedisStorageError> {
+        let _timer = metrics::redis_timer("scan_schedules");
+        let pattern = RedisConnection::get_schedules_key_pattern();
+        let mut keys: Vec<String> = vec![];
+        let mut iter: redis::AsyncIter<String> = self
+            .connection
+            .scan_match(pattern)
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        drop(iter);
+
+        let mut jobs = vec![];
+        for key in keys {
+            let chat_id = match RedisConnection::parse_chat_id(&key) {
+                Some(chat_id) => chat_id,
+                None => continue,
+            };
+            let chat_jobs: Vec<(u64, String)> = self
+                .connection
+                .hgetall(&key)
+                .await
+                .map_err(RedisStorageError::RedisError)?;
+            jobs.extend(chat_jobs.into_iter().map(|(id, job)| (chat_id, id, job)));
+        }
+        Ok(jobs)
+    }
+
+    async fn add_trigger(&mut self, chat_id: i64, trigger: &str) -> Result<u64, RedisStorageError> {
+        let _timer = metrics::redis_timer("add_trigger");
+        let id: u64 = self
+            .connection
+            .incr(RedisConnection::get_trigger_id_key(), 1)
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        let result: RedisResult<()> = self
+            .connection
+            .hset(RedisConnection::get_triggers_key(chat_id), id, trigger)
+            .await;
+        match &result {
+            Ok(()) => tracing::info!("Saved trigger {}", id),
+            Err(e) => tracing::error!("Failed to save trigger to DB: {}", e),
+        }
+        result.map(|_| id).map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_triggers(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(u64, String)>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_triggers");
+        let mut triggers: Vec<(u64, String)> = self
+            .connection
+            .hgetall(RedisConnection::get_triggers_key(chat_id))
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        triggers.sort_unstable();
+        Ok(triggers)
+    }
+
+    async fn remove_trigger(&mut self, chat_id: i64, id: u64) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("remove_trigger");
+        let key = RedisConnection::get_triggers_key(chat_id);
+        let del_res: RedisResult<i64> = self.connection.hdel(key, id).await;
+        match del_res {
+            Ok(0) => Err(RedisStorageError::TriggerNotFound),
+            Ok(_) => {
+                let key = RedisConnection::get_trigger_fired_key(chat_id);
+                let del_res: RedisResult<i64> = self.connection.hdel(key, id).await;
+                del_res.map(drop).map_err(RedisStorageError::RedisError)
+            }
+            Err(e) => Err(RedisStorageError::RedisError(e)),
+        }
+    }
+
+    async fn fire_trigger(
+        &mut self,
+        chat_id: i64,
+        id: u64,
+        now: i64,
+        cooldown: i64,
+    ) -> Result<bool, RedisStorageError> {
+        let _timer = metrics::redis_timer("fire_trigger");
+        // Checked and updated at once, so concurrent messages fire it once.
+        let script = redis::Script::new(
+            r"
+            local last = redis.call('HGET', KEYS[1], ARGV[1])
+            if last and tonumber(ARGV[2]) - tonumber(last) < tonumber(ARGV[3]) then
+                return 0
+            end
+            redis.call('HSET', KEYS[1], ARGV[1], ARGV[2])
+            return 1
+            ",
+        );
+        script
+            .key(RedisConnection::get_trigger_fired_key(chat_id))
+            .arg(id)
+            .arg(now)
+            .arg(cooldown)
+            .invoke_async(&mut self.connection)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to save trigger firing: {}", e);
+                RedisStorageError::RedisError(e)
+            })
+    }
+
+    async fn set_banned(&mut self, id: i64, banned: bool) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("set_banned");
+        let key = RedisConnection::get_banned_key();
+        let result: RedisResult<()> = if banned {
+            self.connection.sadd(key, id).await
+        } else {
+            self.connection.srem(key, id).await
+        };
+        match &result {
+            Ok(_) => tracing::info!("Set ban of {} to {}", id, banned),
+            Err(e) => tracing::error!("Failed to save ban to DB: {}", e),
+        }
+        result.map_err(RedisStorageError::RedisError)
+    }
+
+    async fn is_banned(&mut self, id: i64) -> Result<bool, RedisStorageError> {
+        let _timer = metrics::redis_timer("is_banned");
+        self.connection
+            .sismember(RedisConnection::get_banned_key(), id)
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_bot_setting(&mut self, name: &str) -> Result<Option<String>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_bot_setting");
+        self.connection
+            .hget(RedisConnection::get_bot_settings_key(), name)
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn set_bot_setting(&mut self, name: &str, value: &str) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("set_bot_setting");
+        let key = RedisConnection::get_bot_settings_key();
+        let set_result: RedisResult<()> = self.connection.hset(key, name, value).await;
+        match &set_result {
+            Ok(_) => tracing::info!("Set bot setting '{n}' to '{v}'", n = name, v = value),
+            Err(e) => tracing::error!("Failed to save bot setting to DB: {}", e),
+        }
+        set_result.map_err(RedisStorageError::RedisError)
+    }
+
+    async fn ping(&mut self) -> bool {
+        let _timer = metrics::redis_timer("ping");
+        let result: RedisResult<String> =
+            redis::cmd("PING").query_async(&mut self.connection).await;
+        if let Err(e) = &result {
+            tracing::warn!("Redis does not respond: {}", e);
+        }
+        result.is_ok()
+    }
+
+    async fn set_dialogue_value(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+        value: String,
+    ) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("update_dialogue");
+        let key: String = RedisConnection::get_dialogues_key(chat_id);
+        let field: String = RedisConnection::get_from_field(from_id);
+
+        let now = chrono::Utc::now().timestamp();
+        let set_result: RedisResult<()> = redis::pipe()
+            .atomic()
+            .hset(&key, &field, &value)
+            .ignore()
+            .hset(
+                RedisConnection::get_dialogue_times_key(chat_id),
+                &field,
+                now,
+            )
+            .ignore()
+            // Older versions set expiration of the whole key.
+            .persist(&key)
+            .ignore()
+            .query_async(&mut self.connection)
+            .await;
+        match &set_result {
+            Ok(_) => {
+                tracing::debug!("Saved dialogue for '{f}'", f = field);
+            }
+            Err(err) => {
+                tracing::error!("Failed to save dialogue to DB: {}", err);
+            }
+        }
+        set_result.map_err(RedisStorageError::RedisError)
+    }
+
+    async fn get_dialogue_value(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+    ) -> Result<Option<String>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_dialogue");
+        let key: String = RedisConnection::get_dialogues_key(chat_id);
+        let field: String = RedisConnection::get_from_field(from_id);
+        self.connection
+            .hget(&key, &field)
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn scan_dialogue_values(&mut self) -> Result<Vec<String>, RedisStorageError> {
+        let _timer = metrics::redis_timer("scan_dialogues");
+        let pattern = RedisConnection::get_dialogues_key_pattern();
+        let mut keys: Vec<String> = vec![];
+        let mut iter: redis::AsyncIter<String> = self
+            .connection
+            .scan_match(pattern)
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        drop(iter);
+
+        let mut values = vec![];
+        for key in keys {
+            let chat_values: Vec<String> = self
+                .connection
+                .hvals(&key)
+                .await
+                .map_err(RedisStorageError::RedisError)?;
+            values.extend(chat_values);
+        }
+        Ok(values)
+    }
+
+    async fn get_chat_dialogue_values(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<String>, RedisStorageError> {
+        let _timer = metrics::redis_timer("get_chat_dialogues");
+        self.connection
+            .hvals(RedisConnection::get_dialogues_key(chat_id))
+            .await
+            .map_err(RedisStorageError::RedisError)
+    }
+
+    async fn scan_chat_ids(&mut self) -> Result<Vec<i64>, RedisStorageError> {
+        let _timer = metrics::redis_timer("scan_chat_ids");
+        let pattern = RedisConnection::get_chat_key_pattern();
+        let mut ids: Vec<i64> = vec![];
+        let mut iter: redis::AsyncIter<String> = self
+            .connection
+            .scan_match(pattern)
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        while let Some(key) = iter.next_item().await {
+            if let Some(id) = RedisConnection::parse_chat_id(&key) {
+                ids.push(id);
+            }
+        }
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    async fn purge_chat(&mut self, chat_id: i64) -> Result<(), RedisStorageError> {
+        let _timer = metrics::redis_timer("purge_chat");
+        let dialogues: Vec<String> = self
+            .connection
+            .hvals(RedisConnection::get_dialogues_key(chat_id))
+            .await
+            .map_err(RedisStorageError::RedisError)?;
+        let mut keys = vec![
+            RedisConnection::get_aliases_key(chat_id),
+            RedisConnection::get_alias_uids_key(chat_id),
+            RedisConnection::get_settings_key(chat_id),
+            RedisConnection::get_dialogues_key(chat_id),
+            RedisConnection::get_dialogue_times_key(chat_id),
+            RedisConnection::get_broken_key(chat_id),
+            RedisConnection::get_schedules_key(chat_id),
+            RedisConnection::get_triggers_key(chat_id),
+            RedisConnection::get_trigger_fired_key(chat_id),
+        ];
+        // Id is followed by a letter in every key, so these patterns
+        // don't match keys of other chats.
+        let patterns = [
+            RedisConnection::get_sticker_aliases_prefix(chat_id) + "*",
+            RedisConnection::get_resolved_prefix(chat_id) + "*",
+        ];
+        for pattern in patterns {
+            let mut iter: redis::AsyncIter<String> = self
+                .connection
+                .scan_match(pattern)
+                .await
+                .map_err(RedisStorageError::RedisError)?;
+            while let Some(key) = iter.next_item().await {
+                keys.push(key);
+            }
+        }
+        let del_result: RedisResult<i64> = self.connection.del(&keys).await;
+        match &del_result {
+            Ok(n) => {
+                uncount_dialogues(&dialogues);
+                tracing::info!("Purged chat {} ({} keys)", chat_id, n)
+            }
+            Err(e) => tracing::error!("Failed to purge chat: {}", e),
+        }
+        del_result.map(drop).map_err(RedisStorageError::RedisError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chat_id() {
+        assert_eq!(RedisConnection::parse_chat_id("chat:123aliases"), Some(123));
+        assert_eq!(
+            RedisConnection::parse_chat_id("chat:-100123sticker:abc"),
+            Some(-100123)
+        );
+        assert_eq!(RedisConnection::parse_chat_id("chat:settings"), None);
+        assert_eq!(RedisConnection::parse_chat_id("other:1aliases"), None);
+    }
+}
@@ -0,0 +1,489 @@
+//! In-memory storage.
+//!
+//! Mirrors behaviour of `RedisConnection` for tests without a server,
+//! except that nothing expires by itself.
+
+use super::{uncount_dialogues, RedisStorageError, Storage, DIALOGUE_TTL};
+use crate::alias::Target;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Alias value: encoded target and file unique id of the target (if any).
+type Entry = (String, Option<String>);
+
+#[derive(Default)]
+pub struct MemoryStorage {
+    aliases: HashMap<i64, BTreeMap<String, Entry>>,
+    settings: HashMap<(i64, String), String>,
+    resolved: HashMap<(i64, i32), HashSet<String>>,
+    dialogues: HashMap<(i64, Option<i64>), String>,
+    dialogue_times: HashMap<(i64, Option<i64>), i64>,
+    banned: HashSet<i64>,
+    bot_settings: HashMap<String, String>,
+    removed: HashMap<i64, i64>,
+    broken: HashMap<(i64, String), i64>,
+    schedules: HashMap<(i64, u64), String>,
+    last_schedule_id: u64,
+    triggers: HashMap<(i64, u64), String>,
+    trigger_fired: HashMap<(i64, u64), i64>,
+    last_trigger_id: u64,
+}
+
+impl MemoryStorage {
+    /// Remove dialogues with matching chat and user ids.
+    ///
+    /// Returns number of removed dialogues.
+    fn remove_dialogues_where<F>(&mut self, matches: F) -> usize
+    where
+        F: Fn(&(i64, Option<i64>)) -> bool,
+    {
+        let keys: Vec<_> = self
+            .dialogues
+            .keys()
+            .filter(|key| matches(key))
+            .copied()
+            .collect();
+        let mut removed = vec![];
+        for key in keys {
+            removed.extend(self.dialogues.remove(&key));
+            self.dialogue_times.remove(&key);
+        }
+        uncount_dialogues(&removed);
+        removed.len()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn set_alias(
+        &mut self,
+        chat_id: i64,
+        alias: &str,
+        target: &Target,
+    ) -> Result<(), RedisStorageError> {
+        let entry = (target.encode(), target.unique_id().map(str::to_owned));
+        self.aliases
+            .entry(chat_id)
+            .or_default()
+            .insert(alias.to_owned(), entry);
+        Ok(())
+    }
+
+    async fn get_target(&mut self, chat_id: i64, alias: &str) -> Option<Target> {
+        let (value, unique_id) = self.aliases.get(&chat_id)?.get(alias)?;
+        Some(Target::decode(value).with_unique_id(unique_id.clone()))
+    }
+
+    async fn remove_alias(&mut self, chat_id: i64, alias: &str) -> Result<(), RedisStorageError> {
+        self.aliases
+            .get_mut(&chat_id)
+            .and_then(|aliases| aliases.remove(alias))
+            .map(drop)
+            .ok_or(RedisStorageError::AliasNotFound)
+    }
+
+    async fn rename_alias(
+        &mut self,
+        chat_id: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<(), RedisStorageError> {
+        let aliases = self.aliases.entry(chat_id).or_default();
+        if !aliases.contains_key(old) {
+            return Err(RedisStorageError::AliasNotFound);
+        }
+        if aliases.contains_key(new) {
+            return Err(RedisStorageError::AliasExists);
+        }
+        let entry = aliases.remove(old).expect("alias is checked above");
+        aliases.insert(new.to_owned(), entry);
+        Ok(())
+    }
+
+    async fn merge_aliases(
+        &mut self,
+        chat_id: i64,
+        target: &str,
+        aliases: &[&str],
+    ) -> Result<i64, RedisStorageError> {
+        let chat_aliases = self.aliases.entry(chat_id).or_default();
+        let entry = chat_aliases
+            .get(target)
+            .cloned()
+            .ok_or(RedisStorageError::AliasNotFound)?;
+        let mut changed = 0;
+        for alias in aliases {
+            let old = chat_aliases.insert(alias.to_string(), entry.clone());
+            if old.map(|(value, _)| value) != Some(entry.0.clone()) {
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    }
+
+    async fn get_sticker_aliases(&mut self, chat_id: i64, unique_id: &str) -> Option<Vec<String>> {
+        let aliases = match self.aliases.get(&chat_id) {
+            Some(aliases) => aliases,
+            None => return Some(vec![]),
+        };
+        Some(
+            aliases
+                .iter()
+                .filter(|(_, (_, uid))| uid.as_deref() == Some(unique_id))
+                .map(|(alias, _)| alias.clone())
+                .collect(),
+        )
+    }
+
+    async fn get_targets(&mut self, chat_id: i64, aliases: &[&str]) -> Option<Vec<Option<Target>>> {
+        let mut targets = vec![];
+        for alias in aliases {
+            targets.push(self.get_target(chat_id, alias).await);
+        }
+        Some(targets)
+    }
+
+    async fn scan_aliases(&mut self, chat_id: i64) -> Option<Vec<(String, String)>> {
+        let aliases = match self.aliases.get(&chat_id) {
+            Some(aliases) => aliases,
+            None => return Some(vec![]),
+        };
+        Some(
+            aliases
+                .iter()
+                .map(|(alias, (value, _))| (alias.clone(), value.clone()))
+                .collect(),
+        )
+    }
+
+    async fn count_aliases(&mut self, chat_id: i64) -> Result<usize, RedisStorageError> {
+        Ok(self
+            .aliases
+            .get(&chat_id)
+            .map_or(0, |aliases| aliases.len()))
+    }
+
+    async fn set_broken(
+        &mut self,
+        chat_id: i64,
+        media_key: &str,
+        broken_at: Option<i64>,
+    ) -> Result<(), RedisStorageError> {
+        let key = (chat_id, media_key.to_owned());
+        match broken_at {
+            Some(time) => self.broken.insert(key, time),
+            None => self.broken.remove(&key),
+        };
+        Ok(())
+    }
+
+    async fn get_broken(&mut self, chat_id: i64) -> Result<Vec<String>, RedisStorageError> {
+        Ok(self
+            .broken
+            .keys()
+            .filter(|(id, _)| *id == chat_id)
+            .map(|(_, key)| key.clone())
+            .collect())
+    }
+
+    async fn get_broken_at(
+        &mut self,
+        chat_id: i64,
+        media_key: &str,
+    ) -> Result<Option<i64>, RedisStorageError> {
+        Ok(self.broken.get(&(chat_id, media_key.to_owned())).copied())
+    }
+
+    async fn set_setting(
+        &mut self,
+        chat_id: i64,
+        name: &str,
+        value: &str,
+    ) -> Result<(), RedisStorageError> {
+        self.settings
+            .insert((chat_id, name.to_owned()), value.to_owned());
+        Ok(())
+    }
+
+    async fn get_settings(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(String, String)>, RedisStorageError> {
+        Ok(self
+            .settings
+            .iter()
+            .filter(|((id, _), _)| *id == chat_id)
+            .map(|((_, name), value)| (name.clone(), value.clone()))
+            .collect())
+    }
+
+    async fn mark_resolved(
+        &mut self,
+        chat_id: i64,
+        message_id: i32,
+        aliases: &[&str],
+    ) -> Result<Vec<String>, RedisStorageError> {
+        let resolved = self.resolved.entry((chat_id, message_id)).or_default();
+        Ok(aliases
+            .iter()
+            .filter(|alias| resolved.insert(alias.to_string()))
+            .map(|alias| alias.to_string())
+            .collect())
+    }
+
+    async fn set_dialogue_value(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+        value: String,
+    ) -> Result<(), RedisStorageError> {
+        self.dialogues.insert((chat_id, from_id), value);
+        let now = chrono::Utc::now().timestamp();
+        self.dialogue_times.insert((chat_id, from_id), now);
+        Ok(())
+    }
+
+    async fn get_dialogue_value(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+    ) -> Result<Option<String>, RedisStorageError> {
+        Ok(self.dialogues.get(&(chat_id, from_id)).cloned())
+    }
+
+    async fn remove_dialogue(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+    ) -> Result<(), RedisStorageError> {
+        self.dialogue_times.remove(&(chat_id, from_id));
+        self.dialogues
+            .remove(&(chat_id, from_id))
+            .map(drop)
+            .ok_or(RedisStorageError::DialogueNotFound)
+    }
+
+    async fn remove_chat_dialogues(&mut self, chat_id: i64) -> Result<usize, RedisStorageError> {
+        Ok(self.remove_dialogues_where(|(id, _)| *id == chat_id))
+    }
+
+    async fn remove_user_dialogues(&mut self, user_id: i64) -> Result<usize, RedisStorageError> {
+        Ok(self.remove_dialogues_where(|(_, from_id)| *from_id == Some(user_id)))
+    }
+
+    async fn remove_stale_dialogues(&mut self, now: i64) -> Result<usize, RedisStorageError> {
+        let ttl = match *DIALOGUE_TTL {
+            Some(ttl) => ttl as i64,
+            None => return Ok(0),
+        };
+        let mut stale = vec![];
+        for key in self.dialogues.keys() {
+            let time = *self.dialogue_times.entry(*key).or_insert(now);
+            if time + ttl <= now {
+                stale.push(*key);
+            }
+        }
+        Ok(self.remove_dialogues_where(|key| stale.contains(key)))
+    }
+
+    async fn scan_dialogue_values(&mut self) -> Result<Vec<String>, RedisStorageError> {
+        Ok(self.dialogues.values().cloned().collect())
+    }
+
+    async fn get_chat_dialogue_values(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<String>, RedisStorageError> {
+        Ok(self
+            .dialogues
+            .iter()
+            .filter(|((id, _), _)| *id == chat_id)
+            .map(|(_, value)| value.clone())
+            .collect())
+    }
+
+    async fn scan_chat_ids(&mut self) -> Result<Vec<i64>, RedisStorageError> {
+        let mut ids: Vec<i64> = self
+            .aliases
+            .keys()
+            .copied()
+            .chain(self.settings.keys().map(|(id, _)| *id))
+            .chain(self.resolved.keys().map(|(id, _)| *id))
+            .chain(self.dialogues.keys().map(|(id, _)| *id))
+            .chain(self.broken.keys().map(|(id, _)| *id))
+            .chain(self.schedules.keys().map(|(id, _)| *id))
+            .chain(self.triggers.keys().map(|(id, _)| *id))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    async fn purge_chat(&mut self, chat_id: i64) -> Result<(), RedisStorageError> {
+        self.aliases.remove(&chat_id);
+        self.settings.retain(|(id, _), _| *id != chat_id);
+        self.resolved.retain(|(id, _), _| *id != chat_id);
+        self.remove_dialogues_where(|(id, _)| *id == chat_id);
+        self.broken.retain(|(id, _), _| *id != chat_id);
+        self.schedules.retain(|(id, _), _| *id != chat_id);
+        self.triggers.retain(|(id, _), _| *id != chat_id);
+        self.trigger_fired.retain(|(id, _), _| *id != chat_id);
+        Ok(())
+    }
+
+    async fn migrate_chat(&mut self, from: i64, to: i64) -> Result<usize, RedisStorageError> {
+        let mut moved = 0;
+        if let Some(old) = self.aliases.remove(&from) {
+            let new = self.aliases.entry(to).or_default();
+            for (alias, entry) in old {
+                new.entry(alias).or_insert(entry);
+            }
+            moved += 1;
+        }
+        fn move_keys<K: Eq + std::hash::Hash + Clone, V>(
+            map: &mut HashMap<(i64, K), V>,
+            from: i64,
+            to: i64,
+        ) -> usize {
+            let keys: Vec<_> = map.keys().filter(|(id, _)| *id == from).cloned().collect();
+            for (id, key) in &keys {
+                let value = map
+                    .remove(&(*id, key.clone()))
+                    .expect("key is listed above");
+                map.entry((to, key.clone())).or_insert(value);
+            }
+            keys.len()
+        }
+        moved += move_keys(&mut self.settings, from, to).min(1);
+        moved += move_keys(&mut self.resolved, from, to);
+        moved += move_keys(&mut self.dialogues, from, to).min(1);
+        moved += move_keys(&mut self.dialogue_times, from, to).min(1);
+        moved += move_keys(&mut self.broken, from, to).min(1);
+        moved += move_keys(&mut self.schedules, from, to).min(1);
+        moved += move_keys(&mut self.triggers, from, to).min(1);
+        moved += move_keys(&mut self.trigger_fired, from, to).min(1);
+        Ok(moved)
+    }
+
+    async fn set_removed(
+        &mut self,
+        chat_id: i64,
+        removed_at: Option<i64>,
+    ) -> Result<(), RedisStorageError> {
+        match removed_at {
+            Some(time) => self.removed.insert(chat_id, time),
+            None => self.removed.remove(&chat_id),
+        };
+        Ok(())
+    }
+
+    async fn scan_removed(&mut self) -> Result<Vec<(i64, i64)>, RedisStorageError> {
+        Ok(self.removed.iter().map(|(&id, &time)| (id, time)).collect())
+    }
+
+    async fn add_schedule(&mut self, chat_id: i64, job: &str) -> Result<u64, RedisStorageError> {
+        self.last_schedule_id += 1;
+        let id = self.last_schedule_id;
+        self.schedules.insert((chat_id, id), job.to_owned());
+        Ok(id)
+    }
+
+    async fn get_schedules(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(u64, String)>, RedisStorageError> {
+        let mut jobs: Vec<(u64, String)> = self
+            .schedules
+            .iter()
+            .filter(|((id, _), _)| *id == chat_id)
+            .map(|((_, job_id), job)| (*job_id, job.clone()))
+            .collect();
+        jobs.sort_unstable();
+        Ok(jobs)
+    }
+
+    async fn remove_schedule(&mut self, chat_id: i64, id: u64) -> Result<(), RedisStorageError> {
+        self.schedules
+            .remove(&(chat_id, id))
+            .map(drop)
+            .ok_or(RedisStorageError::ScheduleNotFound)
+    }
+
+    async fn scan_schedules(&mut self) -> Result<Vec<(i64, u64, String)>, RedisStorageError> {
+        Ok(self
+            .schedules
+            .iter()
+            .map(|(&(chat_id, id), job)| (chat_id, id, job.clone()))
+            .collect())
+    }
+
+    async fn add_trigger(&mut self, chat_id: i64, trigger: &str) -> Result<u64, RedisStorageError> {
+        self.last_trigger_id += 1;
+        let id = self.last_trigger_id;
+        self.triggers.insert((chat_id, id), trigger.to_owned());
+        Ok(id)
+    }
+
+    async fn get_triggers(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(u64, String)>, RedisStorageError> {
+        let mut triggers: Vec<(u64, String)> = self
+            .triggers
+            .iter()
+            .filter(|((id, _), _)| *id == chat_id)
+            .map(|((_, trigger_id), trigger)| (*trigger_id, trigger.clone()))
+            .collect();
+        triggers.sort_unstable();
+        Ok(triggers)
+    }
+
+    async fn remove_trigger(&mut self, chat_id: i64, id: u64) -> Result<(), RedisStorageError> {
+        self.trigger_fired.remove(&(chat_id, id));
+        self.triggers
+            .remove(&(chat_id, id))
+            .map(drop)
+            .ok_or(RedisStorageError::TriggerNotFound)
+    }
+
+    async fn fire_trigger(
+        &mut self,
+        chat_id: i64,
+        id: u64,
+        now: i64,
+        cooldown: i64,
+    ) -> Result<bool, RedisStorageError> {
+        let last = self.trigger_fired.entry((chat_id, id)).or_insert(i64::MIN);
+        if now.saturating_sub(*last) < cooldown {
+            return Ok(false);
+        }
+        *last = now;
+        Ok(true)
+    }
+
+    async fn set_banned(&mut self, id: i64, banned: bool) -> Result<(), RedisStorageError> {
+        if banned {
+            self.banned.insert(id);
+        } else {
+            self.banned.remove(&id);
+        }
+        Ok(())
+    }
+
+    async fn is_banned(&mut self, id: i64) -> Result<bool, RedisStorageError> {
+        Ok(self.banned.contains(&id))
+    }
+
+    async fn get_bot_setting(&mut self, name: &str) -> Result<Option<String>, RedisStorageError> {
+        Ok(self.bot_settings.get(name).cloned())
+    }
+
+    async fn set_bot_setting(&mut self, name: &str, value: &str) -> Result<(), RedisStorageError> {
+        self.bot_settings.insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,396 @@
+//! Database connection.
+//!
+//! Handles and provides an interface to the database for bot.
+
+mod connection;
+#[cfg(test)]
+mod memory;
+
+use crate::alias::Target;
+use crate::dialogue::{stored, Dialogue};
+use crate::metrics;
+use async_trait::async_trait;
+pub use connection::RedisConnection;
+#[cfg(test)]
+pub use memory::MemoryStorage;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+
+/// Environment variable with the number of days a dialogue is kept after
+/// its last change (0 - kept forever).
+pub const DIALOGUE_TTL_VAR: &str = "DIALOGUE_TTL_DAYS";
+
+const DEFAULT_DIALOGUE_TTL_DAYS: usize = 30;
+
+/// How long dialogues are kept, in seconds (`None` - forever).
+static DIALOGUE_TTL: Lazy<Option<usize>> = Lazy::new(|| {
+    let days = match std::env::var(DIALOGUE_TTL_VAR) {
+        Ok(days) => days.trim().parse().unwrap_or_else(|_| {
+            tracing::warn!("Invalid {} '{}'", DIALOGUE_TTL_VAR, days);
+            DEFAULT_DIALOGUE_TTL_DAYS
+        }),
+        Err(_) => DEFAULT_DIALOGUE_TTL_DAYS,
+    };
+    (days > 0).then_some(days * 24 * 60 * 60)
+});
+
+/// Stop counting removed dialogues (serialized) in
+/// `metrics::ACTIVE_DIALOGUES`.
+fn uncount_dialogues(values: &[String]) {
+    let active = values
+        .iter()
+        .filter_map(|value| stored::deserialize(value).ok())
+        .filter(Dialogue::is_active)
+        .count();
+    metrics::ACTIVE_DIALOGUES.sub(active as i64);
+}
+
+/// Storage of aliases, chat settings and dialogue states.
+///
+/// Implemented by `RedisConnection`, tests use in-memory `MemoryStorage`.
+#[async_trait]
+pub trait Storage: Send {
+    /// Store alias-target mapping.
+    ///
+    /// If the alias is already tied to some target, overwrite it
+    /// so the alias will be mapped to a new one (for given
+    /// `chat_id`). File unique ID of the target (if any) is used
+    /// for the reverse index.
+    async fn set_alias(
+        &mut self,
+        chat_id: i64,
+        alias: &str,
+        target: &Target,
+    ) -> Result<(), RedisStorageError>;
+
+    /// Obtain target of given alias in the chat (if any).
+    async fn get_target(&mut self, chat_id: i64, alias: &str) -> Option<Target>;
+
+    /// Unmap (remove) the alias for given chat id.
+    async fn remove_alias(&mut self, chat_id: i64, alias: &str) -> Result<(), RedisStorageError>;
+
+    /// Atomically move target of alias `old` to alias `new`.
+    ///
+    /// Fails with `AliasNotFound` if `old` is not assigned and with
+    /// `AliasExists` if `new` is already taken.
+    async fn rename_alias(
+        &mut self,
+        chat_id: i64,
+        old: &str,
+        new: &str,
+    ) -> Result<(), RedisStorageError>;
+
+    /// Atomically point all `aliases` to the target of alias `target`.
+    ///
+    /// Missing aliases are created. Returns number of aliases that were
+    /// changed or created, fails with `AliasNotFound` if `target` is not
+    /// assigned.
+    async fn merge_aliases(
+        &mut self,
+        chat_id: i64,
+        target: &str,
+        aliases: &[&str],
+    ) -> Result<i64, RedisStorageError>;
+
+    /// Get aliases of the media in the chat (sorted).
+    ///
+    /// Uses the reverse index by `unique_id`. Aliases saved before the
+    /// index existed are added to it by `media::backfill_index`.
+    async fn get_sticker_aliases(&mut self, chat_id: i64, unique_id: &str) -> Option<Vec<String>>;
+
+    /// Get targets of the aliases in the chat (`None` for missing ones).
+    async fn get_targets(&mut self, chat_id: i64, aliases: &[&str]) -> Option<Vec<Option<Target>>>;
+
+    /// Get all pairs of alias and encoded target in the chat.
+    async fn scan_aliases(&mut self, chat_id: i64) -> Option<Vec<(String, String)>>;
+
+    /// Get the number of aliases in the chat.
+    async fn count_aliases(&mut self, chat_id: i64) -> Result<usize, RedisStorageError>;
+
+    /// Mark media with given key (see `Target::media_key`) as broken
+    /// since `broken_at` (unix timestamp), `None` removes the mark.
+    async fn set_broken(
+        &mut self,
+        chat_id: i64,
+        media_key: &str,
+        broken_at: Option<i64>,
+    ) -> Result<(), RedisStorageError>;
+
+    /// Get keys of broken media in the chat.
+    async fn get_broken(&mut self, chat_id: i64) -> Result<Vec<String>, RedisStorageError>;
+
+    /// Get time (unix timestamp) the media with given key was marked
+    /// broken at, `None` if it isn't marked.
+    async fn get_broken_at(
+        &mut self,
+        chat_id: i64,
+        media_key: &str,
+    ) -> Result<Option<i64>, RedisStorageError>;
+
+    /// Set the chat setting `name` to `value`.
+    async fn set_setting(
+        &mut self,
+        chat_id: i64,
+        name: &str,
+        value: &str,
+    ) -> Result<(), RedisStorageError>;
+
+    /// Get all pairs of setting name and value in the chat.
+    async fn get_settings(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(String, String)>, RedisStorageError>;
+
+    /// Remember `aliases` as resolved in the message.
+    ///
+    /// Returns the aliases that were not resolved in it before.
+    async fn mark_resolved(
+        &mut self,
+        chat_id: i64,
+        message_id: i32,
+        aliases: &[&str],
+    ) -> Result<Vec<String>, RedisStorageError>;
+
+    /// Save serialized dialogue of the user in the chat.
+    ///
+    /// Time of the change is saved along, see `remove_stale_dialogues`.
+    async fn set_dialogue_value(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+        value: String,
+    ) -> Result<(), RedisStorageError>;
+
+    /// Get serialized dialogue of the user in the chat (if any).
+    async fn get_dialogue_value(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+    ) -> Result<Option<String>, RedisStorageError>;
+
+    /// Remove dialogue.
+    async fn remove_dialogue(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+    ) -> Result<(), RedisStorageError>;
+
+    /// Remove dialogues of all users in the chat.
+    ///
+    /// Returns number of removed dialogues. Active ones are subtracted
+    /// from `metrics::ACTIVE_DIALOGUES`.
+    async fn remove_chat_dialogues(&mut self, chat_id: i64) -> Result<usize, RedisStorageError>;
+
+    /// Remove dialogues of the user in all chats.
+    ///
+    /// Returns number of removed dialogues. Active ones are subtracted
+    /// from `metrics::ACTIVE_DIALOGUES`.
+    async fn remove_user_dialogues(&mut self, user_id: i64) -> Result<usize, RedisStorageError>;
+
+    /// Remove dialogues not changed for `DIALOGUE_TTL_DAYS` before `now`
+    /// (unix timestamp).
+    ///
+    /// Dialogues saved without time of the change are treated as changed
+    /// at `now`. Returns number of removed dialogues. Active ones are
+    /// subtracted from `metrics::ACTIVE_DIALOGUES`.
+    async fn remove_stale_dialogues(&mut self, now: i64) -> Result<usize, RedisStorageError>;
+
+    /// Get serialized dialogues of all chats.
+    async fn scan_dialogue_values(&mut self) -> Result<Vec<String>, RedisStorageError>;
+
+    /// Get serialized dialogues of all users in the chat.
+    async fn get_chat_dialogue_values(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<String>, RedisStorageError>;
+
+    /// Get ids of all chats with any stored data.
+    async fn scan_chat_ids(&mut self) -> Result<Vec<i64>, RedisStorageError>;
+
+    /// Remove everything stored for the chat.
+    ///
+    /// Active dialogues of the chat are subtracted from
+    /// `metrics::ACTIVE_DIALOGUES`.
+    async fn purge_chat(&mut self, chat_id: i64) -> Result<(), RedisStorageError>;
+
+    /// Move everything stored for chat `from` to chat `to`.
+    ///
+    /// Data already stored for `to` takes precedence. Returns number of
+    /// moved storages.
+    async fn migrate_chat(&mut self, from: i64, to: i64) -> Result<usize, RedisStorageError>;
+
+    /// Remember the time (unix timestamp) the bot was removed from the
+    /// chat, `None` forgets it.
+    async fn set_removed(
+        &mut self,
+        chat_id: i64,
+        removed_at: Option<i64>,
+    ) -> Result<(), RedisStorageError>;
+
+    /// Get pairs of chat id and time the bot was removed from the chat.
+    async fn scan_removed(&mut self) -> Result<Vec<(i64, i64)>, RedisStorageError>;
+
+    /// Save a scheduled job (serialized) of the chat and return its id.
+    ///
+    /// Ids are unique among jobs of all chats.
+    async fn add_schedule(&mut self, chat_id: i64, job: &str) -> Result<u64, RedisStorageError>;
+
+    /// Get pairs of id and serialized job of scheduled jobs of the chat.
+    async fn get_schedules(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<(u64, String)>, RedisStorageError>;
+
+    /// Remove scheduled job of the chat.
+    ///
+    /// Fails with `ScheduleNotFound` if the chat has no job with this id.
+    async fn remove_schedule(&mut self, chat_id: i64, id: u64) -> Result<(), RedisStorageError>;
+
+    /// Get chat id, job id and serialized job of scheduled jobs of all chats.
+    async fn scan_schedules(&mut self) -> Result<Vec<(i64, u64, String)>, RedisStorageError>;
+
+    /// Save a keyword trigger (serialized) of the chat and return its id.
+    ///
+    /// Ids are unique among triggers of all chats.
+    async fn add_trigger(&mut self, chat_id: i64, trigger: &str) -> Result<u64, RedisStorageError>;
+
+    /// Get pairs of id and serialized trigger of keyword triggers of the chat.
+    async fn get_triggers(&mut self, chat_id: i64)
+        -> Result<Vec<(u64, String)>, RedisStorageError>;
+
+    /// Remove keyword trigger of the chat (with the time it last fired).
+    ///
+    /// Fails with `TriggerNotFound` if the chat has no trigger with this id.
+    async fn remove_trigger(&mut self, chat_id: i64, id: u64) -> Result<(), RedisStorageError>;
+
+    /// Remember that the trigger fired at `now`, unless it already fired
+    /// less than `cooldown` seconds before.
+    ///
+    /// Returns whether the trigger fired.
+    async fn fire_trigger(
+        &mut self,
+        chat_id: i64,
+        id: u64,
+        now: i64,
+        cooldown: i64,
+    ) -> Result<bool, RedisStorageError>;
+
+    /// Ban or unban the chat or user with given id.
+    async fn set_banned(&mut self, id: i64, banned: bool) -> Result<(), RedisStorageError>;
+
+    /// Check whether the chat or user with given id is banned.
+    async fn is_banned(&mut self, id: i64) -> Result<bool, RedisStorageError>;
+
+    /// Get value of the bot-wide setting `name` (if set).
+    async fn get_bot_setting(&mut self, name: &str) -> Result<Option<String>, RedisStorageError>;
+
+    /// Set the bot-wide setting `name` to `value`.
+    async fn set_bot_setting(&mut self, name: &str, value: &str) -> Result<(), RedisStorageError>;
+
+    /// Check whether the storage responds.
+    async fn ping(&mut self) -> bool;
+}
+
+/// Helpers built on top of `Storage` methods.
+///
+/// Dialogue storage is similar to `teloxide::dispatching::dialogue::Storage`,
+/// but with different dialogue for each user in the chat.
+impl dyn Storage {
+    /// Get mapping of all targets to aliases in the chat.
+    /// Intended for listing the aliases.
+    pub async fn get_aliases(&mut self, chat_id: i64) -> Option<HashMap<String, Vec<String>>> {
+        let pairs = self.scan_aliases(chat_id).await?;
+        let mut mapping: HashMap<String, Vec<String>> = HashMap::new();
+        for (alias, target) in pairs {
+            mapping.entry(target).or_default().push(alias);
+        }
+        Some(mapping)
+    }
+
+    /// Update a dialogue in the storage.
+    ///
+    /// Saves the `dialogue` for given chat and user in the current
+    /// format version.
+    pub async fn update_dialogue(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+        dialogue: &Dialogue,
+    ) -> Result<(), RedisStorageError> {
+        let value: String = stored::serialize(dialogue).map_err(|err| {
+            tracing::error!("Failed to serialize dialogue: {}", err);
+            RedisStorageError::SerdeError(err)
+        })?;
+        self.set_dialogue_value(chat_id, from_id, value).await
+    }
+
+    /// Retrieve a dialogue from the storage.
+    ///
+    /// Give the `dialogue` for given chat and user, migrated from the
+    /// format version it was saved in. Fails with `SerdeError` if the
+    /// dialogue can't be migrated.
+    pub async fn get_dialogue(
+        &mut self,
+        chat_id: i64,
+        from_id: Option<i64>,
+    ) -> Result<Option<Dialogue>, RedisStorageError> {
+        self.get_dialogue_value(chat_id, from_id)
+            .await?
+            .map(|v| stored::deserialize(&v))
+            .transpose()
+            .map_err(RedisStorageError::SerdeError)
+    }
+
+    /// Retrieve dialogues of all users in the chat.
+    ///
+    /// Dialogues that can't be migrated are skipped.
+    pub async fn get_chat_dialogues(
+        &mut self,
+        chat_id: i64,
+    ) -> Result<Vec<Dialogue>, RedisStorageError> {
+        Ok(self
+            .get_chat_dialogue_values(chat_id)
+            .await?
+            .iter()
+            .filter_map(|v| stored::deserialize(v).ok())
+            .collect())
+    }
+}
+
+/// An error returned from `Storage` implementation.
+#[derive(Debug)]
+pub enum RedisStorageError {
+    SerdeError(serde_json::Error),
+
+    RedisError(redis::RedisError),
+
+    /// Returned from [`Storage::remove_dialogue`].
+    DialogueNotFound,
+
+    /// Returned from [`Storage::remove_alias`], [`Storage::rename_alias`],
+    /// [`Storage::merge_aliases`]
+    AliasNotFound,
+
+    /// Returned from [`Storage::rename_alias`]
+    AliasExists,
+
+    /// Returned from [`Storage::remove_schedule`]
+    ScheduleNotFound,
+
+    /// Returned from [`Storage::remove_trigger`]
+    TriggerNotFound,
+}
+
+impl std::fmt::Display for RedisStorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RedisStorageError::SerdeError(e) => write!(f, "serialization error: {}", e),
+            RedisStorageError::RedisError(e) => write!(f, "redis error: {}", e),
+            RedisStorageError::DialogueNotFound => write!(f, "dialogue not found"),
+            RedisStorageError::AliasNotFound => write!(f, "alias not found"),
+            RedisStorageError::AliasExists => write!(f, "alias already exists"),
+            RedisStorageError::ScheduleNotFound => write!(f, "scheduled job not found"),
+            RedisStorageError::TriggerNotFound => write!(f, "trigger not found"),
+        }
+    }
+}
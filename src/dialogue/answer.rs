@@ -2,12 +2,20 @@
 pub enum Answer {
     // Any string or unsupported command
     String(String),
-    Sticker(teloxide::types::Sticker),
+    // Sticker or other media an alias can be assigned to
+    Media(crate::alias::Target),
     Command(crate::commands::Command),
+    // Text or caption of an edited message
+    Edited(String),
+    // Press of inline keyboard button attached to message `message_id`
+    Callback {
+        data: super::CallbackData,
+        message_id: i32,
+    },
 }
 
 // Struct for packing arguments passed to transition funcitons
 pub struct Args {
     pub ans: Answer,
-    pub db: std::sync::Arc<tokio::sync::Mutex<crate::db::RedisConnection>>,
+    pub db: std::sync::Arc<tokio::sync::Mutex<dyn crate::db::Storage>>,
 }
@@ -0,0 +1,90 @@
+//! Inline keyboard callbacks.
+//!
+//! Defines data attached to inline keyboard buttons of dialogues.
+
+/// Action requested by pressing an inline keyboard button.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum CallbackData {
+    /// Select or deselect an option with given index.
+    Toggle(usize),
+    /// Apply the selection.
+    Confirm,
+    /// Continue the current process.
+    More,
+    /// Finish the current process keeping the results.
+    Done,
+    /// Abort the current process.
+    Cancel,
+    /// Change the setting with given index in the settings menu.
+    Setting(usize),
+    /// Cancel the scheduled post with given id.
+    Unschedule(u64),
+    /// Delete the keyword trigger with given id.
+    Untrigger(u64),
+}
+
+impl CallbackData {
+    /// Parse data received in callback query.
+    pub fn parse(data: &str) -> Option<CallbackData> {
+        match data {
+            "confirm" => Some(CallbackData::Confirm),
+            "more" => Some(CallbackData::More),
+            "done" => Some(CallbackData::Done),
+            "cancel" => Some(CallbackData::Cancel),
+            _ => match data.split_once(':') {
+                Some(("toggle", i)) => i.parse().ok().map(CallbackData::Toggle),
+                Some(("setting", i)) => i.parse().ok().map(CallbackData::Setting),
+                Some(("unschedule", id)) => id.parse().ok().map(CallbackData::Unschedule),
+                Some(("untrigger", id)) => id.parse().ok().map(CallbackData::Untrigger),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl std::fmt::Display for CallbackData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallbackData::Toggle(i) => write!(f, "toggle:{}", i),
+            CallbackData::Confirm => write!(f, "confirm"),
+            CallbackData::More => write!(f, "more"),
+            CallbackData::Done => write!(f, "done"),
+            CallbackData::Cancel => write!(f, "cancel"),
+            CallbackData::Setting(i) => write!(f, "setting:{}", i),
+            CallbackData::Unschedule(id) => write!(f, "unschedule:{}", id),
+            CallbackData::Untrigger(id) => write!(f, "untrigger:{}", id),
+        }
+    }
+}
+
+/// Create inline keyboard button sending `data` when pressed.
+pub fn button(text: &str, data: CallbackData) -> teloxide::types::InlineKeyboardButton {
+    teloxide::types::InlineKeyboardButton::callback(text.to_owned(), data.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_callback_data() {
+        let cases = vec![
+            CallbackData::Toggle(0),
+            CallbackData::Toggle(42),
+            CallbackData::Confirm,
+            CallbackData::More,
+            CallbackData::Done,
+            CallbackData::Cancel,
+            CallbackData::Setting(3),
+            CallbackData::Unschedule(12),
+            CallbackData::Untrigger(7),
+        ];
+        for data in cases {
+            assert_eq!(CallbackData::parse(&data.to_string()), Some(data));
+        }
+        assert_eq!(CallbackData::parse("toggle:"), None);
+        assert_eq!(CallbackData::parse("toggle:-1"), None);
+        assert_eq!(CallbackData::parse("setting:x"), None);
+        assert_eq!(CallbackData::parse("unknown"), None);
+    }
+}
@@ -1,9 +1,15 @@
 mod answer;
+mod callback;
 mod states;
+pub mod stored;
+#[cfg(test)]
+mod tests;
 
 pub use answer::{Answer, Args};
+pub use callback::{button, CallbackData};
 use derive_more::From;
 use serde::{Deserialize, Serialize};
+pub use states::{broken_notice, extract_targets, post_targets};
 use states::{AddNamesState, AddStickerState, RemoveNamesState, ReplacingState};
 use teloxide::macros::Transition;
 
@@ -19,6 +25,29 @@ pub enum Dialogue {
     Replacing(ReplacingState),
 }
 
+impl Dialogue {
+    /// Name of the current state for logs.
+    pub fn state_name(&self) -> &'static str {
+        match self {
+            Dialogue::AddSticker(_) => "AddSticker",
+            Dialogue::AddNames(_) => "AddNames",
+            Dialogue::RemoveNames(_) => "RemoveNames",
+            Dialogue::Replacing(_) => "Replacing",
+        }
+    }
+
+    /// Whether the dialogue is in progress (not in the default state).
+    ///
+    /// Finished addition only waits for presses on its summary keyboard.
+    pub fn is_active(&self) -> bool {
+        match self {
+            Dialogue::Replacing(_) => false,
+            Dialogue::AddNames(state) => !state.finished,
+            _ => true,
+        }
+    }
+}
+
 impl Default for Dialogue {
     fn default() -> Self {
         Self::Replacing(ReplacingState)
@@ -1,121 +1,278 @@
 use crate::{
-    commands::{handle_help, handle_list, handle_start, Command},
-    db::RedisConnection,
-    dialogue::{Answer, Args, Dialogue},
-    utils::format_log_chat,
+    alias::{self, Target},
+    cache,
+    commands::{get_matching, handle_command, Command},
+    db::Storage,
+    dialogue::{callback::button, states::ReplacingState, Answer, Args, CallbackData, Dialogue},
 };
 use frunk::Generic;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use teloxide::prelude::*;
-use teloxide::types::Sticker;
+use teloxide::types::InlineKeyboardMarkup;
 // TODO: get rid of using tokio's Mutex https://tokio.rs/tokio/tutorial/channels
 use tokio::sync::Mutex;
 
 #[derive(Clone, Generic, Serialize, Deserialize)]
 pub struct AddNamesState {
-    pub sticker: Sticker,
+    pub target: Target,
+    /// Aliases saved during this addition.
+    #[serde(default)]
+    pub saved: Vec<String>,
+    /// Previous targets of saved aliases which already existed, restored
+    /// if the addition is cancelled.
+    #[serde(default)]
+    pub replaced: Vec<(String, Target)>,
+    /// Whether aliases were saved and the state is only kept for the
+    /// summary keyboard.
+    #[serde(default)]
+    pub finished: bool,
+    /// Message with the summary keyboard (if it was sent).
+    #[serde(default)]
+    pub keyboard_message_id: Option<i32>,
+}
+
+impl AddNamesState {
+    pub fn new(target: Target) -> Self {
+        AddNamesState {
+            target,
+            saved: vec![],
+            replaced: vec![],
+            finished: false,
+            keyboard_message_id: None,
+        }
+    }
+
+    /// Keyboard attached to the summary of saved aliases.
+    fn keyboard() -> InlineKeyboardMarkup {
+        InlineKeyboardMarkup::default().append_row(vec![
+            button("Add more", CallbackData::More),
+            button("Done", CallbackData::Done),
+            button("Cancel", CallbackData::Cancel),
+        ])
+    }
+
+    /// Remove the keyboard from its message (if any).
+    async fn close_keyboard(&self, cx: &TransitionIn<AutoSend<Bot>>) {
+        if let Some(message_id) = self.keyboard_message_id {
+            if let Err(e) = cx
+                .requester
+                .edit_message_reply_markup(cx.chat_id(), message_id)
+                .await
+            {
+                tracing::warn!("Could not remove keyboard: {}", e);
+                crate::metrics::telegram_error(&e);
+            }
+        }
+    }
 }
 
 #[teloxide(subtransition)]
 async fn add_names(
-    state: AddNamesState,
+    mut state: AddNamesState,
     cx: TransitionIn<AutoSend<Bot>>,
     args: Args,
 ) -> TransitionOut<Dialogue> {
     let ans: Answer = args.ans;
+    // The addition is over, everything but the summary keyboard and
+    // /cancel is handled as usual.
+    if state.finished
+        && !matches!(
+            ans,
+            Answer::Callback { .. } | Answer::Command(Command::Cancel)
+        )
+    {
+        state.close_keyboard(&cx).await;
+        let args = Args { ans, db: args.db };
+        return match ReplacingState.react(cx, args).await? {
+            DialogueStage::Next(Dialogue::Replacing(_)) => exit(),
+            stage => Ok(stage),
+        };
+    }
     match ans {
-        Answer::Sticker(_) => {
-            log::info!("{}", format_log_chat("Waiting for names", cx.chat_id()));
+        Answer::Media(_) => {
+            tracing::info!("Waiting for names");
             cx.answer(
-                "Sticker was already specified.\
+                "Media or text was already specified. \
                 Write aliases separated by space or use /cancel to stop adding them.",
             )
             .await?;
             next(state)
         }
         Answer::String(ans_str) => {
-            log::info!(
-                "{}",
-                format_log_chat("Received aliases, saving them...", cx.chat_id())
-            );
-            save_aliases(&state.sticker, &cx, &ans_str, args.db).await;
-            log::info!(
-                "{}",
-                format_log_chat("Finished saving aliases", cx.chat_id())
-            );
-            cx.answer("Aliases are set successfully!").await?;
-            exit()
+            tracing::info!("Received aliases, saving them...");
+            let (saved, failed) = save_aliases(&mut state, &cx, &ans_str, args.db).await;
+            tracing::info!("Finished saving aliases");
+            state.close_keyboard(&cx).await;
+
+            let mut summary = String::new();
+            if !saved.is_empty() {
+                summary.push_str(&format!("Saved aliases: {}\n", saved.join(" ")));
+            }
+            for (alias, reason) in failed {
+                summary.push_str(&format!("'{}' is not saved: {}\n", alias, reason));
+            }
+            if saved.is_empty() {
+                summary.push_str("Send other aliases or use /cancel to stop adding them.");
+                cx.answer(summary).await?;
+                state.keyboard_message_id = None;
+                return next(state);
+            }
+            summary.push_str("Press \"Add more\" to add other aliases to it.");
+            let message = cx
+                .answer(summary)
+                .reply_markup(AddNamesState::keyboard())
+                .await?;
+            state.keyboard_message_id = Some(message.id);
+            state.finished = true;
+            next(state)
         }
         Answer::Command(cmd) => {
-            respond_command(&cx, &cmd, args.db).await?;
+            respond_command(&cx, &cmd, args.db.clone()).await?;
             match cmd {
-                Command::Cancel => exit(),
+                Command::Cancel => {
+                    state.close_keyboard(&cx).await;
+                    revert(&state, &cx, args.db).await?;
+                    exit()
+                }
+                Command::ForgetMe => {
+                    state.close_keyboard(&cx).await;
+                    exit()
+                }
                 _ => next(state),
             }
         }
+        Answer::Edited(_) => next(state),
+        Answer::Callback { message_id, .. } if state.keyboard_message_id != Some(message_id) => {
+            tracing::info!("Ignoring press on outdated keyboard");
+            next(state)
+        }
+        Answer::Callback { data, .. } => match data {
+            CallbackData::More => {
+                state.close_keyboard(&cx).await;
+                state.keyboard_message_id = None;
+                state.finished = false;
+                cx.answer("Send more aliases separated by spaces.").await?;
+                next(state)
+            }
+            CallbackData::Done => {
+                state.close_keyboard(&cx).await;
+                cx.answer("Aliases are set successfully!").await?;
+                exit()
+            }
+            CallbackData::Cancel => {
+                state.close_keyboard(&cx).await;
+                revert(&state, &cx, args.db).await?;
+                exit()
+            }
+            _ => next(state),
+        },
     }
 }
 
 async fn respond_command(
     cx: &TransitionIn<AutoSend<Bot>>,
     cmd: &Command,
-    db: Arc<Mutex<RedisConnection>>,
+    db: Arc<Mutex<dyn Storage>>,
 ) -> Result<(), teloxide::RequestError> {
     match cmd {
         Command::Add => {
-            log::info!(
-                "{}",
-                format_log_chat("Ignoring /add at recieve names stage", cx.chat_id())
-            );
+            tracing::info!("Ignoring /add at recieve names stage");
             cx.answer("Already adding aliases.").await?;
         }
         Command::Remove => {
-            log::info!(
-                "{}",
-                format_log_chat("Ignoring /remove at removal stage", cx.chat_id())
-            );
+            tracing::info!("Ignoring /remove at removal stage");
             cx.answer("To remove aliases /cancel addition first.")
                 .await?;
         }
-        Command::Start => {
-            log::info!("{}", format_log_chat("Printed start message", cx.chat_id()));
-            handle_start(cx).await?;
-        }
-        Command::Help => {
-            log::info!("{}", format_log_chat("Printed help message", cx.chat_id()));
-            handle_help(cx).await?;
+        Command::Cancel => {
+            tracing::info!("Cancelling sticker addition");
         }
-        Command::List => {
-            log::info!("{}", format_log_chat("Listing aliases", cx.chat_id()));
+        _ => handle_command(cx, cmd, &db).await?,
+    }
+    Ok(())
+}
 
-            let mut db = db.lock().await;
-            if let Some(aliases) = db.get_aliases(cx.chat_id()).await {
-                handle_list(cx, aliases).await?;
+/// Undo the addition: remove saved aliases and restore previous targets
+/// of replaced ones, report it to `cx`.
+async fn revert(
+    state: &AddNamesState,
+    cx: &TransitionIn<AutoSend<Bot>>,
+    db: Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    tracing::info!("Reverting added aliases");
+    let mut db = db.lock().await;
+    for alias in &state.saved {
+        let previous = state
+            .replaced
+            .iter()
+            .find_map(|(replaced, target)| (replaced == alias).then_some(target));
+        // Alias could have been removed or renamed in the meantime.
+        let _ = match previous {
+            Some(target) => {
+                cache::set_alias(&cx.requester, &mut *db, cx.chat_id(), alias, target).await
             }
-
-            log::info!("{}", format_log_chat("Finished listing", cx.chat_id()));
-        }
-        Command::Cancel => {
-            log::info!(
-                "{}",
-                format_log_chat("Cancelling sticker addition", cx.chat_id())
-            );
-        }
+            None => db.remove_alias(cx.chat_id(), alias).await,
+        };
     }
+    drop(db);
+    let message = if state.saved.is_empty() {
+        "Cancelled alias addition."
+    } else if state.replaced.is_empty() {
+        "Cancelled, added aliases were removed."
+    } else {
+        "Cancelled, added aliases were removed and replaced ones restored."
+    };
+    cx.answer(message).await?;
     Ok(())
 }
 
+/// Save aliases from `text` for the target of the addition.
+///
+/// Aliases are normalized according to the chat matching. Saved aliases
+/// and previous targets of replaced ones are recorded in `state`.
+/// Returns saved aliases and aliases that failed to save with reasons.
 async fn save_aliases(
-    sticker: &Sticker,
+    state: &mut AddNamesState,
     cx: &TransitionIn<AutoSend<Bot>>,
     text: &str,
-    db: Arc<Mutex<RedisConnection>>,
-) {
+    db: Arc<Mutex<dyn Storage>>,
+) -> (Vec<String>, Vec<(String, String)>) {
     let aliases = text.split_whitespace();
     let mut db = db.lock().await;
+    let matching = get_matching(&mut *db, cx.chat_id()).await;
+    let (mut saved, mut failed) = (vec![], vec![]);
     for alias in aliases {
+        let prepared = match alias::prepare(alias, matching) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                failed.push((alias.to_owned(), e.to_string()));
+                continue;
+            }
+        };
+        let previous = db.get_target(cx.chat_id(), &prepared).await;
         // Maybe it makes sense to create the futures first and then join on them all?
-        db.set_alias(cx.chat_id(), alias, &sticker.file_id).await;
+        let result = cache::set_alias(
+            &cx.requester,
+            &mut *db,
+            cx.chat_id(),
+            &prepared,
+            &state.target,
+        )
+        .await;
+        match result {
+            Ok(()) => {
+                // Only the target from before the addition is restored.
+                if !state.saved.contains(&prepared) {
+                    if let Some(previous) = previous.filter(|target| *target != state.target) {
+                        state.replaced.push((prepared.clone(), previous));
+                    }
+                    state.saved.push(prepared.clone());
+                }
+                saved.push(prepared);
+            }
+            Err(_) => failed.push((alias.to_owned(), String::from("database error"))),
+        }
     }
+    (saved, failed)
 }
@@ -1,8 +1,9 @@
 use crate::{
-    commands::{handle_help, handle_list, handle_start, Command},
-    db::RedisConnection,
+    alias::Target,
+    commands::{handle_command, Command},
+    db::Storage,
     dialogue::{states::AddNamesState, Answer, Args, Dialogue},
-    utils::format_log_chat,
+    media::refresh,
 };
 use frunk::Generic;
 use serde::{Deserialize, Serialize};
@@ -10,6 +11,7 @@ use std::sync::Arc;
 use teloxide::prelude::*;
 use tokio::sync::Mutex;
 
+/// Waiting for media or a text snippet to assign aliases to.
 #[derive(Clone, Generic, Serialize, Deserialize)]
 pub struct AddStickerState;
 
@@ -21,83 +23,64 @@ async fn add_sticker(
 ) -> TransitionOut<Dialogue> {
     let ans: Answer = args.ans;
     match ans {
-        Answer::Sticker(sticker) => {
-            log::info!(
-                "{}",
-                format_log_chat("Received sticker, waiting for aliases", cx.chat_id())
-            );
+        Answer::Media(target) => {
+            tracing::info!("Received media, waiting for aliases");
+            // Existing aliases of the media get its current file id.
+            refresh(
+                &cx.requester,
+                &mut *args.db.lock().await,
+                cx.chat_id(),
+                &target,
+            )
+            .await;
+            cx.answer(format!(
+                "Great! Now specify aliases for the {} \
+                separated by spaces (without colons!).",
+                target.describe()
+            ))
+            .await?;
+            next(AddNamesState::new(target))
+        }
+        Answer::String(text) => {
+            tracing::info!("Received text snippet, waiting for aliases");
             cx.answer(
-                "Great! Now specify aliases for the sticker \
+                "Great! Now specify aliases for the text \
                 separated by spaces (without colons!).",
             )
             .await?;
-            next(AddNamesState::up(state, sticker))
-        }
-        Answer::String(_) => {
-            log::info!(
-                "{}",
-                format_log_chat("Ignoring text in recieve sticker stage", cx.chat_id())
-            );
-            cx.answer("Send sticker to assign aliases to or use /cancel.")
-                .await?;
-            next(state)
+            next(AddNamesState::new(Target::Text(text)))
         }
         Answer::Command(cmd) => {
             respond_command(&cx, &cmd, args.db).await?;
             match cmd {
-                Command::Cancel => exit(),
+                Command::Cancel | Command::ForgetMe => exit(),
                 _ => next(state),
             }
         }
+        Answer::Edited(_) | Answer::Callback { .. } => next(state),
     }
 }
 
 async fn respond_command(
     cx: &TransitionIn<AutoSend<Bot>>,
     cmd: &Command,
-    db: Arc<Mutex<RedisConnection>>,
+    db: Arc<Mutex<dyn Storage>>,
 ) -> Result<(), teloxide::RequestError> {
     match cmd {
         Command::Add => {
-            log::info!("{}", format_log_chat("Waiting for a sticker", cx.chat_id()));
+            tracing::info!("Waiting for a sticker or text");
             cx.answer("Already adding new aliases.").await?;
         }
         Command::Remove => {
-            log::info!(
-                "{}",
-                format_log_chat("Ignoring /remove at adding stage", cx.chat_id())
-            );
+            tracing::info!("Ignoring /remove at adding stage");
             cx.answer("To remove aliases /cancel addition first.")
                 .await?;
         }
-        Command::Start => {
-            log::info!("{}", format_log_chat("Printed start message", cx.chat_id()));
-            handle_start(cx).await?;
-        }
-        Command::Help => {
-            log::info!("{}", format_log_chat("Printed help message", cx.chat_id()));
-            handle_help(cx).await?;
-        }
-        Command::List => {
-            log::info!("{}", format_log_chat("Listing aliases", cx.chat_id()));
-
-            let mut db = db.lock().await;
-            if let Some(aliases) = db.get_aliases(cx.chat_id()).await {
-                handle_list(cx, aliases).await?;
-            }
-
-            log::info!("{}", format_log_chat("Finished listing", cx.chat_id()));
-        }
         Command::Cancel => {
-            log::info!(
-                "{}",
-                format_log_chat(
-                    "Cancelling alias addition in recieve sticker stage.",
-                    cx.chat_id()
-                )
-            );
+            tracing::info!("Cancelling alias addition in recieve sticker stage.");
             cx.answer("Cancelled alias addition.").await?;
         }
+        _ => handle_command(cx, cmd, &db).await?,
     }
     Ok(())
 }
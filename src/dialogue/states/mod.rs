@@ -6,4 +6,4 @@ mod replacing;
 pub use add_names::AddNamesState;
 pub use add_sticker::AddStickerState;
 pub use remove_names::RemoveNamesState;
-pub use replacing::ReplacingState;
+pub use replacing::{broken_notice, extract_targets, post_targets, ReplacingState};
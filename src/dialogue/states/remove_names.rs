@@ -1,29 +1,131 @@
 use crate::{
-    commands::{handle_help, handle_list, handle_start, Command},
-    db::RedisConnection,
-    dialogue::{Answer, Args, Dialogue},
-    utils::format_log_chat,
+    commands::{get_matching, handle_command, stored_alias, Command},
+    db::Storage,
+    dialogue::{callback::button, Answer, Args, CallbackData, Dialogue},
 };
 use frunk::Generic;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::sync::Arc;
 use teloxide::prelude::*;
+use teloxide::types::{InlineKeyboardButton, InlineKeyboardMarkup};
 // TODO: get rid of using tokio's Mutex https://tokio.rs/tokio/tutorial/channels
 use tokio::sync::Mutex;
 
-#[derive(Clone, Generic, Serialize, Deserialize)]
-pub struct RemoveNamesState;
+/// Maximum number of aliases shown as buttons.
+///
+/// Telegram limits the number of buttons in a keyboard, the rest of
+/// aliases can still be typed.
+const MAX_BUTTONS: usize = 90;
+
+#[derive(Clone, Generic, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct RemoveNamesState {
+    /// Aliases shown on the keyboard.
+    pub options: Vec<String>,
+    /// Whether each of `options` is selected for removal.
+    pub selected: Vec<bool>,
+    /// Message with the keyboard (if it was sent).
+    pub keyboard_message_id: Option<i32>,
+}
+
+impl RemoveNamesState {
+    /// Start alias removal.
+    ///
+    /// Sends a prompt with the chat aliases as tappable buttons.
+    pub async fn start(
+        cx: &TransitionIn<AutoSend<Bot>>,
+        db: Arc<Mutex<dyn Storage>>,
+    ) -> Result<RemoveNamesState, teloxide::RequestError> {
+        let mut options: Vec<String> = match db.lock().await.scan_aliases(cx.chat_id()).await {
+            Some(pairs) => pairs.into_iter().map(|(alias, _)| alias).collect(),
+            None => vec![],
+        };
+        options.sort();
+        options.truncate(MAX_BUTTONS);
+
+        let mut state = RemoveNamesState {
+            selected: vec![false; options.len()],
+            options,
+            keyboard_message_id: None,
+        };
+        if state.options.is_empty() {
+            cx.answer("Send aliases you want to remove separated by spaces.")
+                .await?;
+        } else {
+            let message = cx
+                .answer(
+                    "Select aliases you want to remove and press \"Remove\" \
+                    or send them separated by spaces.",
+                )
+                .reply_markup(state.keyboard())
+                .await?;
+            state.keyboard_message_id = Some(message.id);
+        }
+        Ok(state)
+    }
+
+    /// Keyboard with aliases, selected ones are marked.
+    fn keyboard(&self) -> InlineKeyboardMarkup {
+        let mut keyboard = InlineKeyboardMarkup::default();
+        let buttons: Vec<InlineKeyboardButton> = self
+            .options
+            .iter()
+            .zip(&self.selected)
+            .enumerate()
+            .map(|(i, (alias, &selected))| {
+                let text = if selected {
+                    format!("✅ {}", alias)
+                } else {
+                    alias.clone()
+                };
+                button(&text, CallbackData::Toggle(i))
+            })
+            .collect();
+        for row in buttons.chunks(3) {
+            keyboard = keyboard.append_row(row.to_vec());
+        }
+        keyboard.append_row(vec![
+            button("Remove", CallbackData::Confirm),
+            button("Cancel", CallbackData::Cancel),
+        ])
+    }
+
+    /// Aliases selected on the keyboard.
+    fn selected_options(&self) -> Vec<&str> {
+        self.options
+            .iter()
+            .zip(&self.selected)
+            .filter(|(_, &selected)| selected)
+            .map(|(alias, _)| alias.as_str())
+            .collect()
+    }
+
+    /// Remove the keyboard from its message (if any).
+    async fn close_keyboard(&self, cx: &TransitionIn<AutoSend<Bot>>) {
+        if let Some(message_id) = self.keyboard_message_id {
+            if let Err(e) = cx
+                .requester
+                .edit_message_reply_markup(cx.chat_id(), message_id)
+                .await
+            {
+                tracing::warn!("Could not remove keyboard: {}", e);
+                crate::metrics::telegram_error(&e);
+            }
+        }
+    }
+}
 
 #[teloxide(subtransition)]
 async fn remove_names(
-    state: RemoveNamesState,
+    mut state: RemoveNamesState,
     cx: TransitionIn<AutoSend<Bot>>,
     args: Args,
 ) -> TransitionOut<Dialogue> {
     let ans: Answer = args.ans;
     match ans {
-        Answer::Sticker(_) => {
-            log::info!("{}", format_log_chat("Waiting for names", cx.chat_id()));
+        Answer::Media(_) => {
+            tracing::info!("Waiting for names");
             cx.answer(
                 "Write aliases you want to remove separated by space or use /cancel to stop.",
             )
@@ -31,97 +133,103 @@ async fn remove_names(
             next(state)
         }
         Answer::String(ans_str) => {
-            log::info!(
-                "{}",
-                format_log_chat("Received aliases, removing them...", cx.chat_id())
-            );
-            remove_aliases(&cx, &ans_str, args.db).await?;
-            log::info!(
-                "{}",
-                format_log_chat("Finished removing aliases", cx.chat_id())
-            );
+            tracing::info!("Received aliases, removing them...");
+            state.close_keyboard(&cx).await;
+            remove_aliases(&cx, ans_str.split_whitespace().collect(), args.db).await?;
+            tracing::info!("Finished removing aliases");
             exit()
         }
         Answer::Command(cmd) => {
             respond_command(&cx, &cmd, args.db).await?;
             match cmd {
-                Command::Cancel => exit(),
+                Command::Cancel | Command::ForgetMe => {
+                    state.close_keyboard(&cx).await;
+                    exit()
+                }
                 _ => next(state),
             }
         }
+        Answer::Edited(_) => next(state),
+        Answer::Callback { message_id, .. } if state.keyboard_message_id != Some(message_id) => {
+            tracing::info!("Ignoring press on outdated keyboard");
+            next(state)
+        }
+        Answer::Callback { data, message_id } => match data {
+            CallbackData::Toggle(i) if i < state.selected.len() => {
+                state.selected[i] = !state.selected[i];
+                cx.requester
+                    .edit_message_reply_markup(cx.chat_id(), message_id)
+                    .reply_markup(state.keyboard())
+                    .await?;
+                next(state)
+            }
+            CallbackData::Confirm => {
+                let selected = state.selected_options();
+                if selected.is_empty() {
+                    cx.answer("Select aliases to remove first.").await?;
+                    return next(state);
+                }
+                tracing::info!("Removing selected aliases...");
+                state.close_keyboard(&cx).await;
+                remove_aliases(&cx, selected, args.db).await?;
+                exit()
+            }
+            CallbackData::Cancel => {
+                tracing::info!("Cancelling alias removal");
+                state.close_keyboard(&cx).await;
+                cx.answer("Cancelled alias removal.").await?;
+                exit()
+            }
+            _ => next(state),
+        },
     }
 }
 
 async fn respond_command(
     cx: &TransitionIn<AutoSend<Bot>>,
     cmd: &Command,
-    db: Arc<Mutex<RedisConnection>>,
+    db: Arc<Mutex<dyn Storage>>,
 ) -> Result<(), teloxide::RequestError> {
     match cmd {
         Command::Add => {
-            log::info!(
-                "{}",
-                format_log_chat("Ignoring /add at removal stage", cx.chat_id())
-            );
+            tracing::info!("Ignoring /add at removal stage");
             cx.answer("To add new aliases /cancel removal first.")
                 .await?;
         }
         Command::Remove => {
-            log::info!(
-                "{}",
-                format_log_chat("Ignoring /remove at removal stage", cx.chat_id())
-            );
+            tracing::info!("Ignoring /remove at removal stage");
             cx.answer("Already removing aliases. Type them separated by spaces.")
                 .await?;
         }
-        Command::Start => {
-            log::info!(
-                "{}",
-                format_log_chat("Printing start message", cx.chat_id())
-            );
-            handle_start(cx).await?;
-        }
-        Command::Help => {
-            log::info!("{}", format_log_chat("Printing help message", cx.chat_id()));
-            handle_help(cx).await?;
-        }
-        Command::List => {
-            log::info!("{}", format_log_chat("Listing aliases", cx.chat_id()));
-
-            let mut db = db.lock().await;
-            if let Some(aliases) = db.get_aliases(cx.chat_id()).await {
-                handle_list(cx, aliases).await?;
-            }
-
-            log::info!("{}", format_log_chat("Finished listing", cx.chat_id()));
-        }
         Command::Cancel => {
-            log::info!(
-                "{}",
-                format_log_chat("Cancelling alias removal", cx.chat_id())
-            );
+            tracing::info!("Cancelling alias removal");
         }
+        _ => handle_command(cx, cmd, &db).await?,
     }
     Ok(())
 }
 
-/// Remove aliases received in `text` from database.
+/// Remove `aliases` from database.
 ///
-/// Handle the removal, report the result to `cx`.
+/// Handle the removal, report the result to `cx`. Typed aliases are brought
+/// to their stored names first, `HashSet` lets us omit repeating removals.
 async fn remove_aliases(
     cx: &TransitionIn<AutoSend<Bot>>,
-    text: &str,
-    db: Arc<Mutex<RedisConnection>>,
+    typed: Vec<&str>,
+    db: Arc<Mutex<dyn Storage>>,
 ) -> Result<(), teloxide::RequestError> {
-    use std::collections::HashSet;
-    // HashSet lets us omit repeating removals
-    let aliases: HashSet<&str> = text.split_whitespace().collect();
     let mut db = db.lock().await;
 
+    let matching = get_matching(&mut *db, cx.chat_id()).await;
+    let mut aliases = HashSet::new();
+    for alias in typed {
+        aliases.insert(stored_alias(&mut *db, cx.chat_id(), alias, matching).await);
+    }
+
     let mut n_removed: i64 = 0;
     let mut fails: Vec<&str> = vec![];
 
-    for &alias in &aliases {
+    for alias in &aliases {
         let res = db.remove_alias(cx.chat_id(), alias).await;
         match res {
             Ok(()) => {
@@ -153,13 +261,7 @@ async fn remove_aliases(
             }
         }
         Err(e) => {
-            log::error!(
-                "{}",
-                format_log_chat(
-                    &format!("Failed converting usize to i64: {}", e),
-                    cx.chat_id()
-                )
-            );
+            tracing::error!("Failed converting usize to i64: {}", e);
         }
     }
     Ok(())
@@ -1,18 +1,26 @@
 use crate::{
-    commands::{handle_help, handle_list, handle_start, Command},
-    db::RedisConnection,
+    alias::{Delimiter, Target},
+    cache,
+    commands::{handle_command, Command},
+    db::Storage,
     dialogue::{
         states::{AddStickerState, RemoveNamesState},
         Answer, Args, Dialogue,
     },
-    utils::{format_log_chat, format_log_time},
+    media::{is_unavailable, plan_messages, refresh, send_album, send_media, Outgoing},
+    metrics,
+    search::{suggest, SuggestionMode},
+    settings::ChatSettings,
+    template::{render, TemplateContext},
+    trigger,
 };
 use frunk::Generic;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use teloxide::prelude::*;
-use teloxide::types::InputFile;
+use teloxide::types::MessageEntityKind;
 // TODO: get rid of using tokio's Mutex https://tokio.rs/tokio/tutorial/channels
 use tokio::sync::Mutex;
 
@@ -29,117 +37,559 @@ async fn replacing_state(
     let ans: Answer = args.ans;
     match ans {
         Answer::String(ans_str) => {
-            handle_replace(&cx, &ans_str, args.db).await?;
+            let settings = ChatSettings::load(&mut *args.db.lock().await, cx.chat_id()).await;
+            handle_replace(&cx, &ans_str, false, &settings, args.db).await?;
             next(state)
         }
         Answer::Command(cmd) => {
-            respond_command(&cx, &cmd, args.db).await?;
+            respond_command(&cx, &cmd, args.db.clone()).await?;
             match cmd {
                 Command::Add => next(AddStickerState),
-                Command::Remove => next(RemoveNamesState),
+                Command::Remove => next(RemoveNamesState::start(&cx, args.db).await?),
+                // Don't store the dialogue of the forgotten user again.
+                Command::ForgetMe => exit(),
                 _ => next(state),
             }
         }
-        Answer::Sticker(_) => next(state),
+        Answer::Media(target) => {
+            let settings = ChatSettings::load(&mut *args.db.lock().await, cx.chat_id()).await;
+            let restored = refresh(
+                &cx.requester,
+                &mut *args.db.lock().await,
+                cx.chat_id(),
+                &target,
+            )
+            .await;
+            if !restored.is_empty() {
+                tracing::info!("Restored broken media");
+                let aliases: Vec<String> = restored
+                    .iter()
+                    .map(|alias| settings.delimiter.wrap(alias))
+                    .collect();
+                cx.reply_to(format!("Restored {}.", aliases.join(", ")))
+                    .await?;
+            }
+            if let Some(caption) = cx.update.caption() {
+                if settings.captions {
+                    handle_replace(&cx, caption, false, &settings, args.db).await?;
+                }
+            }
+            next(state)
+        }
+        Answer::Edited(text) => {
+            let settings = ChatSettings::load(&mut *args.db.lock().await, cx.chat_id()).await;
+            if settings.edits {
+                handle_replace(&cx, &text, true, &settings, args.db).await?;
+            }
+            next(state)
+        }
+        Answer::Callback { .. } => next(state),
     }
 }
 
 async fn respond_command(
     cx: &TransitionIn<AutoSend<Bot>>,
     cmd: &Command,
-    db: Arc<Mutex<RedisConnection>>,
+    db: Arc<Mutex<dyn Storage>>,
 ) -> Result<(), teloxide::RequestError> {
     match cmd {
         Command::Add => {
-            log::info!("{}", format_log_chat("Waiting for a sticker", cx.chat_id()));
-            cx.answer("Send a sticker you want to assign alias to.")
+            tracing::info!("Waiting for a sticker");
+            cx.answer("Send a sticker or text you want to assign alias to.")
                 .await?;
         }
         Command::Remove => {
-            log::info!(
-                "{}",
-                format_log_chat("Waiting for names to remove", cx.chat_id())
-            );
-            cx.answer("Send aliases you want to remove separated by spaces.")
-                .await?;
-        }
-        Command::Start => {
-            log::info!("{}", format_log_chat("Printed start message", cx.chat_id()));
-            handle_start(cx).await?;
-        }
-        Command::Help => {
-            log::info!("{}", format_log_chat("Printed help message", cx.chat_id()));
-            handle_help(cx).await?;
-        }
-        Command::List => {
-            log::info!("{}", format_log_chat("Listing aliases", cx.chat_id()));
-
-            let mut db = db.lock().await;
-            if let Some(aliases) = db.get_aliases(cx.chat_id()).await {
-                handle_list(cx, aliases).await?;
-            }
-
-            log::info!("{}", format_log_chat("Finished listing", cx.chat_id()));
+            tracing::info!("Waiting for names to remove");
         }
         Command::Cancel => {
-            log::info!(
-                "{}",
-                format_log_chat("Ignoring cancel in replacing mode", cx.chat_id())
-            );
+            tracing::info!("Ignoring cancel in replacing mode");
         }
+        _ => handle_command(cx, cmd, &db).await?,
     }
     Ok(())
 }
 
+/// Send targets of aliases found in `text` of the message and of
+/// keyword triggers it fires.
+///
+/// `text` is the message text or caption. Aliases already resolved in
+/// the message are skipped, so editing it sends only the new ones, and
+/// `edited` messages don't fire triggers again.
 async fn handle_replace(
     cx: &TransitionIn<AutoSend<Bot>>,
     text: &str,
-    db: Arc<Mutex<RedisConnection>>,
+    edited: bool,
+    settings: &ChatSettings,
+    db: Arc<Mutex<dyn Storage>>,
 ) -> Result<(), teloxide::RequestError> {
-    let stickers = extract_stickers(text, cx.chat_id(), db).await;
-    for sticker in stickers {
-        cx.answer_sticker(sticker).await?;
+    let (mut targets, mut unknown) =
+        extract_targets(text, cx.chat_id(), settings, db.clone()).await;
+    if let Some(new) = mark_resolved(cx, &targets, &unknown, db.clone()).await {
+        targets.retain(|(alias, _)| new.contains(*alias));
+        unknown.retain(|alias| new.contains(&format!(":{}", alias)));
+    }
+    metrics::ALIASES
+        .with_label_values(&["resolved"])
+        .inc_by(targets.len() as u64);
+    metrics::ALIASES
+        .with_label_values(&["missed"])
+        .inc_by(unknown.len() as u64);
+    let triggered = if edited {
+        vec![]
+    } else {
+        let now = chrono::Utc::now().timestamp();
+        trigger::fire(text, cx.chat_id(), now, settings.matching, &db).await
+    };
+    let triggered_text: Vec<String> = triggered
+        .iter()
+        .map(|alias| settings.delimiter.wrap(alias))
+        .collect();
+    let triggered_text = triggered_text.join(" ");
+    // Triggers of removed aliases are skipped.
+    let (triggered, _) = extract_targets(&triggered_text, cx.chat_id(), settings, db.clone()).await;
+    metrics::ALIASES
+        .with_label_values(&["triggered"])
+        .inc_by(triggered.len() as u64);
+    let template_cx = TemplateContext::from_message(&cx.update);
+
+    let expansions: HashMap<&str, String> = targets
+        .iter()
+        .filter_map(|(alias, target)| match target {
+            Target::Text(template) => Some((*alias, render(template, &template_cx))),
+            Target::Media { .. } => None,
+        })
+        .collect();
+    // Captions can't be reposted without their media, formatting would
+    // be lost in the plain text copy.
+    let rewritable = cx.update.text().is_some() && !has_formatting(&cx.update);
+    if settings.rewrite && !expansions.is_empty() && !rewritable {
+        tracing::debug!("Not rewriting a caption or formatted message");
+    }
+    let rewritten = !expansions.is_empty()
+        && rewritable
+        && settings.rewrite
+        && repost(cx, text, settings.delimiter, &expansions).await?;
+
+    let mut targets: Vec<(&str, Target)> = targets
+        .into_iter()
+        // Text is already substituted into the reposted message
+        .filter(|(_, target)| !(rewritten && matches!(target, Target::Text(_))))
+        .collect();
+    for (alias, target) in triggered {
+        if !targets.iter().any(|(used, _)| *used == alias) {
+            targets.push((alias, target));
+        }
+    }
+    if settings.max_media > 0 && targets.len() > settings.max_media {
+        tracing::info!(
+            "Sending only {} of {} targets",
+            settings.max_media,
+            targets.len()
+        );
+        targets.truncate(settings.max_media);
+    }
+    // Reposted message is deleted, so there is nothing to reply to.
+    let reply_to = (settings.reply && !rewritten).then_some(cx.update.id);
+    let broken = post_targets(
+        &cx.requester,
+        cx.chat_id(),
+        targets,
+        reply_to,
+        &template_cx,
+        &db,
+    )
+    .await;
+    if !broken.is_empty() {
+        cx.reply_to(broken_notice(&broken, settings.delimiter))
+            .disable_notification(true)
+            .await?;
+    }
+    if !unknown.is_empty() {
+        suggest_aliases(cx, &unknown, settings, db).await?;
     }
     Ok(())
 }
 
-async fn extract_stickers(
+/// Send targets to the chat skipping media known to be broken.
+///
+/// Returns targets whose media can't be sent, newly found ones are
+/// remembered as broken.
+pub async fn post_targets<'a>(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    targets: Vec<(&'a str, Target)>,
+    reply_to: Option<i32>,
+    template_cx: &TemplateContext,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Vec<(&'a str, Target)> {
+    // Broken media is not sent until it's restored.
+    let known_broken = db
+        .lock()
+        .await
+        .get_broken(chat_id)
+        .await
+        .unwrap_or_default();
+    let (mut broken, targets): (Vec<_>, Vec<_>) = targets.into_iter().partition(|(_, target)| {
+        target
+            .media_key()
+            .is_some_and(|key| known_broken.iter().any(|broken| broken == key))
+    });
+    let failed = send_targets(bot, chat_id, targets, reply_to, template_cx, db).await;
+    if !failed.is_empty() {
+        mark_broken(chat_id, &failed, db.clone()).await;
+    }
+    broken.extend(failed);
+    if !broken.is_empty() {
+        metrics::ALIASES
+            .with_label_values(&["broken"])
+            .inc_by(broken.len() as u64);
+    }
+    broken
+}
+
+/// Message asking to restore media of `broken` targets.
+pub fn broken_notice(broken: &[(&str, Target)], delimiter: Delimiter) -> String {
+    let aliases: Vec<String> = broken
+        .iter()
+        .map(|(alias, _)| delimiter.wrap(alias))
+        .collect();
+    format!(
+        "Media of {} can't be sent anymore. Send it to this chat again to \
+        restore it or assign other media with /add.",
+        aliases.join(", ")
+    )
+}
+
+/// Send targets to the chat, each message regardless of others.
+///
+/// Returns targets whose media can't be sent anymore (and is not cached),
+/// other failures are only logged.
+async fn send_targets<'a>(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    targets: Vec<(&'a str, Target)>,
+    reply_to: Option<i32>,
+    template_cx: &TemplateContext,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Vec<(&'a str, Target)> {
+    let mut unavailable = vec![];
+    let mut queue: VecDeque<Outgoing<(&str, Target)>> =
+        plan_messages(targets, |(_, target)| target.media_type()).into();
+    while let Some(message) = queue.pop_front() {
+        let result = match &message {
+            Outgoing::Single((
+                _,
+                Target::Media {
+                    media_type,
+                    file_id,
+                    ..
+                },
+            )) => send_media(bot, chat_id, *media_type, file_id, reply_to).await,
+            Outgoing::Single((_, Target::Text(template))) => {
+                let mut request = bot.send_message(chat_id, render(template, template_cx));
+                if let Some(id) = reply_to {
+                    request = request.reply_to_message_id(id);
+                }
+                request.await.map(drop)
+            }
+            Outgoing::Album(media) => {
+                let media = media.iter().map(|(_, target)| target.clone()).collect();
+                send_album(bot, chat_id, media, reply_to).await
+            }
+        };
+        let e = match result {
+            Ok(()) => continue,
+            Err(e) => e,
+        };
+        metrics::telegram_error(&e);
+        match message {
+            // Telegram doesn't tell which media of the album is broken.
+            Outgoing::Album(media) if is_unavailable(&e) => {
+                tracing::warn!("Could not send album, sending media separately: {}", e);
+                for (i, target) in media.into_iter().enumerate() {
+                    queue.insert(i, Outgoing::Single(target));
+                }
+            }
+            Outgoing::Single((alias, target)) if is_unavailable(&e) => {
+                tracing::warn!("Media of alias '{}' is unavailable: {}", alias, e);
+                if !reupload(bot, chat_id, &target, reply_to, db).await {
+                    unavailable.push((alias, target));
+                }
+            }
+            _ => tracing::warn!("Could not send target: {}", e),
+        }
+    }
+    unavailable
+}
+
+/// Upload cached media of the target and save its new file id.
+///
+/// Returns whether the media was sent.
+async fn reupload(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    target: &Target,
+    reply_to: Option<i32>,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> bool {
+    let cache = match cache::get() {
+        Some(cache) => cache,
+        None => return false,
+    };
+    match cache.upload(bot, chat_id, target, reply_to).await {
+        Ok(Some(uploaded)) => {
+            tracing::info!("Uploaded cached media");
+            metrics::ALIASES.with_label_values(&["reuploaded"]).inc();
+            refresh(bot, &mut *db.lock().await, chat_id, &uploaded).await;
+            true
+        }
+        Ok(None) => false,
+        Err(e) => {
+            tracing::warn!("Failed to upload cached media: {}", e);
+            false
+        }
+    }
+}
+
+/// Remember that media of `targets` can't be sent anymore.
+async fn mark_broken(chat_id: i64, targets: &[(&str, Target)], db: Arc<Mutex<dyn Storage>>) {
+    let now = chrono::Utc::now().timestamp();
+    let mut db = db.lock().await;
+    for key in targets.iter().filter_map(|(_, target)| target.media_key()) {
+        if let Err(e) = db.set_broken(chat_id, key, Some(now)).await {
+            tracing::error!("Failed to mark broken media: {}", e);
+        }
+    }
+}
+
+/// Remember aliases found in the message.
+///
+/// Unknown aliases are stored with a colon prefix (which no alias can
+/// have), so they are suggested once too. Returns entries that were not
+/// remembered before or `None` if the database failed.
+async fn mark_resolved(
+    cx: &TransitionIn<AutoSend<Bot>>,
+    targets: &[(&str, Target)],
+    unknown: &[&str],
+    db: Arc<Mutex<dyn Storage>>,
+) -> Option<HashSet<String>> {
+    let unknown: Vec<String> = unknown.iter().map(|alias| format!(":{}", alias)).collect();
+    let entries: Vec<&str> = targets
+        .iter()
+        .map(|(alias, _)| *alias)
+        .chain(unknown.iter().map(String::as_str))
+        .collect();
+    db.lock()
+        .await
+        .mark_resolved(cx.chat_id(), cx.update.id, &entries)
+        .await
+        .ok()
+        .map(|new| new.into_iter().collect())
+}
+
+/// Whether the message text has formatting entities (links and
+/// mentions are detected again in a plain text copy).
+fn has_formatting(message: &Message) -> bool {
+    message.entities().is_some_and(|entities| {
+        entities.iter().any(|entity| {
+            !matches!(
+                entity.kind,
+                MessageEntityKind::Mention
+                    | MessageEntityKind::Hashtag
+                    | MessageEntityKind::Cashtag
+                    | MessageEntityKind::BotCommand
+                    | MessageEntityKind::Url
+                    | MessageEntityKind::Email
+                    | MessageEntityKind::PhoneNumber
+            )
+        })
+    })
+}
+
+/// Replace the message with its copy where aliases are substituted
+/// with `expansions`.
+///
+/// The copy is credited to the author of the original message. The
+/// original is deleted only after the copy is sent. Returns `false` if
+/// the message was not replaced: the copy could not be sent or the
+/// original could not be deleted (for example, if the bot has no rights
+/// for it), then the copy is deleted too.
+async fn repost(
+    cx: &TransitionIn<AutoSend<Bot>>,
     text: &str,
+    delimiter: Delimiter,
+    expansions: &HashMap<&str, String>,
+) -> Result<bool, teloxide::RequestError> {
+    let author = cx
+        .update
+        .from()
+        .map(|u| u.full_name())
+        .unwrap_or_else(|| String::from("Someone"));
+    let message = format!(
+        "{}: {}",
+        author,
+        substitute_aliases(text, delimiter, expansions)
+    );
+    let mut request = cx.answer(message);
+    if let Some(reply) = cx.update.reply_to_message() {
+        request = request.reply_to_message_id(reply.id);
+    }
+    let copy = match request.await {
+        Ok(copy) => copy,
+        Err(e) => {
+            tracing::warn!("Could not send rewritten message: {}", e);
+            metrics::telegram_error(&e);
+            return Ok(false);
+        }
+    };
+    if let Err(e) = cx
+        .requester
+        .delete_message(cx.chat_id(), cx.update.id)
+        .await
+    {
+        tracing::warn!("Could not delete message for rewriting: {}", e);
+        metrics::telegram_error(&e);
+        cx.requester.delete_message(cx.chat_id(), copy.id).await?;
+        return Ok(false);
+    }
+    tracing::info!("Reposted rewritten message");
+    Ok(true)
+}
+
+/// Find targets for aliases in the text.
+///
+/// Returns found targets with their aliases and aliases that are not
+/// assigned in the chat.
+pub async fn extract_targets<'a>(
+    text: &'a str,
     chat_id: i64,
-    db: Arc<Mutex<RedisConnection>>,
-) -> Vec<InputFile> {
-    let mut stickers: Vec<InputFile> = Vec::new();
-    for alias in extract_aliases(text) {
+    settings: &ChatSettings,
+    db: Arc<Mutex<dyn Storage>>,
+) -> (Vec<(&'a str, Target)>, Vec<&'a str>) {
+    let mut targets: Vec<(&str, Target)> = Vec::new();
+    let mut unknown: Vec<&str> = Vec::new();
+    let matching = settings.matching;
+    for alias in extract_aliases(text, settings.delimiter) {
         let mut db = db.lock().await;
-        if let Some(sticker_id) = db.get_sticker_id(chat_id, alias).await {
-            stickers.push(InputFile::FileId(sticker_id));
+        // Aliases added before matching was changed are stored as is.
+        let mut target = db.get_target(chat_id, alias).await;
+        let normalized = matching.normalize(alias);
+        if target.is_none() && normalized != alias {
+            target = db.get_target(chat_id, &normalized).await;
+        }
+        match target {
+            Some(target) => targets.push((alias, target)),
+            None => unknown.push(alias),
+        }
+    }
+    (targets, unknown)
+}
+
+/// Maximum number of suggestions for each unknown alias.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Suggest existing aliases similar to `unknown` ones.
+///
+/// Does nothing if suggestions are turned off in the chat or nothing
+/// similar was found.
+async fn suggest_aliases(
+    cx: &TransitionIn<AutoSend<Bot>>,
+    unknown: &[&str],
+    settings: &ChatSettings,
+    db: Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let mode = settings.suggestions;
+    if mode == SuggestionMode::Off {
+        return Ok(());
+    }
+    let pairs = db.lock().await.scan_aliases(cx.chat_id()).await;
+    let aliases: Vec<String> = match pairs {
+        Some(pairs) => pairs.into_iter().map(|(alias, _)| alias).collect(),
+        None => return Ok(()),
+    };
+
+    let mut message = String::new();
+    for alias in unknown {
+        let similar = suggest(alias, &aliases, MAX_SUGGESTIONS);
+        if !similar.is_empty() {
+            let similar: Vec<String> = similar
+                .iter()
+                .map(|alias| settings.delimiter.wrap(alias))
+                .collect();
+            message.push_str(&format!(
+                "{} - did you mean {}?\n",
+                settings.delimiter.wrap(alias),
+                similar.join(" or ")
+            ));
+        }
+    }
+    if message.is_empty() {
+        return Ok(());
+    }
+
+    tracing::info!("Suggesting aliases ({})", mode.as_str());
+    match (mode, cx.update.from()) {
+        (SuggestionMode::Private, Some(user)) if user.id != cx.chat_id() => {
+            // User may have never started the bot, so it's not critical.
+            if let Err(e) = cx.requester.send_message(user.id, message).await {
+                tracing::warn!("Could not send suggestions privately: {}", e);
+                metrics::telegram_error(&e);
+            }
+        }
+        _ => {
+            cx.reply_to(message).disable_notification(true).await?;
         }
     }
-    stickers
+    Ok(())
 }
 
 /// Extract aliases from given text.
 ///
-/// Matches the words with pattern ":<alias>:", returns vector of aliases as result.
+/// Matches the words marked with `delimiter` (e.g. ":<alias>:"),
+/// returns vector of aliases as result.
 ///
 /// Examples:
 /// ":cry:" -> vec!("cry")
 /// "sdfssadas  sad fd" -> vec!()
-fn extract_aliases(text: &str) -> Vec<&str> {
-    if let Ok(r) = Regex::new(":([^:\\s]+):") {
+fn extract_aliases(text: &str, delimiter: Delimiter) -> Vec<&str> {
+    if let Ok(r) = Regex::new(delimiter.pattern()) {
         r.captures_iter(text)
             .filter_map(|c| c.get(1))
             .map(|m| m.as_str())
             .collect()
     } else {
-        log::error!(
-            "{}",
-            format_log_time("Regex for extracting aliases does not compile!",)
-        );
+        tracing::error!("Regex for extracting aliases does not compile!");
         vec![]
     }
 }
 
+/// Substitute aliases in given text.
+///
+/// Replaces aliases marked with `delimiter` (e.g. ":<alias>:") with
+/// expansion of the alias, aliases without expansion are left as is.
+///
+/// Examples:
+/// "hi :name:" + {"name": "Bob"} -> "hi Bob"
+fn substitute_aliases(
+    text: &str,
+    delimiter: Delimiter,
+    expansions: &HashMap<&str, String>,
+) -> String {
+    if let Ok(r) = Regex::new(delimiter.pattern()) {
+        r.replace_all(text, |c: &regex::Captures| {
+            let alias = c.get(1).map(|m| m.as_str()).unwrap_or_default();
+            match expansions.get(alias) {
+                Some(expansion) => expansion.clone(),
+                None => c[0].to_owned(),
+            }
+        })
+        .into_owned()
+    } else {
+        tracing::error!("Regex for substituting aliases does not compile!");
+        text.to_owned()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -156,7 +606,35 @@ mod tests {
             (":𝓬𝓻𝔂:", vec!["𝓬𝓻𝔂"]),
         ];
         for (source, target) in cases {
-            assert_eq!(extract_aliases(source), target);
+            assert_eq!(extract_aliases(source, Delimiter::Colon), target);
         }
+        let text = "[a] ;b; :c: [d e] [f]";
+        assert_eq!(extract_aliases(text, Delimiter::Bracket), vec!["a", "f"]);
+        assert_eq!(extract_aliases(text, Delimiter::Semicolon), vec!["b"]);
+    }
+
+    #[test]
+    fn test_substitute_aliases() {
+        let expansions: HashMap<&str, String> =
+            vec![("name", "Bob".to_owned()), ("😭", ":cry:".to_owned())]
+                .into_iter()
+                .collect();
+        let cases = vec![
+            ("hi :name:", "hi Bob"),
+            (":name::name:", "BobBob"),
+            ("hi :unknown: :name:", "hi :unknown: Bob"),
+            (":😭:", ":cry:"),
+            ("no aliases", "no aliases"),
+        ];
+        for (source, target) in cases {
+            assert_eq!(
+                substitute_aliases(source, Delimiter::Colon, &expansions),
+                target
+            );
+        }
+        assert_eq!(
+            substitute_aliases("hi [name] :name:", Delimiter::Bracket, &expansions),
+            "hi Bob :name:"
+        );
     }
 }
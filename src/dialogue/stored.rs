@@ -0,0 +1,149 @@
+//! Stored dialogue format.
+//!
+//! Dialogues are saved in an envelope with the format version, so states
+//! saved by older versions of the bot can be migrated after their structs
+//! change. Dialogues saved before the envelope existed are version 0.
+
+use super::Dialogue;
+use serde::de::Error;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// Version of the current dialogue format.
+pub const VERSION: u64 = 1;
+
+/// Upgrades dialogue JSON by one version.
+type Migration = fn(Value) -> Result<Value, serde_json::Error>;
+
+/// `MIGRATIONS[i]` upgrades version `i` to `i + 1`.
+///
+/// When a state struct changes incompatibly, bump `VERSION` and append
+/// a migration from the previous format.
+const MIGRATIONS: [Migration; VERSION as usize] = [from_unversioned];
+
+/// Version 0 lacked the envelope and had states of the sticker-only bot.
+///
+/// `AddNames` kept the whole sticker instead of the target and
+/// `RemoveNames` had no fields.
+fn from_unversioned(mut dialogue: Value) -> Result<Value, serde_json::Error> {
+    if let Some(state) = dialogue.get_mut("AddNames") {
+        if let Some(sticker) = state.as_object_mut().and_then(|s| s.remove("sticker")) {
+            let file_id = sticker
+                .get("file_id")
+                .and_then(Value::as_str)
+                .ok_or_else(|| serde_json::Error::missing_field("file_id"))?;
+            state["target"] = json!({"Media": {
+                "media_type": "Sticker",
+                "file_id": file_id,
+                "unique_id": sticker.get("file_unique_id"),
+            }});
+        }
+    }
+    if let Some(state) = dialogue.get_mut("RemoveNames") {
+        if state.is_null() {
+            *state = json!({});
+        }
+    }
+    Ok(dialogue)
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u64,
+    dialogue: &'a Dialogue,
+}
+
+/// Serialize the dialogue with the current version.
+pub fn serialize(dialogue: &Dialogue) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&Envelope {
+        version: VERSION,
+        dialogue,
+    })
+}
+
+/// Deserialize the dialogue saved in any known version.
+pub fn deserialize(value: &str) -> Result<Dialogue, serde_json::Error> {
+    let value: Value = serde_json::from_str(value)?;
+    let (version, mut dialogue) = match value {
+        Value::Object(mut envelope) if envelope.contains_key("version") => {
+            let version = envelope
+                .get("version")
+                .and_then(Value::as_u64)
+                .ok_or_else(|| serde_json::Error::custom("invalid dialogue version"))?;
+            let dialogue = envelope
+                .remove("dialogue")
+                .ok_or_else(|| serde_json::Error::missing_field("dialogue"))?;
+            (version, dialogue)
+        }
+        // Unversioned dialogue is the bare enum which has no `version` variant.
+        value => (0, value),
+    };
+    if version > VERSION {
+        return Err(serde_json::Error::custom(format!(
+            "unknown dialogue version {}",
+            version
+        )));
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        dialogue = migration(dialogue)?;
+    }
+    serde_json::from_value(dialogue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alias::Target;
+    use crate::media::MediaType;
+
+    #[test]
+    fn test_roundtrip() {
+        let value = serialize(&Dialogue::default()).unwrap();
+        assert_eq!(value, r#"{"version":1,"dialogue":{"Replacing":null}}"#);
+        assert!(matches!(deserialize(&value), Ok(Dialogue::Replacing(_))));
+    }
+
+    #[test]
+    fn test_unversioned() {
+        assert!(matches!(
+            deserialize(r#"{"AddSticker":null}"#),
+            Ok(Dialogue::AddSticker(_))
+        ));
+        assert!(matches!(
+            deserialize(r#"{"Replacing":null}"#),
+            Ok(Dialogue::Replacing(_))
+        ));
+        let add_names = r#"{"AddNames":{"sticker":{"file_id":"CAACAgIAAxkBAAIBY2","file_unique_id":"AgADbQADwZxgDA","width":512,"height":512,"is_animated":false,"thumb":{"file_id":"AAMCAgADGQEAAgFj","file_unique_id":"AQADbQADwZxgDHI","width":128,"height":128,"file_size":5416},"emoji":"😭","set_name":"Sad","mask_position":null,"file_size":27008}}}"#;
+        match deserialize(add_names) {
+            Ok(Dialogue::AddNames(state)) => {
+                assert_eq!(
+                    state.target,
+                    Target::Media {
+                        media_type: MediaType::Sticker,
+                        file_id: "CAACAgIAAxkBAAIBY2".to_owned(),
+                        unique_id: Some("AgADbQADwZxgDA".to_owned()),
+                    }
+                );
+                assert!(state.saved.is_empty());
+            }
+            _ => panic!("AddNames is not migrated"),
+        }
+        match deserialize(r#"{"RemoveNames":null}"#) {
+            Ok(Dialogue::RemoveNames(state)) => {
+                assert!(state.options.is_empty());
+                assert_eq!(state.keyboard_message_id, None);
+            }
+            _ => panic!("RemoveNames is not migrated"),
+        }
+        assert!(deserialize(r#"{"AddNames":{"sticker":{}}}"#).is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(deserialize(r#"{"version":2,"dialogue":{"Replacing":null}}"#).is_err());
+        assert!(deserialize(r#"{"version":"1","dialogue":{"Replacing":null}}"#).is_err());
+        assert!(deserialize(r#"{"version":1}"#).is_err());
+        assert!(deserialize(r#"{"Unknown":null}"#).is_err());
+        assert!(deserialize("not json").is_err());
+    }
+}
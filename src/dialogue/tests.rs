@@ -0,0 +1,824 @@
+//! Dialogue tests against the fake Bot API and in-memory storage.
+
+use crate::alias::Target;
+use crate::dialogue::states::AddStickerState;
+use crate::dialogue::{CallbackData, Dialogue};
+use crate::media::MediaType;
+use crate::testing::*;
+use serde_json::json;
+
+fn sticker(file_id: &str) -> Target {
+    Target::Media {
+        media_type: MediaType::Sticker,
+        file_id: file_id.to_owned(),
+        unique_id: Some(format!("unique-{}", file_id)),
+    }
+}
+
+/// Harness with aliases of stickers assigned in `CHAT_ID`.
+async fn with_aliases(aliases: &[(&str, &str)]) -> Harness {
+    let h = Harness::new().await;
+    {
+        let mut db = h.db.lock().await;
+        for (alias, file_id) in aliases {
+            db.set_alias(CHAT_ID, alias, &sticker(file_id))
+                .await
+                .unwrap();
+        }
+    }
+    h
+}
+
+async fn has_alias(h: &Harness, alias: &str) -> bool {
+    h.db.lock().await.get_target(CHAT_ID, alias).await.is_some()
+}
+
+/// Start adding aliases to sticker `file_id` and return the keyboard id.
+async fn add_names(h: &Harness, file_id: &str, names: &str) -> i32 {
+    h.send_text("/add").await;
+    h.send(sticker_message(2, file_id)).await;
+    let calls = h.send_text(names).await;
+    keyboard_message_id(&calls).expect("summary has a keyboard")
+}
+
+#[tokio::test]
+async fn test_replacing_known_alias() {
+    let h = with_aliases(&[("cry", "sticker1")]).await;
+    let calls = h.send_text("so sad :cry:").await;
+    let sent: Vec<_> = calls
+        .iter()
+        .filter(|call| call.method == "sendSticker")
+        .collect();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].params["sticker"], json!("sticker1"));
+    assert_eq!(h.state().await, Some("Replacing"));
+}
+
+#[tokio::test]
+async fn test_replacing_unknown_alias() {
+    let h = Harness::new().await;
+    let calls = h.send_text(":nothing:").await;
+    assert!(!methods(&calls).contains(&"sendSticker"));
+    assert_eq!(h.state().await, Some("Replacing"));
+}
+
+#[tokio::test]
+async fn test_replacing_media() {
+    let h = Harness::new().await;
+    let calls = h.send(sticker_message(1, "sticker1")).await;
+    assert!(texts(&calls).is_empty());
+    assert_eq!(h.state().await, Some("Replacing"));
+}
+
+#[tokio::test]
+async fn test_replacing_commands() {
+    let h = Harness::new().await;
+    let calls = h.send_text("/help").await;
+    assert_eq!(texts(&calls).len(), 1);
+    assert_eq!(h.state().await, Some("Replacing"));
+
+    let calls = h.send_text("/cancel").await;
+    assert!(texts(&calls).is_empty());
+    assert_eq!(h.state().await, Some("Replacing"));
+
+    let calls = h.send_text("/add").await;
+    assert_eq!(
+        texts(&calls),
+        vec!["Send a sticker or text you want to assign alias to."]
+    );
+    assert_eq!(h.state().await, Some("AddSticker"));
+}
+
+#[tokio::test]
+async fn test_replacing_remove() {
+    let h = Harness::new().await;
+    let calls = h.send_text("/remove").await;
+    assert_eq!(
+        texts(&calls),
+        vec!["Send aliases you want to remove separated by spaces."]
+    );
+    assert_eq!(h.state().await, Some("RemoveNames"));
+
+    let h = with_aliases(&[("cry", "sticker1")]).await;
+    let calls = h.send_text("/remove").await;
+    assert!(keyboard_message_id(&calls).is_some());
+    assert_eq!(h.state().await, Some("RemoveNames"));
+}
+
+#[tokio::test]
+async fn test_replacing_callback() {
+    let h = Harness::new().await;
+    let calls = h.press(CallbackData::Done, 1).await;
+    assert!(texts(&calls).is_empty());
+    assert_eq!(h.state().await, Some("Replacing"));
+}
+
+#[tokio::test]
+async fn test_replacing_edited() {
+    let h = with_aliases(&[("cry", "sticker1"), ("smile", "sticker2")]).await;
+    h.send(text_message(5, ":cry:")).await;
+    let calls = h.edit(text_message(5, ":cry: :smile:")).await;
+    let sent: Vec<_> = calls
+        .iter()
+        .filter(|call| call.method == "sendSticker")
+        .map(|call| call.params["sticker"].clone())
+        .collect();
+    assert_eq!(sent, vec![json!("sticker2")]);
+
+    let calls = h.edit(text_message(5, ":cry: :smile:")).await;
+    assert!(!methods(&calls).contains(&"sendSticker"));
+}
+
+#[tokio::test]
+async fn test_add_sticker_media() {
+    let h = Harness::new().await;
+    h.set_state(Dialogue::AddSticker(AddStickerState)).await;
+    let calls = h.send(sticker_message(2, "sticker1")).await;
+    assert_eq!(texts(&calls).len(), 1);
+    assert_eq!(h.state().await, Some("AddNames"));
+}
+
+#[tokio::test]
+async fn test_add_sticker_text() {
+    let h = Harness::new().await;
+    h.send_text("/add").await;
+    h.send_text("some snippet").await;
+    assert_eq!(h.state().await, Some("AddNames"));
+    h.send_text("snippet").await;
+    let target = h.db.lock().await.get_target(CHAT_ID, "snippet").await;
+    assert!(matches!(target, Some(Target::Text(text)) if text == "some snippet"));
+}
+
+#[tokio::test]
+async fn test_add_sticker_commands() {
+    let h = Harness::new().await;
+    h.send_text("/add").await;
+    let calls = h.send_text("/add").await;
+    assert_eq!(texts(&calls), vec!["Already adding new aliases."]);
+    assert_eq!(h.state().await, Some("AddSticker"));
+
+    let calls = h.send_text("/cancel").await;
+    assert_eq!(texts(&calls), vec!["Cancelled alias addition."]);
+    assert_eq!(h.state().await, None);
+}
+
+#[tokio::test]
+async fn test_add_sticker_ignored() {
+    let h = Harness::new().await;
+    h.send_text("/add").await;
+    let calls = h.press(CallbackData::Done, 1).await;
+    assert!(texts(&calls).is_empty());
+    let calls = h.edit(text_message(1, "edited")).await;
+    assert!(texts(&calls).is_empty());
+    assert_eq!(h.state().await, Some("AddSticker"));
+}
+
+#[tokio::test]
+async fn test_add_names_done() {
+    let h = Harness::new().await;
+    let keyboard = add_names(&h, "sticker1", "cry sad").await;
+    assert_eq!(h.state().await, Some("AddNames"));
+    assert!(has_alias(&h, "cry").await && has_alias(&h, "sad").await);
+
+    let calls = h.press(CallbackData::Done, keyboard).await;
+    assert!(methods(&calls).contains(&"editMessageReplyMarkup"));
+    assert_eq!(texts(&calls), vec!["Aliases are set successfully!"]);
+    assert_eq!(h.state().await, None);
+    assert!(has_alias(&h, "cry").await);
+}
+
+#[tokio::test]
+async fn test_add_names_finished() {
+    let h = Harness::new().await;
+    add_names(&h, "sticker1", "cry").await;
+    // Later messages are not saved as aliases of the same target.
+    let calls = h.send_text("hello :cry:").await;
+    assert!(methods(&calls).contains(&"editMessageReplyMarkup"));
+    assert_eq!(methods(&calls).last(), Some(&"sendSticker"));
+    assert!(!has_alias(&h, "hello").await);
+    assert_eq!(h.state().await, None);
+
+    // Failed aliases can be sent again.
+    h.send_text("/add").await;
+    h.send(sticker_message(2, "sticker2")).await;
+    let calls = h.send_text("help").await;
+    assert_eq!(keyboard_message_id(&calls), None);
+    assert_eq!(h.state().await, Some("AddNames"));
+    h.send_text("sad").await;
+    assert!(has_alias(&h, "sad").await);
+}
+
+#[tokio::test]
+async fn test_add_names_more() {
+    let h = Harness::new().await;
+    let keyboard = add_names(&h, "sticker1", "cry").await;
+    let calls = h.press(CallbackData::More, keyboard).await;
+    assert_eq!(
+        texts(&calls),
+        vec!["Send more aliases separated by spaces."]
+    );
+    assert_eq!(h.state().await, Some("AddNames"));
+
+    h.send_text("sad").await;
+    assert!(has_alias(&h, "sad").await);
+    assert_eq!(h.state().await, Some("AddNames"));
+}
+
+#[tokio::test]
+async fn test_add_names_cancel_reverts() {
+    let h = Harness::new().await;
+    h.db.lock()
+        .await
+        .set_alias(CHAT_ID, "sad", &sticker("sticker0"))
+        .await
+        .unwrap();
+    let first = add_names(&h, "sticker1", "cry").await;
+    h.press(CallbackData::More, first).await;
+    let calls = h.send_text("sad").await;
+    let second = keyboard_message_id(&calls).unwrap();
+    assert_ne!(first, second);
+
+    // Outdated keyboard is ignored.
+    let calls = h.press(CallbackData::Cancel, first).await;
+    assert!(texts(&calls).is_empty());
+    assert_eq!(h.state().await, Some("AddNames"));
+
+    let calls = h.press(CallbackData::Cancel, second).await;
+    assert_eq!(
+        texts(&calls),
+        vec!["Cancelled, added aliases were removed and replaced ones restored."]
+    );
+    assert_eq!(h.state().await, None);
+    assert!(!has_alias(&h, "cry").await);
+    // The alias existed before the addition, so it points to its old target.
+    assert_eq!(
+        h.db.lock().await.get_target(CHAT_ID, "sad").await,
+        Some(sticker("sticker0"))
+    );
+}
+
+#[tokio::test]
+async fn test_add_names_media_and_edit() {
+    let h = Harness::new().await;
+    h.send_text("/add").await;
+    h.send(sticker_message(2, "sticker1")).await;
+    let calls = h.send(sticker_message(3, "sticker2")).await;
+    assert_eq!(texts(&calls).len(), 1);
+    let calls = h.edit(text_message(1, "edited")).await;
+    assert!(calls.is_empty());
+    assert_eq!(h.state().await, Some("AddNames"));
+}
+
+#[tokio::test]
+async fn test_add_names_command_cancel() {
+    let h = Harness::new().await;
+    add_names(&h, "sticker1", "cry").await;
+    let calls = h.send_text("/cancel").await;
+    assert!(methods(&calls).contains(&"editMessageReplyMarkup"));
+    assert_eq!(
+        texts(&calls),
+        vec!["Cancelled, added aliases were removed."]
+    );
+    assert_eq!(h.state().await, None);
+    // Reverted the same way as with the button.
+    assert!(!has_alias(&h, "cry").await);
+
+    h.send_text("/add").await;
+    h.send(sticker_message(2, "sticker1")).await;
+    let calls = h.send_text("/cancel").await;
+    assert_eq!(texts(&calls), vec!["Cancelled alias addition."]);
+    assert_eq!(h.state().await, None);
+}
+
+#[tokio::test]
+async fn test_remove_names_keyboard() {
+    let h = with_aliases(&[("cry", "sticker1"), ("sad", "sticker1")]).await;
+    let calls = h.send_text("/remove").await;
+    let keyboard = keyboard_message_id(&calls).unwrap();
+
+    let calls = h.press(CallbackData::Confirm, keyboard).await;
+    assert_eq!(texts(&calls), vec!["Select aliases to remove first."]);
+    assert_eq!(h.state().await, Some("RemoveNames"));
+
+    // Options are sorted: "cry", "sad".
+    let calls = h.press(CallbackData::Toggle(1), keyboard).await;
+    assert!(methods(&calls).contains(&"editMessageReplyMarkup"));
+    h.press(CallbackData::Confirm, keyboard).await;
+    assert_eq!(h.state().await, None);
+    assert!(has_alias(&h, "cry").await);
+    assert!(!has_alias(&h, "sad").await);
+}
+
+#[tokio::test]
+async fn test_remove_names_typed() {
+    let h = with_aliases(&[("cry", "sticker1"), ("sad", "sticker1")]).await;
+    h.send_text("/remove").await;
+    let calls = h.send_text("cry unknown").await;
+    assert!(texts(&calls).contains(&"Removed 1/2 (duplicates are omitted)"));
+    assert_eq!(h.state().await, None);
+    assert!(!has_alias(&h, "cry").await);
+    assert!(has_alias(&h, "sad").await);
+}
+
+#[tokio::test]
+async fn test_remove_names_cancel() {
+    let h = with_aliases(&[("cry", "sticker1")]).await;
+    let calls = h.send_text("/remove").await;
+    let keyboard = keyboard_message_id(&calls).unwrap();
+    let calls = h.press(CallbackData::Cancel, keyboard).await;
+    assert_eq!(texts(&calls), vec!["Cancelled alias removal."]);
+    assert_eq!(h.state().await, None);
+    assert!(has_alias(&h, "cry").await);
+
+    h.send_text("/remove").await;
+    h.send_text("/cancel").await;
+    assert_eq!(h.state().await, None);
+}
+
+#[tokio::test]
+async fn test_remove_names_ignored() {
+    let h = with_aliases(&[("cry", "sticker1")]).await;
+    h.send_text("/remove").await;
+    let calls = h.send(sticker_message(2, "sticker1")).await;
+    assert_eq!(texts(&calls).len(), 1);
+    let calls = h.edit(text_message(1, "cry")).await;
+    assert!(calls.is_empty());
+    let calls = h.send_text("/add").await;
+    assert_eq!(
+        texts(&calls),
+        vec!["To add new aliases /cancel removal first."]
+    );
+    assert_eq!(h.state().await, Some("RemoveNames"));
+    assert!(has_alias(&h, "cry").await);
+}
+
+#[tokio::test]
+async fn test_unreadable_dialogue_reset() {
+    let h = with_aliases(&[("cry", "sticker1")]).await;
+    h.db.lock()
+        .await
+        .set_dialogue_value(CHAT_ID, Some(USER_ID), r#"{"Removed":null}"#.to_owned())
+        .await
+        .unwrap();
+    let calls = h.send_text(":cry:").await;
+    assert_eq!(texts(&calls), vec![crate::DIALOGUE_RESET_NOTICE]);
+    // The message is handled in the default state.
+    assert!(methods(&calls).contains(&"sendSticker"));
+    assert_eq!(h.state().await, Some("Replacing"));
+}
+
+#[tokio::test]
+async fn test_unversioned_dialogue() {
+    let h = Harness::new().await;
+    h.db.lock()
+        .await
+        .set_dialogue_value(CHAT_ID, Some(USER_ID), r#"{"AddSticker":null}"#.to_owned())
+        .await
+        .unwrap();
+    h.send(sticker_message(2, "sticker1")).await;
+    assert_eq!(h.state().await, Some("AddNames"));
+}
+
+#[tokio::test]
+async fn test_settings_menu() {
+    let h = Harness::new().await;
+    let calls = h.send_text("/settings").await;
+    let keyboard = keyboard_message_id(&calls).expect("settings menu has a keyboard");
+    assert_eq!(h.state().await, Some("Replacing"));
+
+    // Second button is "Who can change aliases: everyone".
+    let calls = h.press(CallbackData::Setting(1), keyboard).await;
+    assert!(methods(&calls).contains(&"editMessageReplyMarkup"));
+    let settings = h.db.lock().await.get_settings(CHAT_ID).await.unwrap();
+    assert_eq!(
+        settings,
+        vec![("permissions".to_owned(), "admins".to_owned())]
+    );
+
+    let calls = h.press(CallbackData::Setting(100), keyboard).await;
+    assert_eq!(methods(&calls), vec!["answerCallbackQuery"]);
+}
+
+#[tokio::test]
+async fn test_settings_permissions() {
+    let h = Harness::new().await;
+    h.db.lock()
+        .await
+        .set_setting(GROUP_ID, "permissions", "admins")
+        .await
+        .unwrap();
+
+    let calls = h.send(group_message(1, USER_ID, "/add")).await;
+    assert_eq!(texts(&calls), vec![crate::settings::PERMISSION_NOTICE]);
+    let calls = h.send(group_message(2, ADMIN_ID, "/add")).await;
+    assert_ne!(texts(&calls), vec![crate::settings::PERMISSION_NOTICE]);
+
+    // Private chats are not restricted.
+    h.db.lock()
+        .await
+        .set_setting(CHAT_ID, "permissions", "admins")
+        .await
+        .unwrap();
+    let calls = h.send_text("/add").await;
+    assert_ne!(texts(&calls), vec![crate::settings::PERMISSION_NOTICE]);
+    assert_eq!(h.state().await, Some("AddSticker"));
+    // Settings commands only show values to everyone.
+    for command in ["/rewrite on", "/matching exact", "/suggestions off"] {
+        let calls = h.send(group_message(3, USER_ID, command)).await;
+        assert_eq!(texts(&calls), vec![crate::settings::PERMISSION_NOTICE]);
+    }
+    let calls = h.send(group_message(4, USER_ID, "/rewrite")).await;
+    assert_ne!(texts(&calls), vec![crate::settings::PERMISSION_NOTICE]);
+    let calls = h.send(group_message(5, ADMIN_ID, "/rewrite on")).await;
+    assert_ne!(texts(&calls), vec![crate::settings::PERMISSION_NOTICE]);
+    let settings = h.db.lock().await.get_settings(GROUP_ID).await.unwrap();
+    assert!(settings.contains(&("rewrite".to_owned(), "on".to_owned())));
+    assert_eq!(settings.len(), 2);
+}
+
+#[tokio::test]
+async fn test_settings_max_media_and_reply() {
+    let h = with_aliases(&[("a", "sticker1"), ("b", "sticker2")]).await;
+    {
+        let mut db = h.db.lock().await;
+        db.set_setting(CHAT_ID, "max_media", "1").await.unwrap();
+        db.set_setting(CHAT_ID, "reply", "on").await.unwrap();
+    }
+    let calls = h.send(text_message(7, ":a: :b:")).await;
+    let sent: Vec<_> = calls
+        .iter()
+        .filter(|call| call.method == "sendSticker")
+        .collect();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].params["sticker"], json!("sticker1"));
+    assert_eq!(sent[0].params["reply_to_message_id"], json!(7));
+}
+
+#[tokio::test]
+async fn test_forget_me() {
+    let h = Harness::new().await;
+    {
+        let mut db = h.db.lock().await;
+        // Id of the private chat with the user is the user id.
+        db.set_alias(USER_ID, "cry", &sticker("sticker1"))
+            .await
+            .unwrap();
+        db.set_alias(GROUP_ID, "group", &sticker("sticker2"))
+            .await
+            .unwrap();
+    }
+    h.send(group_message(1, USER_ID, "/add")).await;
+    h.send_text("/add").await;
+    assert_eq!(h.state().await, Some("AddSticker"));
+
+    let calls = h.send_text("/forgetme").await;
+    assert_eq!(texts(&calls).len(), 1);
+    assert_eq!(h.state().await, None);
+    let mut db = h.db.lock().await;
+    assert!(db
+        .get_dialogue_value(GROUP_ID, Some(USER_ID))
+        .await
+        .unwrap()
+        .is_none());
+    assert!(!has_alias_in(&mut *db, USER_ID, "cry").await);
+    assert!(has_alias_in(&mut *db, GROUP_ID, "group").await);
+}
+
+async fn has_alias_in(db: &mut dyn crate::db::Storage, chat_id: i64, alias: &str) -> bool {
+    db.get_target(chat_id, alias).await.is_some()
+}
+
+#[tokio::test]
+async fn test_purge() {
+    let h = Harness::new().await;
+    h.db.lock()
+        .await
+        .set_alias(GROUP_ID, "group", &sticker("sticker1"))
+        .await
+        .unwrap();
+
+    let calls = h.send(group_message(1, USER_ID, "/purge confirm")).await;
+    assert_eq!(
+        texts(&calls),
+        vec!["Only administrators can purge the chat."]
+    );
+    let calls = h.send(group_message(2, ADMIN_ID, "/purge")).await;
+    assert!(texts(&calls)[0].contains("/purge confirm"));
+    assert!(has_alias_in(&mut *h.db.lock().await, GROUP_ID, "group").await);
+
+    let calls = h.send(group_message(3, ADMIN_ID, "/purge confirm")).await;
+    assert_eq!(texts(&calls), vec!["All data of the chat is deleted."]);
+    assert!(!has_alias_in(&mut *h.db.lock().await, GROUP_ID, "group").await);
+}
+
+#[tokio::test]
+async fn test_broken_media() {
+    let dead = format!("{}sticker", DEAD_FILE_PREFIX);
+    let h = with_aliases(&[("a", "sticker1"), ("cry", &dead), ("sob", &dead)]).await;
+    let calls = h.send_text(":cry: :a:").await;
+    let sent: Vec<_> = calls
+        .iter()
+        .filter(|call| call.method == "sendSticker" && call.message_id.is_some())
+        .collect();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].params["sticker"], json!("sticker1"));
+    assert_eq!(
+        texts(&calls),
+        vec![
+            "Media of :cry: can't be sent anymore. Send it to this chat again to \
+            restore it or assign other media with /add."
+        ]
+    );
+
+    // Other aliases of the broken media are not sent either.
+    let calls = h.send(text_message(2, ":sob:")).await;
+    assert!(!methods(&calls).contains(&"sendSticker"));
+    assert_eq!(texts(&calls).len(), 1);
+
+    // The same file (by unique id) with a new file id restores aliases.
+    let mut message = message_json(3, json!({}));
+    message["sticker"] = json!({
+        "file_id": "fresh",
+        "file_unique_id": format!("unique-{}", dead),
+        "width": 512,
+        "height": 512,
+        "is_animated": false,
+    });
+    let calls = h.send(serde_json::from_value(message).unwrap()).await;
+    assert_eq!(texts(&calls), vec!["Restored :cry:, :sob:."]);
+    let calls = h.send(text_message(4, ":sob:")).await;
+    let sent: Vec<_> = calls
+        .iter()
+        .filter(|call| call.method == "sendSticker")
+        .collect();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].params["sticker"], json!("fresh"));
+}
+
+#[tokio::test]
+async fn test_broken_album_media() {
+    let photo = |file_id: &str| Target::Media {
+        media_type: MediaType::Photo,
+        file_id: file_id.to_owned(),
+        unique_id: Some(format!("unique-{}", file_id)),
+    };
+    let h = Harness::new().await;
+    {
+        let mut db = h.db.lock().await;
+        db.set_alias(CHAT_ID, "a", &photo("photo1")).await.unwrap();
+        db.set_alias(CHAT_ID, "b", &photo("dead-photo"))
+            .await
+            .unwrap();
+        db.set_alias(CHAT_ID, "c", &photo("photo3")).await.unwrap();
+    }
+    let calls = h.send_text(":a: :b: :c:").await;
+    let photos: Vec<_> = calls
+        .iter()
+        .filter(|call| call.method == "sendPhoto" && call.message_id.is_some())
+        .map(|call| call.params["photo"].clone())
+        .collect();
+    assert_eq!(photos, vec![json!("photo1"), json!("photo3")]);
+    assert_eq!(texts(&calls).len(), 1);
+    assert!(texts(&calls)[0].starts_with("Media of :b:"));
+}
+
+#[tokio::test]
+async fn test_schedule() {
+    use crate::schedule::run_due;
+    use chrono::{TimeZone, Utc};
+
+    let h = with_aliases(&[("meme", "sticker1")]).await;
+    let calls = h.send_text("/schedule 0 10 * * fri :meme: :nope:").await;
+    assert_eq!(texts(&calls), vec!["Unknown aliases: :nope:."]);
+    let calls = h.send_text("/schedule tomorrow :meme:").await;
+    assert!(texts(&calls)[0].starts_with("Unknown time format."));
+
+    let calls = h.send_text("/schedule 0 10 * * fri :meme:").await;
+    assert!(texts(&calls)[0]
+        .starts_with("Scheduled post #1 of :meme: (\"0 10 * * fri\"). Next post is at "));
+    let calls = h.send_text("/schedule 2099-01-01 00:00 :meme:").await;
+    assert_eq!(
+        texts(&calls),
+        vec![
+            "Scheduled post #2 of :meme: (once at 2099-01-01 00:00 UTC). \
+        Next post is at 2099-01-01 00:00 UTC."
+        ]
+    );
+
+    // Friday.
+    let friday = Utc.ymd(2026, 10, 23).and_hms(10, 0, 0).timestamp();
+    assert_eq!(
+        run_due(&h.bot, &h.db, friday - 20, friday).await.unwrap(),
+        1
+    );
+    let calls = h.api.take_calls();
+    assert_eq!(methods(&calls), vec!["sendSticker"]);
+    assert_eq!(calls[0].params["sticker"], json!("sticker1"));
+    assert!(!calls[0].params.contains_key("reply_to_message_id"));
+    assert_eq!(
+        run_due(&h.bot, &h.db, friday, friday + 20).await.unwrap(),
+        0
+    );
+
+    // One-time posts are removed after posting.
+    let at = Utc.ymd(2099, 1, 1).and_hms(0, 0, 0).timestamp();
+    assert_eq!(run_due(&h.bot, &h.db, at - 20, at).await.unwrap(), 1);
+    h.api.take_calls();
+    assert_eq!(run_due(&h.bot, &h.db, at, at + 20).await.unwrap(), 0);
+
+    let calls = h.send_text("/schedules").await;
+    let list = texts(&calls)[0];
+    assert!(list.contains("#1 :meme: - \"0 10 * * fri\", next at "));
+    assert!(!list.contains("#2"));
+    let keyboard = keyboard_message_id(&calls).unwrap();
+    let calls = h.press(CallbackData::Unschedule(1), keyboard).await;
+    assert_eq!(
+        methods(&calls),
+        vec!["answerCallbackQuery", "editMessageText"]
+    );
+    assert_eq!(calls[0].params["text"], json!("Cancelled #1."));
+    assert!(h
+        .db
+        .lock()
+        .await
+        .get_schedules(CHAT_ID)
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test]
+async fn test_schedule_sender() {
+    use crate::schedule::run_due;
+    use chrono::{TimeZone, Utc};
+
+    let h = Harness::new().await;
+    let snippet = Target::Text("{sender} says hi".to_owned());
+    h.db.lock()
+        .await
+        .set_alias(CHAT_ID, "hi", &snippet)
+        .await
+        .unwrap();
+    h.send_text("/schedule 2099-01-01 00:00 :hi:").await;
+    // The name is requested when posting instead.
+    let jobs = h.db.lock().await.get_schedules(CHAT_ID).await.unwrap();
+    assert!(!jobs[0].1.contains("User"));
+
+    let at = Utc.ymd(2099, 1, 1).and_hms(0, 0, 0).timestamp();
+    assert_eq!(run_due(&h.bot, &h.db, at - 20, at).await.unwrap(), 1);
+    let calls = h.api.take_calls();
+    assert_eq!(methods(&calls), vec!["getChatMember", "sendMessage"]);
+    assert_eq!(texts(&calls), vec!["User says hi"]);
+}
+
+#[tokio::test]
+async fn test_schedule_permissions() {
+    let h = Harness::new().await;
+    h.db.lock()
+        .await
+        .set_setting(GROUP_ID, "permissions", "admins")
+        .await
+        .unwrap();
+    let calls = h
+        .send(group_message(1, USER_ID, "/schedule 10:00 :a:"))
+        .await;
+    assert_eq!(
+        texts(&calls),
+        vec!["Only administrators can change aliases and settings here."]
+    );
+}
+
+#[tokio::test]
+async fn test_triggers() {
+    let h = with_aliases(&[("coffee", "sticker1"), ("wave", "sticker2")]).await;
+    let calls = h.send_text("/trigger good morning :nope:").await;
+    assert_eq!(texts(&calls), vec!["Unknown aliases: :nope:."]);
+    let calls = h.send_text("/trigger good morning").await;
+    assert!(texts(&calls)[0].starts_with("Unknown trigger format."));
+    let calls = h.send_text("/trigger good morning :coffee: 1h").await;
+    assert_eq!(
+        texts(&calls),
+        vec!["Added trigger #1: \"good morning\" -> :coffee:, cooldown 1h."]
+    );
+    h.send_text("/trigger /^hi\\b/ :wave:").await;
+
+    let sent = |calls: &[ApiCall]| -> Vec<serde_json::Value> {
+        calls
+            .iter()
+            .filter(|call| call.method == "sendSticker")
+            .map(|call| call.params["sticker"].clone())
+            .collect()
+    };
+    let calls = h.send_text("Good morning, hi").await;
+    assert_eq!(sent(&calls), vec![json!("sticker1")]);
+    // The alias is sent once, the second trigger is on cooldown.
+    let calls = h.send_text("hi :wave: good morning").await;
+    assert_eq!(sent(&calls), vec![json!("sticker2")]);
+    let calls = h.edit(text_message(1, "hi again")).await;
+    assert!(sent(&calls).is_empty());
+
+    let calls = h.send_text("/triggers").await;
+    assert_eq!(
+        texts(&calls),
+        vec![
+            "Triggers (press a button to delete one):\n\
+            #1 \"good morning\" -> :coffee:, cooldown 1h\n\
+            #2 /^hi\\b/ -> :wave:\n"
+        ]
+    );
+    let keyboard = keyboard_message_id(&calls).unwrap();
+    let calls = h.press(CallbackData::Untrigger(2), keyboard).await;
+    assert_eq!(
+        methods(&calls),
+        vec!["answerCallbackQuery", "editMessageText"]
+    );
+    assert_eq!(calls[0].params["text"], json!("Deleted #2."));
+    let calls = h.send_text("hi").await;
+    assert!(sent(&calls).is_empty());
+
+    // A trigger of a removed alias doesn't start its cooldown.
+    h.send_text("/trigger bye :wave: 1h").await;
+    h.db.lock()
+        .await
+        .remove_alias(CHAT_ID, "wave")
+        .await
+        .unwrap();
+    let calls = h.send_text("bye").await;
+    assert!(sent(&calls).is_empty());
+    h.db.lock()
+        .await
+        .set_alias(CHAT_ID, "wave", &sticker("sticker2"))
+        .await
+        .unwrap();
+    let calls = h.send_text("bye").await;
+    assert_eq!(sent(&calls), vec![json!("sticker2")]);
+}
+
+#[tokio::test]
+async fn test_rewrite() {
+    let h = Harness::new().await;
+    {
+        let mut db = h.db.lock().await;
+        let target = Target::Text("Bob".to_owned());
+        db.set_alias(CHAT_ID, "name", &target).await.unwrap();
+        db.set_setting(CHAT_ID, "rewrite", "on").await.unwrap();
+    }
+    // Text messages are parsed as commands with the bot username first.
+    let send = |message| async {
+        let mut calls = h.send(message).await;
+        calls.retain(|call| call.method != "getMe");
+        calls
+    };
+    let calls = send(text_message(5, "hi :name:")).await;
+    assert_eq!(methods(&calls), vec!["sendMessage", "deleteMessage"]);
+    assert_eq!(texts(&calls), vec!["User: hi Bob"]);
+    assert_eq!(calls[1].params["message_id"], json!(5));
+
+    // The copy is removed if the original can't be, the snippet is sent
+    // as usual.
+    let calls = send(text_message(UNDELETABLE_MESSAGE_ID, "hi :name:")).await;
+    assert_eq!(
+        methods(&calls),
+        vec![
+            "sendMessage",
+            "deleteMessage",
+            "deleteMessage",
+            "sendMessage"
+        ]
+    );
+    assert_eq!(calls[2].params["message_id"], json!(calls[0].message_id));
+    assert_eq!(calls[3].params["text"], json!("Bob"));
+
+    // Formatted messages are not rewritten.
+    let mut message = message_json(6, json!({"text": "hi :name:"}));
+    message["entities"] = json!([{"type": "bold", "offset": 0, "length": 2}]);
+    let calls = send(serde_json::from_value(message).unwrap()).await;
+    assert_eq!(methods(&calls), vec!["sendMessage"]);
+    assert_eq!(texts(&calls), vec!["Bob"]);
+}
+
+#[tokio::test]
+async fn test_typed_aliases_normalized() {
+    let h = with_aliases(&[("cry", "sticker1"), ("sad", "sticker2")]).await;
+    h.db.lock()
+        .await
+        .set_setting(CHAT_ID, "matching", "ignorecase")
+        .await
+        .unwrap();
+
+    let calls = h.send_text("/rename Cry sob").await;
+    assert_eq!(texts(&calls), vec!["Renamed 'cry' to 'sob'."]);
+    assert!(has_alias(&h, "sob").await);
+
+    let calls = h.send_text("/merge SOB Sad").await;
+    assert_eq!(
+        texts(&calls),
+        vec!["1 aliases now point to the sticker of 'sob'."]
+    );
+
+    h.send_text("/remove").await;
+    let calls = h.send_text("SOB sob Sad").await;
+    assert!(texts(&calls).contains(&"Removed 2/2 (duplicates are omitted)"));
+    assert!(!has_alias(&h, "sob").await);
+    assert!(!has_alias(&h, "sad").await);
+}
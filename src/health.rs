@@ -0,0 +1,106 @@
+//! Health status.
+//!
+//! Tracks what liveness and readiness probes of `http` server report.
+
+use crate::db::Storage;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use teloxide::prelude::*;
+use tokio::sync::Mutex;
+
+/// Time the bot was started at.
+static STARTED: Lazy<Instant> = Lazy::new(Instant::now);
+/// Unix time of the last received update (0 if none).
+static LAST_UPDATE: AtomicI64 = AtomicI64::new(0);
+/// Whether `get_me` succeeded at least once.
+static TELEGRAM_READY: AtomicBool = AtomicBool::new(false);
+
+/// How long the readiness probe waits for Redis.
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Start counting uptime.
+pub fn start() {
+    Lazy::force(&STARTED);
+}
+
+/// Remember that an update was received now.
+pub fn mark_update() {
+    LAST_UPDATE.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+/// Information about the running bot.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct Status {
+    pub version: &'static str,
+    pub uptime_seconds: u64,
+    /// Unix time of the last received update.
+    pub last_update: Option<i64>,
+}
+
+/// Get information about the running bot.
+pub fn status() -> Status {
+    let last_update = LAST_UPDATE.load(Ordering::Relaxed);
+    Status {
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_seconds: STARTED.elapsed().as_secs(),
+        last_update: (last_update != 0).then_some(last_update),
+    }
+}
+
+/// Readiness of the bot dependencies.
+#[derive(Serialize, PartialEq, Debug)]
+pub struct Readiness {
+    pub redis: bool,
+    pub telegram: bool,
+}
+
+impl Readiness {
+    pub fn is_ready(&self) -> bool {
+        self.redis && self.telegram
+    }
+}
+
+/// Check whether Redis responds and Telegram accepted the bot token.
+///
+/// `get_me` is requested only until it succeeds once.
+pub async fn check_readiness(bot: &AutoSend<Bot>, db: &Arc<Mutex<dyn Storage>>) -> Readiness {
+    let redis = tokio::time::timeout(REDIS_TIMEOUT, async { db.lock().await.ping().await })
+        .await
+        .unwrap_or(false);
+    if !TELEGRAM_READY.load(Ordering::Relaxed) {
+        match bot.get_me().await {
+            Ok(_) => TELEGRAM_READY.store(true, Ordering::Relaxed),
+            Err(e) => {
+                tracing::warn!("Could not get bot info: {}", e);
+                crate::metrics::telegram_error(&e);
+            }
+        }
+    }
+    Readiness {
+        redis,
+        telegram: TELEGRAM_READY.load(Ordering::Relaxed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness() {
+        let readiness = |redis, telegram| Readiness { redis, telegram }.is_ready();
+        assert!(readiness(true, true));
+        assert!(!readiness(true, false));
+        assert!(!readiness(false, true));
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(status().version, env!("CARGO_PKG_VERSION"));
+        mark_update();
+        assert!(status().last_update.is_some());
+    }
+}
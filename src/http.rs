@@ -0,0 +1,85 @@
+//! HTTP server for monitoring.
+//!
+//! Optional, started only if an address is configured. Serves Prometheus
+//! metrics and liveness/readiness probes.
+
+use crate::db::Storage;
+use crate::health;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use tokio::sync::Mutex;
+
+/// Environment variable with the address to listen on, e.g. `0.0.0.0:9090`.
+pub const ADDR_VAR: &str = "HTTP_ADDR";
+
+/// Response with given status and body.
+fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+}
+
+/// Response with JSON of `value`.
+fn respond_json<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(json) => {
+            let mut response = respond(status, json);
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                hyper::header::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        Err(e) => respond(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Respond to the request according to its path.
+async fn route(
+    request: Request<Body>,
+    bot: AutoSend<Bot>,
+    db: Arc<Mutex<dyn Storage>>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => respond(StatusCode::OK, crate::metrics::gather()),
+        (&Method::GET, "/health") => respond_json(StatusCode::OK, &health::status()),
+        (&Method::GET, "/ready") => {
+            let readiness = health::check_readiness(&bot, &db).await;
+            let status = if readiness.is_ready() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            respond_json(status, &readiness)
+        }
+        _ => respond(StatusCode::NOT_FOUND, "Not found"),
+    };
+    Ok(response)
+}
+
+/// Serve monitoring endpoints on `addr` until the process exits.
+pub async fn serve(addr: SocketAddr, bot: AutoSend<Bot>, db: Arc<Mutex<dyn Storage>>) {
+    let make_service = make_service_fn(move |_| {
+        let (bot, db) = (bot.clone(), db.clone());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                route(request, bot.clone(), db.clone())
+            }))
+        }
+    });
+    let server = match Server::try_bind(&addr) {
+        Ok(builder) => builder.serve(make_service),
+        Err(e) => {
+            tracing::error!("Could not bind HTTP server to {}: {}", addr, e);
+            return;
+        }
+    };
+    tracing::info!("Serving HTTP on {}", addr);
+    if let Err(e) = server.await {
+        tracing::error!("HTTP server failed: {}", e);
+    }
+}
@@ -0,0 +1,97 @@
+//! Logging setup.
+//!
+//! Events are emitted with `tracing`. Each update is handled inside a span
+//! carrying ids of its chat, sender and message and the dialogue state, so
+//! events don't repeat them. Records of `log` (used by dependencies) are
+//! forwarded to the same output.
+
+use tracing::field::Empty;
+use tracing::Span;
+use tracing_subscriber::EnvFilter;
+
+/// Environment variable with the filter, e.g. `info,tg_media_bot=debug`.
+const FILTER_VAR: &str = "RUST_LOG";
+/// Environment variable with the output format (`text` or `json`).
+const FORMAT_VAR: &str = "LOG_FORMAT";
+/// Filter used when `FILTER_VAR` is not set or invalid.
+const DEFAULT_FILTER: &str = "info";
+
+/// Output format of the log.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Set up the global subscriber according to the environment.
+pub fn init() {
+    let filter = EnvFilter::try_from_env(FILTER_VAR).unwrap_or_else(|_| DEFAULT_FILTER.into());
+    let format: LogFormat = std::env::var(FORMAT_VAR)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default();
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
+/// Span for handling an update of `kind` in the chat.
+///
+/// Dialogue state is unknown until the dialogue is loaded, it is recorded
+/// with `record_state`.
+pub fn update_span(
+    kind: &'static str,
+    chat_id: i64,
+    user_id: Option<i64>,
+    message_id: Option<i32>,
+) -> Span {
+    let span = tracing::info_span!(
+        "update",
+        kind,
+        chat_id,
+        user_id = Empty,
+        message_id = Empty,
+        state = Empty
+    );
+    if let Some(user_id) = user_id {
+        span.record("user_id", &user_id);
+    }
+    if let Some(message_id) = message_id {
+        span.record("message_id", &message_id);
+    }
+    span
+}
+
+/// Record dialogue state in the current update span.
+pub fn record_state(state: &str) {
+    Span::current().record("state", &state);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format_from_str() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert_eq!(" Text ".parse(), Ok(LogFormat::Text));
+        assert_eq!("yaml".parse::<LogFormat>(), Err(()));
+    }
+}
@@ -1,16 +1,38 @@
+mod admin;
+mod alias;
+mod cache;
+mod chats;
 mod commands;
 mod db;
 mod dialogue;
-mod utils;
+mod health;
+mod http;
+mod logging;
+mod media;
+mod metrics;
+mod owner;
+mod schedule;
+mod search;
+mod settings;
+mod template;
+#[cfg(test)]
+mod testing;
+mod trigger;
+mod updates;
 
-use crate::db::RedisConnection;
+use crate::alias::Target;
+use crate::db::{RedisStorageError, Storage};
 use crate::dialogue::Dialogue;
-use crate::utils::format_log_chat;
 use std::sync::Arc;
 use teloxide::prelude::*;
+use tracing::Instrument;
 // TODO: get rid of using tokio's Mutex https://tokio.rs/tokio/tutorial/channels
 use tokio::sync::Mutex;
 
+/// Sent when the stored dialogue can't be read after a bot update.
+const DIALOGUE_RESET_NOTICE: &str =
+    "The bot was updated and your unfinished action was reset. Please start it again.";
+
 #[tokio::main]
 async fn main() {
     run().await;
@@ -22,34 +44,93 @@ async fn main() {
 async fn run() {
     use tokio_stream::wrappers::UnboundedReceiverStream;
 
-    teloxide::enable_logging!();
-    log::info!("Starting dialogue bot...");
+    let args: Vec<String> = std::env::args().collect();
+    let config = parse_args(args);
+    if let Some(command) = config.command {
+        admin::run(&config.redis_ip, command).await;
+        return;
+    }
+
+    logging::init();
+    health::start();
+    tracing::info!("Starting dialogue bot...");
 
     let bot = Bot::from_env().auto_send();
+    commands::register_commands(&bot).await;
 
-    let args: Vec<String> = std::env::args().collect();
-    let config = parse_args(args);
+    let db_shared: Arc<Mutex<dyn Storage>> =
+        Arc::new(Mutex::new(
+            match db::RedisConnection::new(&config.redis_ip[..]).await {
+                Ok(v) => v,
+                Err(err) => panic!("Could not start redis connection: {}", err),
+            },
+        ));
 
-    let db_shared: Arc<Mutex<RedisConnection>> = Arc::new(Mutex::new(
-        match db::RedisConnection::new(&config.redis_ip[..]).await {
-            Ok(v) => v,
-            Err(err) => panic!("Could not start redis connection: {}", err),
-        },
-    ));
+    if let Ok(addr) = std::env::var(http::ADDR_VAR) {
+        match addr.parse() {
+            Ok(addr) => {
+                tokio::spawn(http::serve(addr, bot.clone(), db_shared.clone()));
+            }
+            Err(e) => tracing::error!("Invalid {}: {}", http::ADDR_VAR, e),
+        }
+    }
+
+    let readiness = health::check_readiness(&bot, &db_shared).await;
+    if !readiness.is_ready() {
+        tracing::warn!("Started without some dependencies: {:?}", readiness);
+    }
 
+    chats::start_cleanup(db_shared.clone());
+    media::start_backfill(bot.clone(), db_shared.clone());
+    schedule::start(bot.clone(), db_shared.clone());
+
+    let listener = updates::polling(bot.clone()).await;
     Dispatcher::new(bot)
-        .messages_handler(
+        .messages_handler({
+            let db_shared = db_shared.clone();
             |rx: UnboundedReceiver<UpdateWithCx<AutoSend<Bot>, Message>>| async move {
                 UnboundedReceiverStream::new(rx)
                     .for_each_concurrent(None, |cx| async {
                         handle_message(cx, db_shared.clone()).await
                     })
                     .await;
+            }
+        })
+        .edited_messages_handler({
+            let db_shared = db_shared.clone();
+            |rx: UnboundedReceiver<UpdateWithCx<AutoSend<Bot>, Message>>| async move {
+                UnboundedReceiverStream::new(rx)
+                    .for_each_concurrent(None, |cx| async {
+                        handle_edited_message(cx, db_shared.clone()).await
+                    })
+                    .await;
+            }
+        })
+        .callback_queries_handler({
+            let db_shared = db_shared.clone();
+            |rx: UnboundedReceiver<UpdateWithCx<AutoSend<Bot>, CallbackQuery>>| async move {
+                UnboundedReceiverStream::new(rx)
+                    .for_each_concurrent(None, |cx| async {
+                        handle_callback_query(cx, db_shared.clone()).await
+                    })
+                    .await;
+            }
+        })
+        .my_chat_members_handler(
+            |rx: UnboundedReceiver<UpdateWithCx<AutoSend<Bot>, ChatMemberUpdated>>| async move {
+                UnboundedReceiverStream::new(rx)
+                    .for_each_concurrent(None, |cx| async {
+                        chats::handle_my_chat_member(cx, db_shared.clone()).await
+                    })
+                    .await;
             },
         )
-        .dispatch()
+        .dispatch_with_listener(
+            listener,
+            LoggingErrorHandler::with_custom_text("An error from the update listener"),
+        )
         .await;
-    log::info!("Closing the bot...");
+    tracing::info!("Closing the bot...");
 }
 
 /// Application configuration.
@@ -58,31 +139,43 @@ async fn run() {
 #[derive(PartialEq, Debug)]
 struct Config {
     redis_ip: String,
+    /// Administration command to run instead of the bot.
+    command: Option<admin::AdminCommand>,
 }
 
 /// Parse config from splitted arguments.
 ///
-/// Assumes `std::env::args().collect()` ordering and length of vector.
+/// Assumes `std::env::args().collect()` ordering. Redis address is
+/// optional and may be followed by an administration command.
 fn parse_args(args: Vec<String>) -> Config {
-    match args.len() {
-        1 => Config {
-            redis_ip: String::from("redis://127.0.0.1/"),
-        },
-        2 => Config {
-            redis_ip: String::from("redis://") + &args[1][..] + "/",
-        },
-        _ => {
-            print_usage();
-            panic!();
+    let mut rest = args.get(1..).unwrap_or_default();
+    let redis_ip = match rest.first() {
+        Some(arg) if !admin::COMMANDS.contains(&arg.as_str()) => {
+            rest = &rest[1..];
+            String::from("redis://") + &arg[..] + "/"
         }
-    }
+        _ => String::from("redis://127.0.0.1/"),
+    };
+    let command = match rest {
+        [] => None,
+        args => match admin::AdminCommand::parse(args) {
+            Ok(command) => Some(command),
+            Err(e) => {
+                println!("{}", e);
+                print_usage();
+                panic!();
+            }
+        },
+    };
+    Config { redis_ip, command }
 }
 
 /// Print out usage of the application in standard output
 fn print_usage() {
     println!(
         "Telegram bot. Run with no arguments or specify redis ip as first argument \
-    (without 'redis://' prefix)."
+    (without 'redis://' prefix).\n\n{}",
+        admin::USAGE
     )
 }
 
@@ -96,7 +189,7 @@ fn print_usage() {
 async fn handle_dialogue(
     cx: UpdateWithCx<AutoSend<Bot>, Message>,
     dialogue: Dialogue,
-    db: Arc<Mutex<RedisConnection>>,
+    db: Arc<Mutex<dyn Storage>>,
 ) -> TransitionOut<Dialogue> {
     use crate::commands::Command;
     use crate::dialogue::Answer;
@@ -104,14 +197,8 @@ async fn handle_dialogue(
     use teloxide::utils::command::BotCommand;
 
     // Don't know hot to avoid repeating of this code properly
-    async fn default_response(
-        cx: UpdateWithCx<AutoSend<Bot>, Message>,
-        dialogue: Dialogue,
-    ) -> TransitionOut<Dialogue> {
-        log::info!(
-            "{}",
-            format_log_chat("Received something else", cx.chat_id())
-        );
+    fn default_response(dialogue: Dialogue) -> TransitionOut<Dialogue> {
+        tracing::info!("Received something else");
         next(dialogue)
     }
 
@@ -128,109 +215,315 @@ async fn handle_dialogue(
                         bot_info.user.username.unwrap_or_default(),
                     ) {
                         Ok(cmd) => {
-                            log::info!(
-                                "{}",
-                                format_log_chat("Received a bot command", cx.chat_id())
-                            );
+                            tracing::info!("Received a bot command");
+                            metrics::COMMANDS.with_label_values(&[cmd.name()]).inc();
+                            let from_id = cx.update.from().map(|u| u.id);
+                            if cmd.changes_chat()
+                                && !settings::can_change(
+                                    &cx.requester,
+                                    &cx.update.chat,
+                                    from_id,
+                                    &db,
+                                )
+                                .await
+                            {
+                                tracing::info!("Command is not permitted");
+                                cx.answer(settings::PERMISSION_NOTICE).await?;
+                                return next(dialogue);
+                            }
                             Answer::Command(cmd)
                         }
                         Err(_) => {
-                            log::info!(
-                                "{}",
-                                format_log_chat(
-                                    "Received a text or unsupported command",
-                                    cx.chat_id()
-                                )
-                            );
+                            tracing::info!("Received a text or unsupported command");
                             Answer::String(media.text.clone())
                         }
                     };
                 }
-                MediaKind::Sticker(media) => {
-                    log::info!("{}", format_log_chat("Received a sticker", cx.chat_id()));
-                    ans = Answer::Sticker(media.sticker.clone());
-                }
-                _ => {
-                    return default_response(cx, dialogue).await;
-                }
+                _ => match Target::from_message(&cx.update) {
+                    Some(target) => {
+                        tracing::info!("Received media");
+                        ans = Answer::Media(target);
+                    }
+                    None => {
+                        return default_response(dialogue);
+                    }
+                },
             }
 
             // Forward the user answer to dialogue to handle.
             let args = crate::dialogue::Args { ans, db };
             dialogue.react(cx, args).await
         }
-        _ => default_response(cx, dialogue).await,
+        _ => default_response(dialogue),
     }
 }
 
 /// Handle message update.
 ///
-/// Find `Dialogue` for `handle_dialogue` from db. Use the function
-/// result to update dialogue state in database.
+/// Use `handle_dialogue` on behalf of the message sender.
 async fn handle_message(
     cx: UpdateWithCx<AutoSend<Bot>, Message>,
-    db_shared: Arc<Mutex<RedisConnection>>,
+    db_shared: Arc<Mutex<dyn Storage>>,
+) {
+    health::mark_update();
+    metrics::UPDATES
+        .with_label_values(&[metrics::message_kind(&cx.update)])
+        .inc();
+    let from_id = cx.update.from().map(|u| u.id);
+    let span = logging::update_span("message", cx.chat_id(), from_id, Some(cx.update.id));
+    async move {
+        if chats::handle_migration(&cx, &db_shared).await {
+            return;
+        }
+        if owner::handle_owner_message(&cx, &db_shared).await {
+            return;
+        }
+        run_dialogue(cx, from_id, None, db_shared).await;
+    }
+    .instrument(span)
+    .await
+}
+
+/// Handle edited message update.
+///
+/// Pass the new text or caption to the dialogue of the message sender.
+async fn handle_edited_message(
+    cx: UpdateWithCx<AutoSend<Bot>, Message>,
+    db_shared: Arc<Mutex<dyn Storage>>,
 ) {
-    let mut db_con: tokio::sync::MutexGuard<RedisConnection> = db_shared.lock().await;
+    use crate::dialogue::Answer;
+
+    health::mark_update();
+
+    metrics::UPDATES
+        .with_label_values(&["edited_message"])
+        .inc();
+    let text = match cx.update.text().or_else(|| cx.update.caption()) {
+        Some(text) => text.to_owned(),
+        None => return,
+    };
+    let from_id = cx.update.from().map(|u| u.id);
+    let span = logging::update_span("edited_message", cx.chat_id(), from_id, Some(cx.update.id));
+    async move {
+        tracing::info!("Received an edited message");
+        run_dialogue(cx, from_id, Some(Answer::Edited(text)), db_shared).await;
+    }
+    .instrument(span)
+    .await
+}
+
+/// Handle callback query update (inline keyboard button press).
+///
+/// Acknowledge the query and pass it to the dialogue of the user who
+/// pressed the button, in context of the message with the keyboard.
+async fn handle_callback_query(
+    cx: UpdateWithCx<AutoSend<Bot>, CallbackQuery>,
+    db_shared: Arc<Mutex<dyn Storage>>,
+) {
+    use crate::dialogue::{Answer, CallbackData};
+
+    let UpdateWithCx {
+        requester,
+        update: query,
+    } = cx;
+    health::mark_update();
+    metrics::UPDATES
+        .with_label_values(&["callback_query"])
+        .inc();
+    let span = logging::update_span(
+        "callback_query",
+        query
+            .message
+            .as_ref()
+            .map_or(query.from.id, |m| m.chat_id()),
+        Some(query.from.id),
+        query.message.as_ref().map(|m| m.id),
+    );
+    async move {
+        // Menus below work regardless of the dialogue state, so they are
+        // checked here instead of `run_dialogue`.
+        let menu_press = matches!(
+            query.data.as_deref().and_then(CallbackData::parse),
+            Some(
+                CallbackData::Setting(_) | CallbackData::Unschedule(_) | CallbackData::Untrigger(_)
+            )
+        );
+        if let (true, Some(message)) = (menu_press, &query.message) {
+            let cx = UpdateWithCx {
+                requester: requester.clone(),
+                update: message.clone(),
+            };
+            if !owner::check_access(&cx, Some(query.from.id), &db_shared).await {
+                if let Err(e) = requester.answer_callback_query(query.id.clone()).await {
+                    tracing::warn!("Could not answer callback query: {:?}", e);
+                    metrics::telegram_error(&e);
+                }
+                return;
+            }
+        }
+        // Settings menu works regardless of the dialogue state.
+        if let Some(CallbackData::Setting(index)) =
+            query.data.as_deref().and_then(CallbackData::parse)
+        {
+            tracing::info!("Received a settings menu press");
+            if let Err(e) = settings::handle_press(&requester, &query, index, &db_shared).await {
+                tracing::warn!("Could not handle settings menu press: {}", e);
+                metrics::telegram_error(&e);
+            }
+            return;
+        }
+        // So does the list of scheduled posts.
+        if let Some(CallbackData::Unschedule(id)) =
+            query.data.as_deref().and_then(CallbackData::parse)
+        {
+            tracing::info!("Received a scheduled post cancellation");
+            if let Err(e) = schedule::handle_press(&requester, &query, id, &db_shared).await {
+                tracing::warn!("Could not handle scheduled post cancellation: {}", e);
+                metrics::telegram_error(&e);
+            }
+            return;
+        }
+        // And the list of triggers.
+        if let Some(CallbackData::Untrigger(id)) =
+            query.data.as_deref().and_then(CallbackData::parse)
+        {
+            tracing::info!("Received a trigger deletion");
+            if let Err(e) = trigger::handle_press(&requester, &query, id, &db_shared).await {
+                tracing::warn!("Could not handle trigger deletion: {}", e);
+                metrics::telegram_error(&e);
+            }
+            return;
+        }
+        if let Err(e) = requester.answer_callback_query(query.id.clone()).await {
+            tracing::warn!("Could not answer callback query: {:?}", e);
+            metrics::telegram_error(&e);
+        }
+        let (message, data) = match (
+            query.message,
+            query.data.as_deref().and_then(CallbackData::parse),
+        ) {
+            (Some(message), Some(data)) => (message, data),
+            _ => {
+                tracing::info!("Ignoring callback query without message or known data");
+                return;
+            }
+        };
+        tracing::info!("Received a callback query");
+        let ans = Answer::Callback {
+            data,
+            message_id: message.id,
+        };
+        let cx = UpdateWithCx {
+            requester,
+            update: message,
+        };
+        run_dialogue(cx, Some(query.from.id), Some(ans), db_shared).await;
+    }
+    .instrument(span)
+    .await
+}
+
+/// Run dialogue of user `from_id` in the chat of `cx`.
+///
+/// Find `Dialogue` for `handle_dialogue` from db. Use the function
+/// result to update dialogue state in database. `ans` is passed
+/// to the dialogue instead of parsing the message if given. Banned
+/// users and chats are ignored, see `owner::check_access`.
+async fn run_dialogue(
+    cx: UpdateWithCx<AutoSend<Bot>, Message>,
+    from_id: Option<i64>,
+    ans: Option<crate::dialogue::Answer>,
+    db_shared: Arc<Mutex<dyn Storage>>,
+) {
+    if !owner::check_access(&cx, from_id, &db_shared).await {
+        return;
+    }
+    let mut db_con: tokio::sync::MutexGuard<dyn Storage> = db_shared.lock().await;
 
     // Obtain dialogue from database
     let chat_id = cx.update.chat_id();
-    let from_id = cx.update.from().map(|u| u.id);
     let dialogue: Dialogue = match db_con
         .get_dialogue(chat_id, from_id)
         .await
         .map(Option::unwrap_or_default)
     {
         Ok(d) => d,
+        // Saved by an incompatible version of the bot, don't leave the user stuck.
+        Err(RedisStorageError::SerdeError(e)) => {
+            tracing::warn!("Resetting dialogue that can't be migrated: {}", e);
+            if let Err(e) = cx.answer(DIALOGUE_RESET_NOTICE).await {
+                tracing::warn!("Could not notify about dialogue reset: {}", e);
+                metrics::telegram_error(&e);
+            }
+            Dialogue::default()
+        }
         Err(e) => {
-            log::error!(
-                "{}",
-                format_log_chat(
-                    &format!(
-                        "Could not get dialogue (from {f:?}): {e:?}",
-                        f = from_id,
-                        e = e
-                    ),
-                    chat_id
-                )
+            tracing::error!(
+                "Could not get dialogue (from {f:?}): {e:?}",
+                f = from_id,
+                e = e
             );
             return;
         }
     };
     drop(db_con);
+    let state = dialogue.state_name();
+    let was_active = dialogue.is_active();
+    logging::record_state(state);
 
     // Handle the dialogue and receive results.
-    let stage = match handle_dialogue(cx, dialogue, db_shared.clone()).await {
+    let result = match ans {
+        Some(ans) => {
+            let args = crate::dialogue::Args {
+                ans,
+                db: db_shared.clone(),
+            };
+            dialogue.react(cx, args).await
+        }
+        None => handle_dialogue(cx, dialogue, db_shared.clone()).await,
+    };
+    let stage = match result {
         Ok(a) => a,
         Err(e) => {
-            log::error!(
-                "{}",
-                format_log_chat(
-                    &format!(
-                        "Could not handle dialogue (from {f:?}): {e:?}",
-                        f = from_id,
-                        e = e
-                    ),
-                    chat_id
-                )
+            tracing::error!(
+                "Could not handle dialogue (from {f:?}): {e:?}",
+                f = from_id,
+                e = e
             );
+            metrics::telegram_error(&e);
             return;
         }
     };
 
-    let mut db_con: tokio::sync::MutexGuard<RedisConnection> = db_shared.lock().await;
+    let (new_state, is_active) = match &stage {
+        DialogueStage::Next(new_dialogue) => (new_dialogue.state_name(), new_dialogue.is_active()),
+        DialogueStage::Exit => ("Exit", false),
+    };
+    metrics::DIALOGUE_TRANSITIONS
+        .with_label_values(&[state, new_state])
+        .inc();
+    match (was_active, is_active) {
+        (false, true) => metrics::ACTIVE_DIALOGUES.inc(),
+        (true, false) => metrics::ACTIVE_DIALOGUES.dec(),
+        _ => {}
+    }
+
+    let mut db_con: tokio::sync::MutexGuard<dyn Storage> = db_shared.lock().await;
     // Update the dialogue state in database.
     match stage {
         DialogueStage::Next(new_dialogue) => {
-            if let Err(e) = db_con.update_dialogue(chat_id, from_id, new_dialogue).await {
-                log::error!("Storage::update_dialogue failed: {:?}", e);
-            }
-        }
-        DialogueStage::Exit => {
-            if let Err(e) = db_con.remove_dialogue(chat_id, from_id).await {
-                log::error!("Storage::remove_dialogue failed: {:?}", e);
+            if let Err(e) = db_con
+                .update_dialogue(chat_id, from_id, &new_dialogue)
+                .await
+            {
+                tracing::error!("Storage::update_dialogue failed: {:?}", e);
             }
         }
+        DialogueStage::Exit => match db_con.remove_dialogue(chat_id, from_id).await {
+            // Already removed, e.g. by /forgetme.
+            Ok(()) | Err(RedisStorageError::DialogueNotFound) => {}
+            Err(e) => tracing::error!("Storage::remove_dialogue failed: {:?}", e),
+        },
     }
 }
 
@@ -244,7 +537,8 @@ mod tests {
         assert_eq!(
             parse_args(args),
             Config {
-                redis_ip: "redis://127.0.0.1/".to_owned()
+                redis_ip: "redis://127.0.0.1/".to_owned(),
+                command: None
             }
         );
 
@@ -252,7 +546,29 @@ mod tests {
         assert_eq!(
             parse_args(args),
             Config {
-                redis_ip: format!("redis://{}/", "192.168.88.123").to_owned()
+                redis_ip: format!("redis://{}/", "192.168.88.123").to_owned(),
+                command: None
+            }
+        );
+
+        let args = vec!["asdsad".to_owned(), "stats".to_owned()];
+        assert_eq!(
+            parse_args(args),
+            Config {
+                redis_ip: "redis://127.0.0.1/".to_owned(),
+                command: Some(admin::AdminCommand::Stats)
+            }
+        );
+
+        let args: Vec<String> = vec!["asdsad", "10.0.0.1", "export", "-100"]
+            .into_iter()
+            .map(str::to_owned)
+            .collect();
+        assert_eq!(
+            parse_args(args),
+            Config {
+                redis_ip: "redis://10.0.0.1/".to_owned(),
+                command: Some(admin::AdminCommand::Export { chat_id: -100 })
             }
         );
     }
@@ -0,0 +1,460 @@
+//! Media sending.
+//!
+//! Sends alias targets to chats, grouping compatible media into albums,
+//! and keeps file ids of stored media working.
+
+use crate::alias::Target;
+use crate::db::{RedisStorageError, Storage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{
+    InputFile, InputMedia, InputMediaDocument, InputMediaPhoto, InputMediaVideo,
+};
+use teloxide::{ApiError, RequestError};
+use tokio::sync::Mutex;
+
+/// Type of media an alias can be assigned to.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum MediaType {
+    Sticker,
+    Photo,
+    Video,
+    Document,
+    Animation,
+}
+
+impl MediaType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Sticker => "sticker",
+            MediaType::Photo => "photo",
+            MediaType::Video => "video",
+            MediaType::Document => "document",
+            MediaType::Animation => "animation",
+        }
+    }
+
+    /// Get media type by its name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sticker" => Some(MediaType::Sticker),
+            "photo" => Some(MediaType::Photo),
+            "video" => Some(MediaType::Video),
+            "document" => Some(MediaType::Document),
+            "animation" => Some(MediaType::Animation),
+            _ => None,
+        }
+    }
+
+    /// Kind of album the media can be sent in (if any).
+    ///
+    /// Telegram allows mixing photos and videos in one album, while
+    /// documents can only be grouped with documents.
+    fn album_kind(&self) -> Option<AlbumKind> {
+        match self {
+            MediaType::Photo | MediaType::Video => Some(AlbumKind::Visual),
+            MediaType::Document => Some(AlbumKind::Documents),
+            MediaType::Sticker | MediaType::Animation => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum AlbumKind {
+    Visual,
+    Documents,
+}
+
+/// Minimum number of media in an album.
+const MIN_ALBUM_SIZE: usize = 2;
+/// Maximum number of media in an album.
+const MAX_ALBUM_SIZE: usize = 10;
+
+/// Message to be sent in response.
+#[derive(PartialEq, Debug)]
+pub enum Outgoing<T> {
+    Single(T),
+    /// Media group of photos/videos or documents.
+    Album(Vec<T>),
+}
+
+/// Split targets into messages preserving their order.
+///
+/// Consecutive compatible media are grouped into albums, everything else
+/// is sent separately.
+pub fn plan_messages<T, F>(targets: Vec<T>, media_type: F) -> Vec<Outgoing<T>>
+where
+    F: Fn(&T) -> Option<MediaType>,
+{
+    let mut messages: Vec<Outgoing<T>> = vec![];
+    let mut album: Vec<T> = vec![];
+    let mut album_kind: Option<AlbumKind> = None;
+
+    fn flush<T>(album: &mut Vec<T>, messages: &mut Vec<Outgoing<T>>) {
+        if album.len() >= MIN_ALBUM_SIZE {
+            messages.push(Outgoing::Album(std::mem::take(album)));
+        } else {
+            messages.extend(album.drain(..).map(Outgoing::Single));
+        }
+    }
+
+    for target in targets {
+        let kind = media_type(&target).and_then(|t| t.album_kind());
+        if kind.is_none() || kind != album_kind || album.len() == MAX_ALBUM_SIZE {
+            flush(&mut album, &mut messages);
+            album_kind = kind;
+        }
+        match kind {
+            Some(_) => album.push(target),
+            None => messages.push(Outgoing::Single(target)),
+        }
+    }
+    flush(&mut album, &mut messages);
+    messages
+}
+
+/// Send media with given type and file id to the chat.
+///
+/// The media is sent as a reply to message `reply_to` (if any).
+pub async fn send_media(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    media_type: MediaType,
+    file_id: &str,
+    reply_to: Option<i32>,
+) -> Result<(), teloxide::RequestError> {
+    let file = InputFile::FileId(file_id.to_owned());
+    send_file(bot, chat_id, media_type, file, reply_to)
+        .await
+        .map(drop)
+}
+
+/// Send `file` as media of the type to the chat.
+///
+/// The media is sent as a reply to message `reply_to` (if any). Returns
+/// the sent message.
+pub async fn send_file(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    media_type: MediaType,
+    file: InputFile,
+    reply_to: Option<i32>,
+) -> Result<Message, teloxide::RequestError> {
+    // Each request has its own payload type, so the reply is set in a macro.
+    macro_rules! send {
+        ($request:expr) => {
+            match reply_to {
+                Some(id) => $request.reply_to_message_id(id).await,
+                None => $request.await,
+            }
+        };
+    }
+    match media_type {
+        MediaType::Sticker => send!(bot.send_sticker(chat_id, file)),
+        MediaType::Photo => send!(bot.send_photo(chat_id, file)),
+        MediaType::Video => send!(bot.send_video(chat_id, file)),
+        MediaType::Document => send!(bot.send_document(chat_id, file)),
+        MediaType::Animation => send!(bot.send_animation(chat_id, file)),
+    }
+}
+
+/// Send media targets as one album to the chat.
+///
+/// Non-media targets are skipped. The album is sent as a reply to
+/// message `reply_to` (if any).
+pub async fn send_album(
+    bot: &AutoSend<Bot>,
+    chat_id: i64,
+    targets: Vec<Target>,
+    reply_to: Option<i32>,
+) -> Result<(), teloxide::RequestError> {
+    let media: Vec<InputMedia> = targets
+        .into_iter()
+        .filter_map(|target| match target {
+            Target::Media {
+                media_type,
+                file_id,
+                ..
+            } => {
+                let file = InputFile::FileId(file_id);
+                match media_type {
+                    MediaType::Photo => Some(InputMedia::Photo(InputMediaPhoto::new(file))),
+                    MediaType::Video => Some(InputMedia::Video(InputMediaVideo::new(file))),
+                    MediaType::Document => {
+                        Some(InputMedia::Document(InputMediaDocument::new(file)))
+                    }
+                    MediaType::Sticker | MediaType::Animation => None,
+                }
+            }
+            Target::Text(_) => None,
+        })
+        .collect();
+    let mut request = bot.send_media_group(chat_id, media);
+    if let Some(id) = reply_to {
+        request = request.reply_to_message_id(id);
+    }
+    request.await?;
+    Ok(())
+}
+
+/// Whether sending media failed because its file can't be used anymore.
+///
+/// Such media won't be sent with the same file id, unlike after network
+/// or rate limit errors.
+pub fn is_unavailable(error: &RequestError) -> bool {
+    match error {
+        RequestError::ApiError {
+            kind: ApiError::WrongFileId | ApiError::FileIdInvalid,
+            ..
+        } => true,
+        RequestError::ApiError {
+            kind: ApiError::Unknown(description),
+            ..
+        } => {
+            let description = description.to_lowercase().replace('_', " ");
+            ["file identifier", "file reference", "wrong file id"]
+                .iter()
+                .any(|pattern| description.contains(pattern))
+        }
+        _ => false,
+    }
+}
+
+/// Update aliases of the media in the chat to its file id in `target`.
+///
+/// Telegram may change file ids of the same file (identified by the
+/// file unique id) and the stored ones may stop working. Only aliases
+/// with a different file id are saved again and the broken mark of the
+/// media is removed. Returns aliases of the media if it was marked broken.
+pub async fn refresh(
+    bot: &AutoSend<Bot>,
+    db: &mut dyn Storage,
+    chat_id: i64,
+    target: &Target,
+) -> Vec<String> {
+    let unique_id = match target.unique_id() {
+        Some(unique_id) => unique_id,
+        None => return vec![],
+    };
+    let aliases = db
+        .get_sticker_aliases(chat_id, unique_id)
+        .await
+        .unwrap_or_default();
+    if aliases.is_empty() {
+        return vec![];
+    }
+    let names: Vec<&str> = aliases.iter().map(String::as_str).collect();
+    let stored = db.get_targets(chat_id, &names).await.unwrap_or_default();
+    for (alias, stored) in names.iter().zip(stored) {
+        if stored.is_some_and(|stored| &stored != target) {
+            tracing::info!("Refreshing file id of alias '{}'", alias);
+            if let Err(e) = crate::cache::set_alias(bot, db, chat_id, alias, target).await {
+                tracing::error!("Failed to refresh file id: {}", e);
+            }
+        }
+    }
+    match db.get_broken_at(chat_id, unique_id).await {
+        Ok(Some(_)) => {
+            if let Err(e) = db.set_broken(chat_id, unique_id, None).await {
+                tracing::error!("Failed to unmark broken media: {}", e);
+            }
+            aliases
+        }
+        Ok(None) => vec![],
+        Err(e) => {
+            tracing::error!("Failed to check broken media: {}", e);
+            vec![]
+        }
+    }
+}
+
+/// Bot setting marking that all aliases were added to the reverse index.
+const BACKFILL_SETTING: &str = "alias_index_backfilled";
+
+/// Add media aliases saved without file unique id to the reverse index.
+///
+/// Older versions stored only file ids, so unique ids are requested
+/// from Telegram. The storage is locked for each chat and alias, not
+/// for the whole run. It is repeated at the next start if some requests
+/// failed for other reasons than unavailable files. Returns number of
+/// indexed aliases.
+pub async fn backfill_index(
+    bot: &AutoSend<Bot>,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<usize, RedisStorageError> {
+    if db
+        .lock()
+        .await
+        .get_bot_setting(BACKFILL_SETTING)
+        .await?
+        .is_some()
+    {
+        return Ok(0);
+    }
+    let chat_ids = db.lock().await.scan_chat_ids().await?;
+    let mut unique_ids: HashMap<String, Option<String>> = HashMap::new();
+    let mut complete = true;
+    let mut indexed = 0;
+    for chat_id in chat_ids {
+        let legacy = {
+            let mut db = db.lock().await;
+            let mut legacy = vec![];
+            for (alias, _) in db.scan_aliases(chat_id).await.unwrap_or_default() {
+                if let Some(
+                    target @ Target::Media {
+                        unique_id: None, ..
+                    },
+                ) = db.get_target(chat_id, &alias).await
+                {
+                    legacy.push((alias, target));
+                }
+            }
+            legacy
+        };
+        for (alias, target) in legacy {
+            let file_id = match &target {
+                Target::Media { file_id, .. } => file_id.clone(),
+                Target::Text(_) => continue,
+            };
+            if !unique_ids.contains_key(&file_id) {
+                let unique_id = match bot.get_file(&file_id).await {
+                    Ok(file) => Some(file.file_unique_id),
+                    Err(e) => {
+                        tracing::warn!("Failed to get unique id of '{}': {}", file_id, e);
+                        complete &= is_unavailable(&e);
+                        None
+                    }
+                };
+                unique_ids.insert(file_id.clone(), unique_id);
+            }
+            let unique_id = match &unique_ids[&file_id] {
+                Some(unique_id) => unique_id.clone(),
+                None => continue,
+            };
+            let mut db = db.lock().await;
+            // The alias could be changed while the storage was unlocked.
+            if db.get_target(chat_id, &alias).await.as_ref() == Some(&target) {
+                let with_id = target.with_unique_id(Some(unique_id.clone()));
+                crate::cache::set_alias(bot, &mut *db, chat_id, &alias, &with_id).await?;
+                // Media is marked broken by file id only without unique id.
+                if let Some(broken_at) = db.get_broken_at(chat_id, &file_id).await? {
+                    db.set_broken(chat_id, &file_id, None).await?;
+                    db.set_broken(chat_id, &unique_id, Some(broken_at)).await?;
+                }
+                indexed += 1;
+            }
+        }
+    }
+    if complete {
+        db.lock()
+            .await
+            .set_bot_setting(BACKFILL_SETTING, "1")
+            .await?;
+    }
+    Ok(indexed)
+}
+
+/// Start adding aliases saved by older versions to the reverse index.
+pub fn start_backfill(bot: AutoSend<Bot>, db: Arc<Mutex<dyn Storage>>) {
+    tokio::spawn(async move {
+        match backfill_index(&bot, &db).await {
+            Ok(0) => {}
+            Ok(n) => tracing::info!("Added {} aliases to the reverse index", n),
+            Err(e) => tracing::error!("Failed to add aliases to the reverse index: {}", e),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan_messages() {
+        use MediaType::*;
+        use Outgoing::*;
+
+        let plan = |targets: Vec<Option<MediaType>>| plan_messages(targets, |t| *t);
+        assert_eq!(
+            plan(vec![Some(Photo), Some(Video), Some(Sticker), Some(Photo)]),
+            vec![
+                Album(vec![Some(Photo), Some(Video)]),
+                Single(Some(Sticker)),
+                Single(Some(Photo))
+            ]
+        );
+        assert_eq!(
+            plan(vec![Some(Photo), Some(Document), Some(Document), None]),
+            vec![
+                Single(Some(Photo)),
+                Album(vec![Some(Document), Some(Document)]),
+                Single(None)
+            ]
+        );
+        assert_eq!(
+            plan(vec![Some(Animation), Some(Animation)]),
+            vec![Single(Some(Animation)), Single(Some(Animation))]
+        );
+        let sizes = |n: usize| -> Vec<usize> {
+            plan(vec![Some(Photo); n])
+                .into_iter()
+                .map(|m| match m {
+                    Single(_) => 1,
+                    Album(media) => media.len(),
+                })
+                .collect()
+        };
+        assert_eq!(sizes(11), vec![10, 1]);
+        assert_eq!(sizes(12), vec![10, 2]);
+    }
+
+    #[test]
+    fn test_is_unavailable() {
+        let api_error = |kind| RequestError::ApiError {
+            kind,
+            status_code: reqwest::StatusCode::BAD_REQUEST,
+        };
+        assert!(is_unavailable(&api_error(ApiError::WrongFileId)));
+        assert!(is_unavailable(&api_error(ApiError::Unknown(
+            "Bad Request: FILE_REFERENCE_EXPIRED".to_owned()
+        ))));
+        assert!(!is_unavailable(&api_error(
+            ApiError::MessageToDeleteNotFound
+        )));
+        assert!(!is_unavailable(&RequestError::RetryAfter(1)));
+    }
+
+    #[tokio::test]
+    async fn test_backfill_index() {
+        use crate::testing::{Harness, CHAT_ID};
+
+        let h = Harness::new().await;
+        let legacy = Target::Media {
+            media_type: MediaType::Sticker,
+            file_id: "file".to_owned(),
+            unique_id: None,
+        };
+        {
+            let mut db = h.db.lock().await;
+            db.set_alias(CHAT_ID, "cry", &legacy).await.unwrap();
+            db.set_broken(CHAT_ID, "file", Some(10)).await.unwrap();
+            assert_eq!(
+                db.get_sticker_aliases(CHAT_ID, "unique-file").await,
+                Some(vec![])
+            );
+        }
+        assert_eq!(backfill_index(&h.bot, &h.db).await.unwrap(), 1);
+        let mut db = h.db.lock().await;
+        assert_eq!(
+            db.get_sticker_aliases(CHAT_ID, "unique-file").await,
+            Some(vec!["cry".to_owned()])
+        );
+        assert_eq!(db.get_broken(CHAT_ID).await.unwrap(), vec!["unique-file"]);
+        db.set_alias(CHAT_ID, "sob", &legacy).await.unwrap();
+        drop(db);
+        // The backfill is done once.
+        assert_eq!(backfill_index(&h.bot, &h.db).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,136 @@
+//! Prometheus metrics.
+//!
+//! Metrics are collected always and exposed by `http` server (if enabled).
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use teloxide::types::{MediaKind, Message, MessageKind};
+use teloxide::RequestError;
+
+/// Prefix of all metric names.
+const NAMESPACE: &str = "tg_media_bot";
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+/// Registers `collector` in `REGISTRY` and returns it.
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric can't be registered twice");
+    collector
+}
+
+fn counter_vec(name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    let opts = Opts::new(name, help).namespace(NAMESPACE);
+    register(IntCounterVec::new(opts, labels).expect("metric options are valid"))
+}
+
+/// Received updates by kind (media kind for messages).
+pub static UPDATES: Lazy<IntCounterVec> =
+    Lazy::new(|| counter_vec("updates_total", "Received updates", &["kind"]));
+
+/// Received bot commands by command.
+pub static COMMANDS: Lazy<IntCounterVec> =
+    Lazy::new(|| counter_vec("commands_total", "Received bot commands", &["command"]));
+
+/// Aliases found in messages by result (`resolved`, `missed`, `broken`
+/// if media of the alias can't be sent, `reuploaded` if it was sent
+/// from the media cache instead or `triggered` if a keyword trigger
+/// fired).
+pub static ALIASES: Lazy<IntCounterVec> =
+    Lazy::new(|| counter_vec("aliases_total", "Aliases found in messages", &["result"]));
+
+/// Failed Telegram API requests by error kind.
+pub static TELEGRAM_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "telegram_errors_total",
+        "Failed Telegram API requests",
+        &["kind"],
+    )
+});
+
+/// Dialogue state changes by states before and after.
+pub static DIALOGUE_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    counter_vec(
+        "dialogue_transitions_total",
+        "Dialogue state changes",
+        &["from", "to"],
+    )
+});
+
+/// Dialogues in progress (in any state except the default one).
+pub static ACTIVE_DIALOGUES: Lazy<IntGauge> = Lazy::new(|| {
+    let opts = Opts::new("active_dialogues", "Dialogues in progress").namespace(NAMESPACE);
+    register(IntGauge::with_opts(opts).expect("metric options are valid"))
+});
+
+/// Duration of Redis operations by operation.
+pub static REDIS_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = HistogramOpts::new("redis_latency_seconds", "Duration of Redis operations")
+        .namespace(NAMESPACE)
+        .buckets(vec![
+            0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+        ]);
+    register(HistogramVec::new(opts, &["operation"]).expect("metric options are valid"))
+});
+
+/// Name of the message kind for `UPDATES`.
+pub fn message_kind(message: &Message) -> &'static str {
+    match &message.kind {
+        MessageKind::Common(common) => match common.media_kind {
+            MediaKind::Text(_) => "text",
+            MediaKind::Sticker(_) => "sticker",
+            MediaKind::Photo(_) => "photo",
+            MediaKind::Video(_) => "video",
+            MediaKind::Document(_) => "document",
+            MediaKind::Animation(_) => "animation",
+            MediaKind::Audio(_) => "audio",
+            MediaKind::Voice(_) => "voice",
+            _ => "other",
+        },
+        _ => "service",
+    }
+}
+
+/// Count failed Telegram API request.
+pub fn telegram_error(error: &RequestError) {
+    let kind = match error {
+        RequestError::ApiError { .. } => "api",
+        RequestError::MigrateToChatId(_) => "migrate",
+        RequestError::RetryAfter(_) => "retry_after",
+        RequestError::NetworkError(_) => "network",
+        RequestError::InvalidJson(_) => "invalid_json",
+        RequestError::Io(_) => "io",
+    };
+    TELEGRAM_ERRORS.with_label_values(&[kind]).inc();
+}
+
+/// Start timer of Redis operation, the duration is observed on drop.
+pub fn redis_timer(operation: &str) -> prometheus::HistogramTimer {
+    REDIS_LATENCY.with_label_values(&[operation]).start_timer()
+}
+
+/// All metrics in Prometheus text format.
+pub fn gather() -> String {
+    let mut buffer = vec![];
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather() {
+        UPDATES.with_label_values(&["text"]).inc();
+        ACTIVE_DIALOGUES.set(2);
+        let text = gather();
+        assert!(text.contains("tg_media_bot_updates_total{kind=\"text\"}"));
+        assert!(text.contains("tg_media_bot_active_dialogues 2"));
+    }
+}
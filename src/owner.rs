@@ -0,0 +1,448 @@
+//! Bot owner commands.
+//!
+//! Users listed in `OWNER_IDS` can manage the bot from a private chat:
+//! inspect chats, broadcast announcements, ban chats or users and turn
+//! maintenance mode on. Owners are never affected by bans or maintenance.
+
+use crate::commands::handle_list;
+use crate::db::{RedisStorageError, Storage};
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+use std::time::Duration;
+use teloxide::prelude::*;
+use teloxide::utils::command::BotCommand;
+use tokio::sync::Mutex;
+
+/// Environment variable with comma-separated user ids of the owners.
+pub const OWNERS_VAR: &str = "OWNER_IDS";
+
+/// Name of the bot setting storing whether maintenance mode is on.
+pub const MAINTENANCE_SETTING: &str = "maintenance";
+
+/// Delay between broadcast messages to stay within Telegram limits.
+const BROADCAST_DELAY: Duration = Duration::from_millis(50);
+
+static OWNERS: Lazy<Vec<i64>> = Lazy::new(|| match std::env::var(OWNERS_VAR) {
+    Ok(ids) => parse_ids(&ids),
+    Err(_) => vec![],
+});
+
+/// Parse comma-separated ids, invalid ones are skipped.
+fn parse_ids(ids: &str) -> Vec<i64> {
+    ids.split(',')
+        .filter_map(|id| match id.trim().parse() {
+            Ok(id) => Some(id),
+            Err(_) => {
+                tracing::warn!("Invalid owner id '{}'", id);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Whether the user is one of the bot owners.
+pub fn is_owner(user_id: Option<i64>) -> bool {
+    user_id.is_some_and(|id| OWNERS.contains(&id))
+}
+
+#[derive(BotCommand, Debug, PartialEq)]
+#[command(rename = "lowercase", description = "Owner commands:")]
+pub enum OwnerCommand {
+    #[command(description = "show this message")]
+    Owner,
+    #[command(description = "list known chats with alias counts")]
+    Chats,
+    #[command(description = "show aliases of the chat: /chataliases <chat>")]
+    ChatAliases(String),
+    #[command(description = "remove all aliases of the chat: /purgealiases <chat>")]
+    PurgeAliases(String),
+    #[command(description = "send announcement to all known chats: /broadcast <text>")]
+    Broadcast(String),
+    #[command(description = "ignore the chat or user: /ban <id>")]
+    Ban(String),
+    #[command(description = "stop ignoring the chat or user: /unban <id>")]
+    Unban(String),
+    #[command(description = "ignore everyone except owners: on or off")]
+    Maintenance(String),
+}
+
+/// Handle the message if it's an owner command sent by an owner in
+/// a private chat.
+///
+/// Returns whether the message was handled.
+pub async fn handle_owner_message(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> bool {
+    let text = match cx.update.text() {
+        Some(text) if text.starts_with('/') => text,
+        _ => return false,
+    };
+    if !cx.update.chat.is_private() || !is_owner(cx.update.from().map(|u| u.id)) {
+        return false;
+    }
+    let username = match cx.requester.get_me().await {
+        Ok(me) => me.user.username.unwrap_or_default(),
+        Err(e) => {
+            tracing::warn!("Could not get bot info: {}", e);
+            crate::metrics::telegram_error(&e);
+            return false;
+        }
+    };
+    let command = match OwnerCommand::parse(text, username) {
+        Ok(command) => command,
+        Err(_) => return false,
+    };
+    tracing::info!("Received owner command {:?}", command);
+    if let Err(e) = handle_owner_command(cx, command, db).await {
+        tracing::warn!("Could not handle owner command: {}", e);
+        crate::metrics::telegram_error(&e);
+    }
+    true
+}
+
+async fn handle_owner_command(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    command: OwnerCommand,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    match command {
+        OwnerCommand::Owner => {
+            cx.answer(OwnerCommand::descriptions()).await?;
+        }
+        OwnerCommand::Chats => {
+            let message = list_chats(db).await;
+            cx.answer(message).await?;
+        }
+        OwnerCommand::ChatAliases(chat) => match parse_id(&chat) {
+            Some(chat_id) => {
+                let aliases = db.lock().await.get_aliases(chat_id).await;
+                match aliases {
+                    Some(aliases) => handle_list(cx, aliases).await?,
+                    None => {
+                        cx.answer("Could not get aliases.").await?;
+                    }
+                }
+            }
+            None => {
+                cx.answer("Specify chat id: /chataliases <chat>").await?;
+            }
+        },
+        OwnerCommand::PurgeAliases(chat) => match parse_id(&chat) {
+            Some(chat_id) => {
+                let message = purge_aliases(&mut *db.lock().await, chat_id).await;
+                cx.answer(message).await?;
+            }
+            None => {
+                cx.answer("Specify chat id: /purgealiases <chat>").await?;
+            }
+        },
+        OwnerCommand::Broadcast(text) => {
+            if text.trim().is_empty() {
+                cx.answer("Specify announcement text: /broadcast <text>")
+                    .await?;
+                return Ok(());
+            }
+            let chats = reachable_chats(&mut *db.lock().await).await;
+            let chats = match chats {
+                Ok(chats) => chats,
+                Err(e) => {
+                    tracing::error!("Failed to get chats for broadcast: {}", e);
+                    cx.answer("Could not get chats.").await?;
+                    return Ok(());
+                }
+            };
+            let mut sent = 0;
+            for chat_id in &chats {
+                match cx.requester.send_message(*chat_id, text.trim()).await {
+                    Ok(_) => sent += 1,
+                    // Missed the migration message, move the data now.
+                    Err(teloxide::RequestError::MigrateToChatId(new_id)) => {
+                        tracing::info!("Chat {} was migrated to {}", chat_id, new_id);
+                        let result =
+                            crate::chats::migrate(&mut *db.lock().await, *chat_id, new_id).await;
+                        if let Err(e) = result {
+                            tracing::error!("Failed to migrate chat data: {}", e);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::warn!("Could not send announcement to {}: {}", chat_id, e);
+                        crate::metrics::telegram_error(&e);
+                    }
+                }
+                tokio::time::sleep(BROADCAST_DELAY).await;
+            }
+            cx.answer(format!("Sent to {}/{} chats.", sent, chats.len()))
+                .await?;
+        }
+        OwnerCommand::Ban(id) => {
+            let message = set_banned(&mut *db.lock().await, &id, true).await;
+            cx.answer(message).await?;
+        }
+        OwnerCommand::Unban(id) => {
+            let message = set_banned(&mut *db.lock().await, &id, false).await;
+            cx.answer(message).await?;
+        }
+        OwnerCommand::Maintenance(arg) => {
+            let message = maintenance(&mut *db.lock().await, &arg).await;
+            cx.answer(message).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Known chats except the ones the bot was removed from (their data is
+/// kept for a while, but messages can't be sent there).
+async fn reachable_chats(db: &mut dyn Storage) -> Result<Vec<i64>, RedisStorageError> {
+    let removed: Vec<i64> = db
+        .scan_removed()
+        .await?
+        .into_iter()
+        .map(|(chat_id, _)| chat_id)
+        .collect();
+    let mut chats = db.scan_chat_ids().await?;
+    chats.retain(|chat_id| !removed.contains(chat_id));
+    Ok(chats)
+}
+
+fn parse_id(id: &str) -> Option<i64> {
+    id.trim().parse().ok()
+}
+
+/// Known chats with their number of aliases.
+///
+/// The storage is locked per chat, so other updates are not blocked for the
+/// whole listing.
+async fn list_chats(db: &Arc<Mutex<dyn Storage>>) -> String {
+    let chats = match db.lock().await.scan_chat_ids().await {
+        Ok(chats) => chats,
+        Err(e) => {
+            tracing::error!("Failed to get chats: {}", e);
+            return String::from("Could not get chats.");
+        }
+    };
+    let mut message = format!("Known chats: {}\n", chats.len());
+    for chat_id in chats {
+        match db.lock().await.count_aliases(chat_id).await {
+            Ok(aliases) => message.push_str(&format!("{}: {} aliases\n", chat_id, aliases)),
+            Err(e) => {
+                tracing::error!("Failed to count aliases: {}", e);
+                message.push_str(&format!("{}: unknown number of aliases\n", chat_id));
+            }
+        }
+    }
+    message
+}
+
+async fn purge_aliases(db: &mut dyn Storage, chat_id: i64) -> String {
+    let aliases = match db.scan_aliases(chat_id).await {
+        Some(pairs) => pairs,
+        None => return String::from("Could not get aliases."),
+    };
+    let mut removed = 0;
+    for (alias, _) in &aliases {
+        match db.remove_alias(chat_id, alias).await {
+            Ok(()) | Err(RedisStorageError::AliasNotFound) => removed += 1,
+            Err(e) => tracing::error!("Failed to remove alias: {}", e),
+        }
+    }
+    format!(
+        "Removed {}/{} aliases of {}.",
+        removed,
+        aliases.len(),
+        chat_id
+    )
+}
+
+async fn set_banned(db: &mut dyn Storage, id: &str, banned: bool) -> String {
+    let id = match parse_id(id) {
+        Some(id) => id,
+        None => return String::from("Specify chat or user id: /ban <id>"),
+    };
+    match db.set_banned(id, banned).await {
+        Ok(()) if banned => format!("{} is banned.", id),
+        Ok(()) => format!("{} is unbanned.", id),
+        Err(e) => {
+            tracing::error!("Failed to change ban: {}", e);
+            String::from("Could not change the ban.")
+        }
+    }
+}
+
+async fn maintenance(db: &mut dyn Storage, arg: &str) -> String {
+    let on = match arg.trim() {
+        "" => {
+            let on = is_maintenance(db).await;
+            return format!("Maintenance mode is {}.", if on { "on" } else { "off" });
+        }
+        "on" => true,
+        "off" => false,
+        _ => return String::from("Use /maintenance on or /maintenance off."),
+    };
+    let value = if on { "on" } else { "off" };
+    match db.set_bot_setting(MAINTENANCE_SETTING, value).await {
+        Ok(()) => format!("Maintenance mode is {}.", value),
+        Err(e) => {
+            tracing::error!("Failed to set maintenance mode: {}", e);
+            String::from("Could not change maintenance mode.")
+        }
+    }
+}
+
+/// Check whether maintenance mode is on.
+pub async fn is_maintenance(db: &mut dyn Storage) -> bool {
+    match db.get_bot_setting(MAINTENANCE_SETTING).await {
+        Ok(value) => value.as_deref() == Some("on"),
+        Err(e) => {
+            tracing::error!("Failed to get maintenance mode: {}", e);
+            false
+        }
+    }
+}
+
+/// Message sent to private chats during maintenance.
+const MAINTENANCE_NOTICE: &str = "The bot is under maintenance, please try again later.";
+
+/// Check whether the update in the chat from the user should be handled.
+///
+/// Updates from banned chats or users are ignored, as well as all
+/// updates from non-owners during maintenance (private chats are
+/// notified about it). Storage errors don't block the bot.
+pub async fn check_access(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    from_id: Option<i64>,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> bool {
+    if is_owner(from_id) {
+        return true;
+    }
+    let mut db = db.lock().await;
+    for id in std::iter::once(cx.chat_id()).chain(from_id) {
+        match db.is_banned(id).await {
+            Ok(true) => {
+                tracing::info!("Ignoring update from banned {}", id);
+                return false;
+            }
+            Ok(false) => {}
+            Err(e) => tracing::error!("Failed to check ban: {}", e),
+        }
+    }
+    if !is_maintenance(&mut *db).await {
+        return true;
+    }
+    drop(db);
+    tracing::info!("Ignoring update during maintenance");
+    if cx.update.chat.is_private() {
+        if let Err(e) = cx.answer(MAINTENANCE_NOTICE).await {
+            tracing::warn!("Could not send maintenance notice: {}", e);
+            crate::metrics::telegram_error(&e);
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_parse_ids() {
+        assert_eq!(parse_ids("1, 2,x,-3"), vec![1, 2, -3]);
+        assert!(parse_ids("").is_empty());
+    }
+
+    /// Harness where `OWNER_ID` sent `text` in private chat.
+    async fn owner_sends(h: &Harness, text: &str) -> Vec<ApiCall> {
+        h.send(owner_message(1, text)).await
+    }
+
+    #[tokio::test]
+    async fn test_not_owner() {
+        let h = Harness::new().await;
+        let calls = h.send_text("/chats").await;
+        assert!(texts(&calls).iter().all(|t| !t.starts_with("Known chats")));
+    }
+
+    #[tokio::test]
+    async fn test_chats_and_purge() {
+        let h = Harness::new().await;
+        h.send_text("/add").await;
+        h.send(sticker_message(2, "sticker1")).await;
+        h.send_text("cry sad").await;
+
+        let calls = owner_sends(&h, "/chats").await;
+        assert_eq!(texts(&calls).len(), 1);
+        assert!(texts(&calls)[0].contains(&format!("{}: 2 aliases", CHAT_ID)));
+        // Owner commands are not passed to dialogues.
+        assert_eq!(methods(&calls), vec!["getMe", "sendMessage"]);
+
+        let calls = owner_sends(&h, &format!("/purgealiases {}", CHAT_ID)).await;
+        assert_eq!(
+            texts(&calls),
+            vec![format!("Removed 2/2 aliases of {}.", CHAT_ID)]
+        );
+        let aliases = h.db.lock().await.scan_aliases(CHAT_ID).await;
+        assert_eq!(aliases, Some(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_ban() {
+        let h = Harness::new().await;
+        owner_sends(&h, &format!("/ban {}", USER_ID)).await;
+        let calls = h.send_text("/help").await;
+        assert!(calls.is_empty());
+
+        owner_sends(&h, &format!("/unban {}", USER_ID)).await;
+        let calls = h.send_text("/help").await;
+        assert_eq!(texts(&calls).len(), 1);
+
+        // Menu presses are checked too.
+        let calls = h.send_text("/settings").await;
+        let keyboard = keyboard_message_id(&calls).unwrap();
+        owner_sends(&h, &format!("/ban {}", USER_ID)).await;
+        let calls = h
+            .press(crate::dialogue::CallbackData::Setting(1), keyboard)
+            .await;
+        assert_eq!(methods(&calls), vec!["answerCallbackQuery"]);
+        let settings = h.db.lock().await.get_settings(CHAT_ID).await.unwrap();
+        assert!(settings.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let h = Harness::new().await;
+        let calls = owner_sends(&h, "/maintenance on").await;
+        assert_eq!(texts(&calls), vec!["Maintenance mode is on."]);
+        let calls = h.send_text("/help").await;
+        assert_eq!(texts(&calls), vec![MAINTENANCE_NOTICE]);
+        // Owners can still use the bot.
+        let calls = owner_sends(&h, "/help").await;
+        assert_ne!(texts(&calls), vec![MAINTENANCE_NOTICE]);
+
+        owner_sends(&h, "/maintenance off").await;
+        let calls = h.send_text("/help").await;
+        assert_ne!(texts(&calls), vec![MAINTENANCE_NOTICE]);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let h = Harness::new().await;
+        h.send_text("hello").await;
+        // Removed chats are kept until the cleanup, but skipped.
+        h.send(group_message(1, USER_ID, "hello")).await;
+        h.db.lock()
+            .await
+            .set_removed(GROUP_ID, Some(0))
+            .await
+            .unwrap();
+        let calls = owner_sends(&h, "/broadcast News").await;
+        let sent: Vec<_> = calls
+            .iter()
+            .filter(|call| call.text() == Some("News"))
+            .map(|call| call.params["chat_id"].clone())
+            .collect();
+        assert_eq!(sent, vec![serde_json::json!(CHAT_ID)]);
+        assert!(texts(&calls).contains(&"Sent to 1/1 chats."));
+    }
+}
@@ -0,0 +1,677 @@
+//! Scheduled posts.
+//!
+//! `/schedule` saves a job posting aliases to the chat once at given time
+//! or repeatedly by a cron expression (times are in UTC). Jobs are kept in
+//! the storage, so they survive restarts: one-time posts missed while the
+//! bot was down are sent late, missed repeated ones are skipped.
+
+use crate::alias::{Delimiter, Target};
+use crate::db::{RedisStorageError, Storage};
+use crate::dialogue::{broken_notice, button, extract_targets, post_targets, CallbackData};
+use crate::settings::{can_change, ChatSettings, PERMISSION_NOTICE};
+use crate::template::TemplateContext;
+use chrono::{
+    DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::InlineKeyboardMarkup;
+use tokio::sync::Mutex;
+
+/// Interval between checks for due jobs.
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(20);
+
+/// Maximum number of scheduled jobs in a chat.
+const MAX_JOBS: usize = 20;
+
+/// How far cron expressions are searched for the next time, in days.
+///
+/// Enough for any day of month to happen, e.g. February 29.
+const MAX_SEARCH_DAYS: i64 = 8 * 366;
+
+/// Format of times in messages.
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M UTC";
+
+const USAGE: &str = "Use /schedule <time> <aliases...>, where time is HH:MM, \
+YYYY-MM-DD HH:MM (once, UTC) or a cron expression like \"0 10 * * fri\" (repeatedly).";
+
+/// An error of parsing a schedule.
+#[derive(PartialEq, Debug)]
+pub enum ScheduleError {
+    /// The text doesn't start with a time or a cron expression.
+    Invalid,
+    /// The time has already passed.
+    Passed,
+    /// The cron expression never matches.
+    Never,
+}
+
+impl std::fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScheduleError::Invalid => write!(f, "Unknown time format."),
+            ScheduleError::Passed => write!(f, "This time has already passed."),
+            ScheduleError::Never => write!(f, "This cron expression never happens."),
+        }
+    }
+}
+
+/// When a job posts its aliases.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum When {
+    /// Once at the unix timestamp.
+    Once(i64),
+    /// Repeatedly by the cron expression.
+    Cron(String),
+}
+
+impl When {
+    /// Parse the schedule at the start of `text`.
+    ///
+    /// Returns the schedule and the rest of the text. Time without a date
+    /// means its next occurrence after `now`.
+    pub fn parse(text: &str, now: DateTime<Utc>) -> Result<(When, &str), ScheduleError> {
+        if let Some((words, rest)) = split_words(text, 5) {
+            let expression = words.join(" ");
+            if let Ok(cron) = expression.parse::<Cron>() {
+                cron.next_after(now).ok_or(ScheduleError::Never)?;
+                return Ok((When::Cron(expression), rest));
+            }
+        }
+        if let Some((words, rest)) = split_words(text, 2) {
+            let time = NaiveDateTime::parse_from_str(&words.join(" "), "%Y-%m-%d %H:%M");
+            if let Ok(time) = time {
+                let time = Utc.from_utc_datetime(&time);
+                if time <= now {
+                    return Err(ScheduleError::Passed);
+                }
+                return Ok((When::Once(time.timestamp()), rest));
+            }
+        }
+        let (words, rest) = split_words(text, 1).ok_or(ScheduleError::Invalid)?;
+        let time =
+            NaiveTime::parse_from_str(words[0], "%H:%M").map_err(|_| ScheduleError::Invalid)?;
+        let mut time = Utc.from_utc_datetime(&now.naive_utc().date().and_time(time));
+        if time <= now {
+            time = time + Duration::days(1);
+        }
+        Ok((When::Once(time.timestamp()), rest))
+    }
+
+    /// First time the job posts after `time` (`None` if it never does).
+    pub fn next_after(&self, time: i64) -> Option<i64> {
+        match self {
+            When::Once(at) => (*at > time).then_some(*at),
+            When::Cron(expression) => {
+                let time = Utc.timestamp_opt(time, 0).single()?;
+                let next = expression.parse::<Cron>().ok()?.next_after(time)?;
+                Some(next.timestamp())
+            }
+        }
+    }
+
+    /// Human readable schedule.
+    fn describe(&self) -> String {
+        match self {
+            When::Once(at) => format!("once at {}", format_time(*at)),
+            When::Cron(expression) => format!("\"{}\"", expression),
+        }
+    }
+}
+
+/// Format unix timestamp for messages.
+fn format_time(time: i64) -> String {
+    Utc.timestamp(time, 0).format(TIME_FORMAT).to_string()
+}
+
+/// Split the first `n` words off the text.
+///
+/// Returns the words and the rest of the text (`None` if there are less
+/// than `n` words).
+fn split_words(text: &str, n: usize) -> Option<(Vec<&str>, &str)> {
+    let mut words = vec![];
+    let mut rest = text.trim_start();
+    for _ in 0..n {
+        if rest.is_empty() {
+            return None;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        words.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    Some((words, rest))
+}
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Cron expression: minute, hour, day of month, month and day of week.
+///
+/// Fields are sets of allowed values as bit masks.
+#[derive(PartialEq, Debug)]
+struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether both days of month and days of week are restricted, then
+    /// matching either of them is enough (like in cron).
+    any_day_field: bool,
+}
+
+impl std::str::FromStr for Cron {
+    type Err = ();
+
+    /// Parse fields of numbers (or names of months and days of week),
+    /// ranges, lists and steps, e.g. "*/15 9-18 * * mon-fri".
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(());
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7, &WEEKDAYS)?;
+        // Both 0 and 7 are Sunday.
+        if weekdays & 1 << 7 != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, &MONTHS)?,
+            weekdays,
+            any_day_field: !fields[2].starts_with('*') && !fields[4].starts_with('*'),
+        })
+    }
+}
+
+/// Parse cron field with values from `min` to `max` into a bit mask.
+///
+/// `names` are names of values starting from `min`.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, ()> {
+    let value = |value: &str| -> Result<u32, ()> {
+        if let Some(i) = names
+            .iter()
+            .position(|name| value.eq_ignore_ascii_case(name))
+        {
+            return Ok(min + i as u32);
+        }
+        match value.parse() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(()),
+        }
+    };
+    let mut mask = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(drop)?),
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // "5/15" means from 5 to the end with step 15.
+            None if item.contains('/') => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if step == 0 || start > end {
+            return Err(());
+        }
+        for value in (start..=end).step_by(step) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl Cron {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if self.months & 1 << date.month() == 0 {
+            return false;
+        }
+        let day = self.days & 1 << date.day() != 0;
+        let weekday = self.weekdays & 1 << date.weekday().num_days_from_sunday() != 0;
+        if self.any_day_field {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// First matching minute after `time`.
+    fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let first_date = start.naive_utc().date();
+        for day in 0..MAX_SEARCH_DAYS {
+            let date = first_date + Duration::days(day);
+            if !self.matches_date(date) {
+                continue;
+            }
+            let from = if day == 0 {
+                start.time()
+            } else {
+                NaiveTime::from_hms(0, 0, 0)
+            };
+            for hour in from.hour()..24 {
+                if self.hours & 1 << hour == 0 {
+                    continue;
+                }
+                let first_minute = if hour == from.hour() {
+                    from.minute()
+                } else {
+                    0
+                };
+                if let Some(minute) = (first_minute..60).find(|m| self.minutes & 1 << m != 0) {
+                    return Some(Utc.from_utc_datetime(&date.and_hms(hour, minute, 0)));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Scheduled post of aliases.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Job {
+    pub when: When,
+    /// Aliases posted by the job.
+    pub aliases: Vec<String>,
+    /// User who scheduled the job. The name for `{sender}` in snippets
+    /// is requested when posting, so it isn't stored.
+    #[serde(default)]
+    pub author_id: Option<i64>,
+}
+
+impl Job {
+    /// Text with the aliases as they are written in the chat.
+    fn text(&self, delimiter: Delimiter) -> String {
+        let aliases: Vec<String> = self
+            .aliases
+            .iter()
+            .map(|alias| delimiter.wrap(alias))
+            .collect();
+        aliases.join(" ")
+    }
+}
+
+/// Schedule a post of aliases in `arg` (`/schedule <time> <aliases...>`).
+pub async fn handle_schedule(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    arg: &str,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let now = Utc::now();
+    let (when, text) = match When::parse(arg, now) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            cx.answer(format!("{} {}", e, USAGE)).await?;
+            return Ok(());
+        }
+    };
+    let chat_id = cx.chat_id();
+    let settings = ChatSettings::load(&mut *db.lock().await, chat_id).await;
+    let (targets, unknown) = extract_targets(text, chat_id, &settings, db.clone()).await;
+    if !unknown.is_empty() {
+        let unknown: Vec<String> = unknown
+            .iter()
+            .map(|alias| settings.delimiter.wrap(alias))
+            .collect();
+        cx.answer(format!("Unknown aliases: {}.", unknown.join(", ")))
+            .await?;
+        return Ok(());
+    }
+    if targets.is_empty() {
+        cx.answer(format!("No aliases to post. {}", USAGE)).await?;
+        return Ok(());
+    }
+    let job = Job {
+        when,
+        aliases: targets.iter().map(|(alias, _)| alias.to_string()).collect(),
+        author_id: cx.update.from().map(|u| u.id),
+    };
+    let value = serde_json::to_string(&job).expect("job is serializable");
+
+    let mut db = db.lock().await;
+    let jobs = db.get_schedules(chat_id).await.map(|jobs| jobs.len());
+    let result = match jobs {
+        Ok(n) if n >= MAX_JOBS => {
+            let message = format!(
+                "The chat already has {} scheduled posts, cancel some of them with /schedules.",
+                n
+            );
+            cx.answer(message).await?;
+            return Ok(());
+        }
+        Ok(_) => db.add_schedule(chat_id, &value).await,
+        Err(e) => Err(e),
+    };
+    drop(db);
+    let message = match result {
+        Ok(id) => {
+            tracing::info!("Scheduled job {}", id);
+            let next = job
+                .when
+                .next_after(now.timestamp())
+                .map_or_else(String::new, |next| {
+                    format!(" Next post is at {}.", format_time(next))
+                });
+            format!(
+                "Scheduled post #{} of {} ({}).{}",
+                id,
+                job.text(settings.delimiter),
+                job.when.describe(),
+                next
+            )
+        }
+        Err(_) => String::from("Failed to schedule the post, try again later."),
+    };
+    cx.answer(message).await?;
+    Ok(())
+}
+
+/// List of scheduled jobs of the chat with buttons cancelling them.
+async fn jobs_message(
+    db: &mut dyn Storage,
+    chat_id: i64,
+) -> Result<(String, InlineKeyboardMarkup), RedisStorageError> {
+    let jobs = db.get_schedules(chat_id).await?;
+    let delimiter = ChatSettings::load(db, chat_id).await.delimiter;
+    let now = Utc::now().timestamp();
+    let mut keyboard = InlineKeyboardMarkup::default();
+    if jobs.is_empty() {
+        return Ok((
+            String::from("There are no scheduled posts. Add one with /schedule."),
+            keyboard,
+        ));
+    }
+    let mut text = String::from("Scheduled posts (press a button to cancel one):\n");
+    for (id, value) in jobs {
+        match serde_json::from_str::<Job>(&value) {
+            Ok(job) => {
+                text.push_str(&format!(
+                    "#{} {} - {}",
+                    id,
+                    job.text(delimiter),
+                    job.when.describe()
+                ));
+                if let (When::Cron(_), Some(next)) = (&job.when, job.when.next_after(now)) {
+                    text.push_str(&format!(", next at {}", format_time(next)));
+                }
+                text.push('\n');
+            }
+            Err(_) => text.push_str(&format!("#{} (unreadable)\n", id)),
+        }
+        let label = format!("Cancel #{}", id);
+        keyboard = keyboard.append_row(vec![button(&label, CallbackData::Unschedule(id))]);
+    }
+    Ok((text, keyboard))
+}
+
+/// Send the list of scheduled posts of the chat.
+pub async fn handle_schedules(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    db: &mut dyn Storage,
+) -> Result<(), teloxide::RequestError> {
+    match jobs_message(db, cx.chat_id()).await {
+        Ok((text, keyboard)) => cx.answer(text).reply_markup(keyboard).await?,
+        Err(_) => {
+            cx.answer("Failed to get scheduled posts, try again later.")
+                .await?
+        }
+    };
+    Ok(())
+}
+
+/// Handle press of the button cancelling job `id`.
+///
+/// Removes the job and updates the list.
+pub async fn handle_press(
+    bot: &AutoSend<Bot>,
+    query: &CallbackQuery,
+    id: u64,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let message = match &query.message {
+        Some(message) => message,
+        None => {
+            bot.answer_callback_query(query.id.clone()).await?;
+            return Ok(());
+        }
+    };
+    if !can_change(bot, &message.chat, Some(query.from.id), db).await {
+        bot.answer_callback_query(query.id.clone())
+            .text(PERMISSION_NOTICE)
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+
+    let chat_id = message.chat_id();
+    let mut db = db.lock().await;
+    let notice = match db.remove_schedule(chat_id, id).await {
+        Ok(()) => {
+            tracing::info!("Cancelled scheduled job {}", id);
+            format!("Cancelled #{}.", id)
+        }
+        Err(RedisStorageError::ScheduleNotFound) => format!("#{} is already cancelled.", id),
+        Err(_) => String::from("Failed to cancel the post, try again later."),
+    };
+    let list = jobs_message(&mut *db, chat_id).await;
+    drop(db);
+
+    bot.answer_callback_query(query.id.clone())
+        .text(notice)
+        .await?;
+    if let Ok((text, keyboard)) = list {
+        bot.edit_message_text(chat_id, message.id, text)
+            .reply_markup(keyboard)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Post aliases of the job to the chat.
+async fn post(bot: &AutoSend<Bot>, chat_id: i64, job: &Job, db: &Arc<Mutex<dyn Storage>>) {
+    let settings = ChatSettings::load(&mut *db.lock().await, chat_id).await;
+    let text = job.text(settings.delimiter);
+    let (mut targets, unknown) = extract_targets(&text, chat_id, &settings, db.clone()).await;
+    if !unknown.is_empty() {
+        tracing::info!("Skipping {} removed aliases", unknown.len());
+    }
+    if settings.max_media > 0 {
+        targets.truncate(settings.max_media);
+    }
+    let uses_sender = targets
+        .iter()
+        .any(|(_, target)| matches!(target, Target::Text(text) if text.contains("{sender}")));
+    let sender = match job.author_id {
+        Some(user_id) if uses_sender => author_name(bot, chat_id, user_id).await,
+        _ => String::new(),
+    };
+    let template_cx = TemplateContext::from_sender(sender);
+    let broken = post_targets(bot, chat_id, targets, None, &template_cx, db).await;
+    if !broken.is_empty() {
+        let notice = broken_notice(&broken, settings.delimiter);
+        if let Err(e) = bot
+            .send_message(chat_id, notice)
+            .disable_notification(true)
+            .await
+        {
+            tracing::warn!("Could not send broken media notice: {}", e);
+            crate::metrics::telegram_error(&e);
+        }
+    }
+}
+
+/// Current name of the user who scheduled a job in the chat.
+///
+/// Empty if it can't be requested.
+async fn author_name(bot: &AutoSend<Bot>, chat_id: i64, user_id: i64) -> String {
+    match bot.get_chat_member(chat_id, user_id).await {
+        Ok(member) => member.user.full_name(),
+        Err(e) => {
+            tracing::warn!("Could not get author of scheduled job: {}", e);
+            crate::metrics::telegram_error(&e);
+            String::new()
+        }
+    }
+}
+
+/// Post jobs due after `since` until `now` (unix timestamps).
+///
+/// One-time jobs are removed after posting. Returns number of posted jobs.
+pub async fn run_due(
+    bot: &AutoSend<Bot>,
+    db: &Arc<Mutex<dyn Storage>>,
+    since: i64,
+    now: i64,
+) -> Result<usize, RedisStorageError> {
+    let jobs = {
+        let mut db = db.lock().await;
+        if crate::owner::is_maintenance(&mut *db).await {
+            return Ok(0);
+        }
+        db.scan_schedules().await?
+    };
+    let mut posted = 0;
+    for (chat_id, id, value) in jobs {
+        let job: Job = match serde_json::from_str(&value) {
+            Ok(job) => job,
+            Err(e) => {
+                tracing::warn!("Skipping unreadable scheduled job {}: {}", id, e);
+                continue;
+            }
+        };
+        let due = match job.when {
+            When::Once(at) => at <= now,
+            When::Cron(_) => job.when.next_after(since).is_some_and(|next| next <= now),
+        };
+        if !due {
+            continue;
+        }
+        if db.lock().await.is_banned(chat_id).await? {
+            tracing::info!("Skipping scheduled job {} of a banned chat", id);
+        } else {
+            tracing::info!("Posting scheduled job {}", id);
+            post(bot, chat_id, &job, db).await;
+            posted += 1;
+        }
+        if let When::Once(_) = job.when {
+            match db.lock().await.remove_schedule(chat_id, id).await {
+                Ok(()) | Err(RedisStorageError::ScheduleNotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    Ok(posted)
+}
+
+/// Start posting scheduled jobs.
+pub fn start(bot: AutoSend<Bot>, db: Arc<Mutex<dyn Storage>>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        let mut since = Utc::now().timestamp();
+        loop {
+            interval.tick().await;
+            let now = Utc::now().timestamp();
+            match run_due(&bot, &db, since, now).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("Posted {} scheduled jobs", n),
+                Err(e) => tracing::error!("Failed to run scheduled jobs: {}", e),
+            }
+            since = now;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(text: &str) -> DateTime<Utc> {
+        Utc.from_utc_datetime(&NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap())
+    }
+
+    #[test]
+    fn test_parse_when() {
+        // Wednesday.
+        let now = time("2026-10-21 12:30");
+        assert_eq!(
+            When::parse("0 10 * * fri :meme:", now),
+            Ok((When::Cron("0 10 * * fri".to_owned()), ":meme:"))
+        );
+        assert_eq!(
+            When::parse("2026-10-22 09:00  :a: :b:", now),
+            Ok((When::Once(time("2026-10-22 09:00").timestamp()), ":a: :b:"))
+        );
+        assert_eq!(
+            When::parse("13:00 :a:", now),
+            Ok((When::Once(time("2026-10-21 13:00").timestamp()), ":a:"))
+        );
+        assert_eq!(
+            When::parse("12:30 :a:", now),
+            Ok((When::Once(time("2026-10-22 12:30").timestamp()), ":a:"))
+        );
+        assert_eq!(
+            When::parse("2026-10-20 09:00 :a:", now),
+            Err(ScheduleError::Passed)
+        );
+        assert_eq!(
+            When::parse("0 0 31 2 * :a:", now),
+            Err(ScheduleError::Never)
+        );
+        assert_eq!(
+            When::parse("tomorrow :a:", now),
+            Err(ScheduleError::Invalid)
+        );
+        assert_eq!(When::parse("", now), Err(ScheduleError::Invalid));
+    }
+
+    #[test]
+    fn test_cron() {
+        let next = |expression: &str, after: &str| {
+            expression
+                .parse::<Cron>()
+                .unwrap()
+                .next_after(time(after))
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+        };
+        let next = |expression, after| next(expression, after).unwrap();
+        assert_eq!(next("* * * * *", "2026-10-21 12:30"), "2026-10-21 12:31");
+        assert_eq!(next("*/15 * * * *", "2026-10-21 12:30"), "2026-10-21 12:45");
+        assert_eq!(next("0 10 * * fri", "2026-10-21 12:30"), "2026-10-23 10:00");
+        assert_eq!(next("0 10 * * 5", "2026-10-23 10:00"), "2026-10-30 10:00");
+        assert_eq!(
+            next("30 9-17/4 * * mon-fri", "2026-10-23 17:30"),
+            "2026-10-26 09:30"
+        );
+        assert_eq!(next("0 0 1,15 * *", "2026-10-21 12:30"), "2026-11-01 00:00");
+        assert_eq!(next("0 0 29 feb *", "2026-10-21 12:30"), "2028-02-29 00:00");
+        assert_eq!(next("0 12 * * 7", "2026-10-21 12:30"), "2026-10-25 12:00");
+        // Either day of month or day of week matches.
+        assert_eq!(next("0 0 13 * fri", "2026-10-21 12:30"), "2026-10-23 00:00");
+
+        for invalid in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+            "* * * * friday",
+        ] {
+            assert_eq!(invalid.parse::<Cron>(), Err(()), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words(" a  b c ", 2), Some((vec!["a", "b"], "c ")));
+        assert_eq!(split_words("a", 1), Some((vec!["a"], "")));
+        assert_eq!(split_words("a", 2), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Alias search.
+//!
+//! Matches aliases against partial or misspelled names.
+
+/// How an alias matched the searched text.
+///
+/// Variants are ordered from the best match to the worst one.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
+pub enum MatchKind {
+    Exact,
+    Prefix,
+    Substring,
+    /// Alias is within given edit distance from the searched text.
+    Fuzzy(usize),
+}
+
+/// Maximum edit distance for fuzzy matches of given text.
+///
+/// Grows with the text length, so short names don't match everything.
+fn max_distance(text: &str) -> usize {
+    std::cmp::max(1, text.chars().count() / 3)
+}
+
+/// Check how `alias` matches `query` (if it does).
+///
+/// Comparison is case-insensitive.
+pub fn match_alias(query: &str, alias: &str) -> Option<MatchKind> {
+    let query = query.to_lowercase();
+    let alias = alias.to_lowercase();
+    if alias == query {
+        Some(MatchKind::Exact)
+    } else if alias.starts_with(&query) {
+        Some(MatchKind::Prefix)
+    } else if alias.contains(&query) {
+        Some(MatchKind::Substring)
+    } else {
+        let distance = strsim::levenshtein(&query, &alias);
+        if distance <= max_distance(&query) {
+            Some(MatchKind::Fuzzy(distance))
+        } else {
+            None
+        }
+    }
+}
+
+/// Find aliases matching `query`.
+///
+/// Returns matched items sorted from the best match to the worst one,
+/// items with the same match quality are sorted by alias.
+pub fn find<'a, T, F>(query: &str, items: &'a [T], alias: F) -> Vec<&'a T>
+where
+    F: Fn(&T) -> &str,
+{
+    let mut matches: Vec<(MatchKind, &T)> = items
+        .iter()
+        .filter_map(|item| match_alias(query, alias(item)).map(|kind| (kind, item)))
+        .collect();
+    matches.sort_by(|(kind_a, a), (kind_b, b)| kind_a.cmp(kind_b).then(alias(a).cmp(alias(b))));
+    matches.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Suggest up to `limit` existing aliases similar to unknown `alias`.
+pub fn suggest<'a>(alias: &str, aliases: &'a [String], limit: usize) -> Vec<&'a str> {
+    find(alias, aliases, |a| a)
+        .into_iter()
+        .take(limit)
+        .map(String::as_str)
+        .collect()
+}
+
+/// Where suggestions for unknown aliases are sent.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum SuggestionMode {
+    /// Unknown aliases are silently ignored.
+    #[default]
+    Off,
+    /// Suggestions are sent as a silent reply in the chat.
+    Chat,
+    /// Suggestions are sent to the sender in private messages.
+    Private,
+}
+
+impl SuggestionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SuggestionMode::Off => "off",
+            SuggestionMode::Chat => "chat",
+            SuggestionMode::Private => "private",
+        }
+    }
+}
+
+impl std::str::FromStr for SuggestionMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(SuggestionMode::Off),
+            "chat" => Ok(SuggestionMode::Chat),
+            "private" => Ok(SuggestionMode::Private),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_match_alias() {
+        let cases = vec![
+            ("cry", "cry", Some(MatchKind::Exact)),
+            ("CRY", "cry", Some(MatchKind::Exact)),
+            ("cr", "crying", Some(MatchKind::Prefix)),
+            ("ryi", "crying", Some(MatchKind::Substring)),
+            ("cryng_ct", "crying_cat", Some(MatchKind::Fuzzy(2))),
+            ("cyr", "cry", None),
+            ("crz", "cry", Some(MatchKind::Fuzzy(1))),
+            ("cat", "dog", None),
+            ("😭", "😭😭", Some(MatchKind::Prefix)),
+        ];
+        for (query, alias, target) in cases {
+            assert_eq!(match_alias(query, alias), target, "{} / {}", query, alias);
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let items = vec!["crying", "cry", "scary", "cat", "dry"];
+        let found: Vec<&&str> = find("cry", &items, |s| s);
+        assert_eq!(found, vec![&"cry", &"crying", &"dry"]);
+    }
+
+    #[test]
+    fn test_suggest() {
+        let aliases: Vec<String> = vec!["crying", "cry", "dry", "cat"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert_eq!(suggest("cyr", &aliases, 3), Vec::<&str>::new());
+        assert_eq!(suggest("crz", &aliases, 3), vec!["cry"]);
+        assert_eq!(suggest("cr", &aliases, 1), vec!["cry"]);
+    }
+
+    #[test]
+    fn test_suggestion_mode_from_str() {
+        for mode in [
+            SuggestionMode::Off,
+            SuggestionMode::Chat,
+            SuggestionMode::Private,
+        ] {
+            assert_eq!(mode.as_str().parse(), Ok(mode));
+        }
+        assert_eq!(" Chat ".parse(), Ok(SuggestionMode::Chat));
+        assert_eq!("loud".parse::<SuggestionMode>(), Err(()));
+    }
+}
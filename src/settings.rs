@@ -0,0 +1,431 @@
+//! Chat settings.
+//!
+//! Settings of each chat are stored as fields of the chat settings hash
+//! and loaded into `ChatSettings` at once. Unset or invalid fields fall
+//! back to the defaults, which can be overridden with `DEFAULT_SETTINGS`.
+
+use crate::alias::{Delimiter, Matching};
+use crate::commands::{MATCHING_SETTING, REWRITE_SETTING, SUGGESTIONS_SETTING};
+use crate::db::Storage;
+use crate::dialogue::{button, CallbackData};
+use crate::search::SuggestionMode;
+use once_cell::sync::Lazy;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::{Chat, InlineKeyboardMarkup};
+use tokio::sync::Mutex;
+
+/// Environment variable overriding defaults, e.g. `max_media=5,reply=on`.
+pub const DEFAULTS_VAR: &str = "DEFAULT_SETTINGS";
+
+/// Largest allowed `max_media` value.
+const MAX_MEDIA_LIMIT: usize = 50;
+
+/// Who may change aliases and settings of the chat.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Permissions {
+    #[default]
+    Everyone,
+    /// Only chat administrators (and bot owners).
+    Admins,
+}
+
+impl Permissions {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permissions::Everyone => "everyone",
+            Permissions::Admins => "admins",
+        }
+    }
+}
+
+impl std::str::FromStr for Permissions {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "everyone" => Ok(Permissions::Everyone),
+            "admins" => Ok(Permissions::Admins),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Language of bot messages.
+///
+/// Only English is available for now.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Language {
+    #[default]
+    English,
+}
+
+impl Language {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Language::English => "en",
+        }
+    }
+}
+
+impl std::str::FromStr for Language {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "en" => Ok(Language::English),
+            _ => Err(()),
+        }
+    }
+}
+
+/// All settings of a chat.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ChatSettings {
+    pub delimiter: Delimiter,
+    pub permissions: Permissions,
+    /// Maximum number of targets sent for one message (0 - no limit).
+    pub max_media: usize,
+    /// Whether targets are sent as replies to the message with aliases.
+    pub reply: bool,
+    pub language: Language,
+    pub suggestions: SuggestionMode,
+    pub matching: Matching,
+    pub rewrite: bool,
+    /// Whether aliases in media captions are resolved.
+    pub captions: bool,
+    /// Whether aliases added by editing a message are resolved.
+    pub edits: bool,
+}
+
+impl Default for ChatSettings {
+    fn default() -> Self {
+        ChatSettings {
+            delimiter: Delimiter::default(),
+            permissions: Permissions::default(),
+            max_media: 0,
+            reply: false,
+            language: Language::default(),
+            suggestions: SuggestionMode::default(),
+            matching: Matching::default(),
+            rewrite: false,
+            captions: true,
+            edits: true,
+        }
+    }
+}
+
+/// Setting shown in the /settings menu.
+struct Setting {
+    /// Field name in the database.
+    name: &'static str,
+    title: &'static str,
+    /// Values the menu button cycles through.
+    values: &'static [&'static str],
+}
+
+const SETTINGS: [Setting; 10] = [
+    Setting {
+        name: "delimiter",
+        title: "Alias marks",
+        values: &["colon", "semicolon", "bracket"],
+    },
+    Setting {
+        name: "permissions",
+        title: "Who can change aliases",
+        values: &["everyone", "admins"],
+    },
+    Setting {
+        name: "max_media",
+        title: "Media per message",
+        values: &["unlimited", "1", "3", "5", "10"],
+    },
+    Setting {
+        name: "reply",
+        title: "Reply to messages",
+        values: &["off", "on"],
+    },
+    Setting {
+        name: "language",
+        title: "Language",
+        values: &["en"],
+    },
+    Setting {
+        name: SUGGESTIONS_SETTING,
+        title: "Suggestions",
+        values: &["off", "chat", "private"],
+    },
+    Setting {
+        name: MATCHING_SETTING,
+        title: "Matching",
+        values: &["exact", "ignorecase", "normalized"],
+    },
+    Setting {
+        name: REWRITE_SETTING,
+        title: "Rewrite messages",
+        values: &["off", "on"],
+    },
+    Setting {
+        name: "captions",
+        title: "Aliases in captions",
+        values: &["on", "off"],
+    },
+    Setting {
+        name: "edits",
+        title: "Aliases in edits",
+        values: &["on", "off"],
+    },
+];
+
+/// Parse "on" or "off" ("true" and "false" are kept from older versions).
+fn parse_switch(value: &str) -> Result<bool, ()> {
+    match value.trim().to_lowercase().as_str() {
+        "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        _ => Err(()),
+    }
+}
+
+fn switch(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+impl ChatSettings {
+    /// Get value of the setting `name` as it's stored.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "delimiter" => self.delimiter.as_str(),
+            "permissions" => self.permissions.as_str(),
+            "max_media" if self.max_media == 0 => "unlimited",
+            "max_media" => return Some(self.max_media.to_string()),
+            "reply" => switch(self.reply),
+            "language" => self.language.as_str(),
+            SUGGESTIONS_SETTING => self.suggestions.as_str(),
+            MATCHING_SETTING => self.matching.as_str(),
+            REWRITE_SETTING => switch(self.rewrite),
+            "captions" => switch(self.captions),
+            "edits" => switch(self.edits),
+            _ => return None,
+        };
+        Some(value.to_owned())
+    }
+
+    /// Set the setting `name` parsed from `value`.
+    ///
+    /// Fails if there is no such setting or the value is invalid.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ()> {
+        match name {
+            "delimiter" => self.delimiter = value.parse()?,
+            "permissions" => self.permissions = value.parse()?,
+            "max_media" => {
+                let max_media = match value.trim() {
+                    "unlimited" => 0,
+                    n => n.parse().map_err(drop)?,
+                };
+                if max_media > MAX_MEDIA_LIMIT {
+                    return Err(());
+                }
+                self.max_media = max_media;
+            }
+            "reply" => self.reply = parse_switch(value)?,
+            "language" => self.language = value.parse()?,
+            SUGGESTIONS_SETTING => self.suggestions = value.parse()?,
+            MATCHING_SETTING => self.matching = value.parse()?,
+            REWRITE_SETTING => self.rewrite = parse_switch(value)?,
+            "captions" => self.captions = parse_switch(value)?,
+            "edits" => self.edits = parse_switch(value)?,
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    /// Load settings of the chat.
+    ///
+    /// Falls back to the defaults if the storage failed.
+    pub async fn load(db: &mut dyn Storage, chat_id: i64) -> ChatSettings {
+        let mut settings = DEFAULTS.clone();
+        match db.get_settings(chat_id).await {
+            Ok(values) => {
+                for (name, value) in values {
+                    if settings.set(&name, &value).is_err() {
+                        tracing::warn!("Ignoring invalid setting {}={}", name, value);
+                    }
+                }
+            }
+            Err(e) => tracing::error!("Failed to get settings: {}", e),
+        }
+        settings
+    }
+
+    /// Menu with a button for each setting showing its value.
+    fn keyboard(&self) -> InlineKeyboardMarkup {
+        SETTINGS.iter().enumerate().fold(
+            InlineKeyboardMarkup::default(),
+            |keyboard, (i, setting)| {
+                let value = self.get(setting.name).unwrap_or_default();
+                let text = format!("{}: {}", setting.title, value);
+                keyboard.append_row(vec![button(&text, CallbackData::Setting(i))])
+            },
+        )
+    }
+}
+
+/// Parse defaults from `name=value` pairs separated by commas.
+fn parse_defaults(pairs: &str) -> ChatSettings {
+    let mut settings = ChatSettings::default();
+    for pair in pairs.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let result = match pair.split_once('=') {
+            Some((name, value)) => settings.set(name.trim(), value),
+            None => Err(()),
+        };
+        if result.is_err() {
+            tracing::warn!("Invalid default setting '{}'", pair);
+        }
+    }
+    settings
+}
+
+static DEFAULTS: Lazy<ChatSettings> = Lazy::new(|| match std::env::var(DEFAULTS_VAR) {
+    Ok(pairs) => parse_defaults(&pairs),
+    Err(_) => ChatSettings::default(),
+});
+
+/// Message sent when the user may not change the chat.
+pub const PERMISSION_NOTICE: &str = "Only administrators can change aliases and settings here.";
+
+/// Check whether the user may change aliases and settings in the chat.
+///
+/// Everyone can change them in private chats, otherwise it depends on
+/// the `permissions` setting. Bot owners are always allowed.
+pub async fn can_change(
+    bot: &AutoSend<Bot>,
+    chat: &Chat,
+    user_id: Option<i64>,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> bool {
+    if chat.is_private() || crate::owner::is_owner(user_id) {
+        return true;
+    }
+    let permissions = ChatSettings::load(&mut *db.lock().await, chat.id)
+        .await
+        .permissions;
+    let user_id = match (permissions, user_id) {
+        (Permissions::Everyone, _) => return true,
+        (Permissions::Admins, Some(user_id)) => user_id,
+        (Permissions::Admins, None) => return false,
+    };
+    is_admin(bot, chat.id, user_id).await
+}
+
+/// Check whether the user is an administrator of the chat.
+pub async fn is_admin(bot: &AutoSend<Bot>, chat_id: i64, user_id: i64) -> bool {
+    match bot.get_chat_member(chat_id, user_id).await {
+        Ok(member) => member.kind.is_privileged(),
+        Err(e) => {
+            tracing::warn!("Could not get chat member: {}", e);
+            crate::metrics::telegram_error(&e);
+            false
+        }
+    }
+}
+
+/// Send the settings menu of the chat.
+pub async fn handle_settings(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    db: &mut dyn Storage,
+) -> Result<(), teloxide::RequestError> {
+    let settings = ChatSettings::load(db, cx.chat_id()).await;
+    cx.answer("Chat settings. Press a button to change the value.")
+        .reply_markup(settings.keyboard())
+        .await?;
+    Ok(())
+}
+
+/// Handle press of the settings menu button `index`.
+///
+/// Switches the setting to its next value and updates the menu.
+pub async fn handle_press(
+    bot: &AutoSend<Bot>,
+    query: &CallbackQuery,
+    index: usize,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let (message, setting) = match (&query.message, SETTINGS.get(index)) {
+        (Some(message), Some(setting)) => (message, setting),
+        _ => {
+            bot.answer_callback_query(query.id.clone()).await?;
+            return Ok(());
+        }
+    };
+    if !can_change(bot, &message.chat, Some(query.from.id), db).await {
+        bot.answer_callback_query(query.id.clone())
+            .text(PERMISSION_NOTICE)
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+
+    let chat_id = message.chat_id();
+    let mut db = db.lock().await;
+    let mut settings = ChatSettings::load(&mut *db, chat_id).await;
+    let current = settings.get(setting.name).unwrap_or_default();
+    let next = match setting.values.iter().position(|v| *v == current) {
+        Some(i) => setting.values[(i + 1) % setting.values.len()],
+        None => setting.values[0],
+    };
+    tracing::info!("Changing setting {} to {}", setting.name, next);
+    settings
+        .set(setting.name, next)
+        .expect("menu values are valid");
+    let saved = db.set_setting(chat_id, setting.name, next).await.is_ok();
+    drop(db);
+
+    if saved {
+        bot.answer_callback_query(query.id.clone()).await?;
+        bot.edit_message_reply_markup(chat_id, message.id)
+            .reply_markup(settings.keyboard())
+            .await?;
+    } else {
+        bot.answer_callback_query(query.id.clone())
+            .text("Failed to save the setting, try again later.")
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_set() {
+        let mut settings = ChatSettings::default();
+        for setting in &SETTINGS {
+            for value in setting.values {
+                assert_eq!(settings.set(setting.name, value), Ok(()));
+                assert_eq!(settings.get(setting.name).as_deref(), Some(*value));
+            }
+        }
+        assert_eq!(settings.set("max_media", "7"), Ok(()));
+        assert_eq!(settings.max_media, 7);
+        assert_eq!(settings.set("max_media", "1000"), Err(()));
+        assert_eq!(settings.max_media, 7);
+        assert_eq!(settings.set("rewrite", "true"), Ok(()));
+        assert!(settings.rewrite);
+        assert_eq!(settings.set("reply", "maybe"), Err(()));
+        assert_eq!(settings.set("unknown", "on"), Err(()));
+    }
+
+    #[test]
+    fn test_parse_defaults() {
+        let settings = parse_defaults("max_media=5, reply=on,invalid,delimiter=bracket");
+        assert_eq!(settings.max_media, 5);
+        assert!(settings.reply);
+        assert_eq!(settings.delimiter, Delimiter::Bracket);
+        assert_eq!(parse_defaults(""), ChatSettings::default());
+    }
+}
@@ -0,0 +1,97 @@
+//! Text snippet templates.
+//!
+//! Fills placeholders of text snippets with details of the message
+//! that used the alias.
+
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+/// Known placeholders.
+static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{(sender|date|reply)\}").unwrap());
+
+/// Values of template placeholders.
+pub struct TemplateContext {
+    /// Name of the message sender, for `{sender}`.
+    pub sender: String,
+    /// Current date, for `{date}`.
+    pub date: String,
+    /// Name of the author of the replied message, for `{reply}`.
+    pub reply: Option<String>,
+}
+
+impl TemplateContext {
+    /// Collect placeholder values from the message.
+    pub fn from_message(message: &teloxide::types::Message) -> Self {
+        TemplateContext {
+            reply: message
+                .reply_to_message()
+                .and_then(|m| m.from())
+                .map(|u| u.full_name()),
+            ..TemplateContext::from_sender(
+                message.from().map(|u| u.full_name()).unwrap_or_default(),
+            )
+        }
+    }
+
+    /// Placeholder values for a post without a message (not a reply).
+    pub fn from_sender(sender: String) -> Self {
+        TemplateContext {
+            sender,
+            date: chrono::offset::Local::today()
+                .format("%Y-%m-%d")
+                .to_string(),
+            reply: None,
+        }
+    }
+}
+
+/// Fill placeholders of `template`.
+///
+/// Unknown placeholders are left as is, `{reply}` is removed if the
+/// message is not a reply. Placeholders are filled in one pass, so
+/// values containing placeholders are not expanded.
+pub fn render(template: &str, cx: &TemplateContext) -> String {
+    PLACEHOLDER
+        .replace_all(template, |caps: &Captures| match &caps[1] {
+            "sender" => cx.sender.as_str(),
+            "date" => cx.date.as_str(),
+            _ => cx.reply.as_deref().unwrap_or_default(),
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let cx = TemplateContext {
+            sender: "Alice".to_owned(),
+            date: "2022-01-12".to_owned(),
+            reply: None,
+        };
+        let cases = vec![
+            ("plain", "plain"),
+            ("{sender} at {date}", "Alice at 2022-01-12"),
+            ("hi {reply}!", "hi !"),
+            ("{unknown} {sender}", "{unknown} Alice"),
+        ];
+        for (template, target) in cases {
+            assert_eq!(render(template, &cx), target);
+        }
+        let cx = TemplateContext {
+            reply: Some("Bob".to_owned()),
+            ..cx
+        };
+        assert_eq!(render("hi {reply}!", &cx), "hi Bob!");
+
+        // Values are not expanded again.
+        let cx = TemplateContext {
+            sender: "{date}".to_owned(),
+            reply: Some("{sender}".to_owned()),
+            ..cx
+        };
+        assert_eq!(render("{sender} {reply}", &cx), "{date} {sender}");
+    }
+}
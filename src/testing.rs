@@ -0,0 +1,456 @@
+//! Offline test harness.
+//!
+//! Runs handlers against a local fake Telegram Bot API server and
+//! in-memory storage. Requests the bot makes are recorded, so tests can
+//! assert on sent messages and resulting dialogue states.
+
+use crate::db::{MemoryStorage, Storage};
+use crate::dialogue::{CallbackData, Dialogue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use serde_json::{json, Map, Value};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use teloxide::prelude::*;
+use tokio::sync::Mutex;
+
+/// Chat used by tests unless specified.
+pub const CHAT_ID: i64 = 100;
+/// Group chat for tests of group-only behaviour.
+pub const GROUP_ID: i64 = -100;
+/// User sending messages in tests unless specified.
+pub const USER_ID: i64 = 200;
+/// Administrator of every group chat (other users are members).
+pub const ADMIN_ID: i64 = 400;
+/// Bot owner (listed in `OWNER_IDS` by `Harness`).
+pub const OWNER_ID: i64 = 300;
+/// Prefix of file ids the fake API refuses to send like Telegram refuses
+/// files which are no longer available.
+pub const DEAD_FILE_PREFIX: &str = "dead-";
+/// File id the fake API gives to uploaded stickers.
+pub const UPLOADED_FILE_ID: &str = "uploaded";
+/// Message the fake API refuses to delete like messages the bot has no
+/// rights for.
+pub const UNDELETABLE_MESSAGE_ID: i32 = 13;
+/// Username of the bot returned by fake `getMe`.
+pub const BOT_USERNAME: &str = "test_bot";
+
+/// Request made by the bot to the fake API.
+#[derive(Clone, Debug)]
+pub struct ApiCall {
+    /// Bot API method, e.g. `sendMessage`.
+    pub method: String,
+    pub params: Map<String, Value>,
+    /// Id of the message sent by the request (if any).
+    pub message_id: Option<i32>,
+}
+
+impl ApiCall {
+    /// Text of sent message (if any).
+    pub fn text(&self) -> Option<&str> {
+        self.params.get("text").and_then(Value::as_str)
+    }
+}
+
+/// Recorded requests and id of the next sent message.
+#[derive(Default)]
+struct ApiState {
+    calls: std::sync::Mutex<Vec<ApiCall>>,
+    next_message_id: AtomicI32,
+}
+
+/// Fake Telegram Bot API server.
+pub struct FakeApi {
+    url: String,
+    state: Arc<ApiState>,
+}
+
+impl FakeApi {
+    /// Start the server on a free local port.
+    pub async fn start() -> FakeApi {
+        let state = Arc::new(ApiState {
+            next_message_id: AtomicI32::new(1000),
+            ..ApiState::default()
+        });
+        let service_state = state.clone();
+        let make_service = make_service_fn(move |_| {
+            let state = service_state.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    handle_api_request(request, state.clone())
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+        FakeApi { url, state }
+    }
+
+    /// Bot sending requests to this server.
+    pub fn bot(&self) -> AutoSend<Bot> {
+        let url = reqwest::Url::parse(&self.url).expect("server address is a valid url");
+        Bot::new("TOKEN").set_api_url(url).auto_send()
+    }
+
+    /// Take requests made since the last call.
+    pub fn take_calls(&self) -> Vec<ApiCall> {
+        std::mem::take(&mut *self.state.calls.lock().unwrap())
+    }
+}
+
+/// Record the request and respond like Telegram would.
+async fn handle_api_request(
+    request: Request<Body>,
+    state: Arc<ApiState>,
+) -> Result<Response<Body>, Infallible> {
+    if let Some(file_path) = request.uri().path().strip_prefix("/file/botTOKEN/") {
+        return Ok(Response::new(Body::from(file_content(file_path))));
+    }
+    // teloxide names methods in PascalCase, Bot API docs in camelCase.
+    let method = request.uri().path().rsplit('/').next().unwrap_or_default();
+    let mut chars = method.chars();
+    let method: String = chars
+        .next()
+        .map(|c| c.to_ascii_lowercase())
+        .into_iter()
+        .chain(chars)
+        .collect();
+    let content_type = request
+        .headers()
+        .get(hyper::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_owned();
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .unwrap_or_default();
+    let body = String::from_utf8_lossy(&body);
+    let multipart = content_type.starts_with("multipart/form-data");
+    let params = if multipart {
+        parse_multipart(&body)
+    } else {
+        serde_json::from_str(&body).unwrap_or_default()
+    };
+
+    let message = |params: &Map<String, Value>| {
+        let chat_id = params
+            .get("chat_id")
+            .and_then(Value::as_i64)
+            .unwrap_or(CHAT_ID);
+        let mut message = json!({
+            "message_id": state.next_message_id.fetch_add(1, Ordering::Relaxed),
+            "date": 0,
+            "chat": chat(chat_id),
+            "from": {"id": 1, "is_bot": true, "first_name": "Bot", "username": BOT_USERNAME},
+            "text": "",
+        });
+        if let Some(text) = params.get("text") {
+            message["text"] = text.clone();
+        }
+        message
+    };
+    let dead_file = method.starts_with("send")
+        && params.iter().any(|(name, value)| {
+            name != "text" && value.as_str().is_some_and(|v| v.contains(DEAD_FILE_PREFIX))
+        });
+    let undeletable = method == "deleteMessage"
+        && params.get("message_id").and_then(Value::as_i64) == Some(UNDELETABLE_MESSAGE_ID.into());
+    if dead_file || undeletable {
+        let description = if dead_file {
+            "Bad Request: wrong file identifier/HTTP URL specified"
+        } else {
+            "Bad Request: message can't be deleted"
+        };
+        state.calls.lock().unwrap().push(ApiCall {
+            method,
+            params,
+            message_id: None,
+        });
+        let response = json!({
+            "ok": false,
+            "error_code": 400,
+            "description": description,
+        });
+        return Ok(Response::new(Body::from(response.to_string())));
+    }
+    let result = match method.as_str() {
+        "getMe" => json!({
+            "id": 1,
+            "is_bot": true,
+            "first_name": "Bot",
+            "username": BOT_USERNAME,
+            "can_join_groups": true,
+            "can_read_all_group_messages": false,
+            "supports_inline_queries": false,
+        }),
+        // Files are downloaded by their ids.
+        "getFile" => {
+            let file_id = params
+                .get("file_id")
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            json!({
+                "file_id": file_id,
+                "file_unique_id": format!("unique-{}", file_id),
+                "file_size": file_content(file_id).len(),
+                "file_path": file_id,
+            })
+        }
+        // Uploaded files get new ids.
+        "sendSticker" if multipart => {
+            let mut message = message(&params);
+            message["sticker"] = json!({
+                "file_id": UPLOADED_FILE_ID,
+                "file_unique_id": format!("unique-{}", UPLOADED_FILE_ID),
+                "width": 512,
+                "height": 512,
+                "is_animated": false,
+            });
+            if let Some(message) = message.as_object_mut() {
+                message.remove("text");
+            }
+            message
+        }
+        "sendMediaGroup" => {
+            let n = params
+                .get("media")
+                .and_then(Value::as_str)
+                .and_then(|media| serde_json::from_str::<Vec<Value>>(media).ok())
+                .map_or(1, |media| media.len());
+            Value::Array((0..n).map(|_| message(&params)).collect())
+        }
+        "getChatMember" => {
+            let user_id = params
+                .get("user_id")
+                .and_then(Value::as_i64)
+                .unwrap_or(USER_ID);
+            json!({
+                "user": {"id": user_id, "is_bot": false, "first_name": "User"},
+                "status": if user_id == ADMIN_ID { "administrator" } else { "member" },
+                "can_be_edited": false,
+                "can_manage_chat": true,
+                "can_change_info": true,
+                "can_delete_messages": true,
+                "can_manage_voice_chats": true,
+                "can_invite_users": true,
+                "can_restrict_members": true,
+                "can_pin_messages": true,
+                "can_promote_members": false,
+                "is_anonymous": false,
+            })
+        }
+        m if m.starts_with("send") || m.starts_with("edit") => message(&params),
+        _ => Value::Bool(true),
+    };
+    let message_id = result
+        .get("message_id")
+        .and_then(Value::as_i64)
+        .map(|id| id as i32);
+    state.calls.lock().unwrap().push(ApiCall {
+        method,
+        params,
+        message_id,
+    });
+    let response = json!({"ok": true, "result": result}).to_string();
+    Ok(Response::new(Body::from(response)))
+}
+
+/// Contents of the file downloaded from the fake API by its path.
+pub fn file_content(file_path: &str) -> String {
+    format!("content of {}", file_path)
+}
+
+/// Parse fields of `multipart/form-data` body.
+///
+/// Numbers are parsed, everything else is kept as string.
+fn parse_multipart(body: &str) -> Map<String, Value> {
+    let mut params = Map::new();
+    for part in body.split("\r\n--") {
+        let (headers, value) = match part.split_once("\r\n\r\n") {
+            Some(split) => split,
+            None => continue,
+        };
+        let name = match headers
+            .split("name=\"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+        {
+            Some(name) => name,
+            None => continue,
+        };
+        let value = value.trim_end_matches("\r\n");
+        let value = match value.parse::<i64>() {
+            Ok(number) => Value::from(number),
+            Err(_) => Value::String(value.to_owned()),
+        };
+        params.insert(name.to_owned(), value);
+    }
+    params
+}
+
+/// JSON of the chat with given id (private if positive, group otherwise).
+fn chat(chat_id: i64) -> Value {
+    if chat_id > 0 {
+        json!({"id": chat_id, "type": "private", "first_name": "User"})
+    } else {
+        json!({"id": chat_id, "type": "group", "title": "Group"})
+    }
+}
+
+/// JSON of a message from `USER_ID` in `CHAT_ID` with given content.
+///
+/// `content` fields (e.g. `text` or `sticker`) are added to the message.
+pub fn message_json(message_id: i32, content: Value) -> Value {
+    let mut message = json!({
+        "message_id": message_id,
+        "date": 0,
+        "chat": chat(CHAT_ID),
+        "from": {"id": USER_ID, "is_bot": false, "first_name": "User"},
+    });
+    if let (Some(message), Value::Object(content)) = (message.as_object_mut(), content) {
+        message.extend(content);
+    }
+    message
+}
+
+/// Text message from `USER_ID` in `CHAT_ID`.
+pub fn text_message(message_id: i32, text: &str) -> Message {
+    serde_json::from_value(message_json(message_id, json!({ "text": text })))
+        .expect("message json is valid")
+}
+
+/// Text message from `OWNER_ID` in private chat with the bot.
+pub fn owner_message(message_id: i32, text: &str) -> Message {
+    let mut message = message_json(message_id, json!({ "text": text }));
+    message["chat"] = chat(OWNER_ID);
+    message["from"] = json!({"id": OWNER_ID, "is_bot": false, "first_name": "Owner"});
+    serde_json::from_value(message).expect("message json is valid")
+}
+
+/// Text message from `user_id` in group chat `GROUP_ID`.
+pub fn group_message(message_id: i32, user_id: i64, text: &str) -> Message {
+    let mut message = message_json(message_id, json!({ "text": text }));
+    message["chat"] = chat(GROUP_ID);
+    message["from"] = json!({"id": user_id, "is_bot": false, "first_name": "User"});
+    serde_json::from_value(message).expect("message json is valid")
+}
+
+/// Sticker message from `USER_ID` in `CHAT_ID`.
+pub fn sticker_message(message_id: i32, file_id: &str) -> Message {
+    let sticker = json!({
+        "file_id": file_id,
+        "file_unique_id": format!("unique-{}", file_id),
+        "width": 512,
+        "height": 512,
+        "is_animated": false,
+    });
+    serde_json::from_value(message_json(message_id, json!({ "sticker": sticker })))
+        .expect("message json is valid")
+}
+
+/// Bot and storage for driving handlers.
+pub struct Harness {
+    pub api: FakeApi,
+    pub bot: AutoSend<Bot>,
+    pub db: Arc<Mutex<dyn Storage>>,
+}
+
+impl Harness {
+    pub async fn new() -> Harness {
+        // Owners are read once, every test sets the same value before that.
+        std::env::set_var(crate::owner::OWNERS_VAR, OWNER_ID.to_string());
+        let api = FakeApi::start().await;
+        let bot = api.bot();
+        Harness {
+            api,
+            bot,
+            db: Arc::new(Mutex::new(MemoryStorage::default())),
+        }
+    }
+
+    /// Handle `message` as a new message and return requests it caused.
+    pub async fn send(&self, message: Message) -> Vec<ApiCall> {
+        let cx = UpdateWithCx {
+            requester: self.bot.clone(),
+            update: message,
+        };
+        crate::handle_message(cx, self.db.clone()).await;
+        self.api.take_calls()
+    }
+
+    /// Handle text message.
+    pub async fn send_text(&self, text: &str) -> Vec<ApiCall> {
+        self.send(text_message(1, text)).await
+    }
+
+    /// Handle `message` as an edited message.
+    pub async fn edit(&self, message: Message) -> Vec<ApiCall> {
+        let cx = UpdateWithCx {
+            requester: self.bot.clone(),
+            update: message,
+        };
+        crate::handle_edited_message(cx, self.db.clone()).await;
+        self.api.take_calls()
+    }
+
+    /// Press button with `data` on the keyboard of message `message_id`.
+    pub async fn press(&self, data: CallbackData, message_id: i32) -> Vec<ApiCall> {
+        let mut keyboard_message = message_json(message_id, json!({"text": "keyboard"}));
+        keyboard_message["from"] = json!({"id": 1, "is_bot": true, "first_name": "Bot"});
+        let query = json!({
+            "id": "query",
+            "from": {"id": USER_ID, "is_bot": false, "first_name": "User"},
+            "message": keyboard_message,
+            "chat_instance": "instance",
+            "data": data.to_string(),
+        });
+        let cx = UpdateWithCx {
+            requester: self.bot.clone(),
+            update: serde_json::from_value(query).expect("callback query json is valid"),
+        };
+        crate::handle_callback_query(cx, self.db.clone()).await;
+        self.api.take_calls()
+    }
+
+    /// Name of the stored dialogue state of `USER_ID` (`None` if not stored).
+    pub async fn state(&self) -> Option<&'static str> {
+        let mut db = self.db.lock().await;
+        db.get_dialogue(CHAT_ID, Some(USER_ID))
+            .await
+            .expect("stored dialogue is valid")
+            .map(|dialogue| dialogue.state_name())
+    }
+
+    /// Store dialogue state of `USER_ID`.
+    pub async fn set_state(&self, dialogue: Dialogue) {
+        let mut db = self.db.lock().await;
+        db.update_dialogue(CHAT_ID, Some(USER_ID), &dialogue)
+            .await
+            .expect("memory storage does not fail");
+    }
+}
+
+/// Texts of sent messages.
+pub fn texts(calls: &[ApiCall]) -> Vec<&str> {
+    calls
+        .iter()
+        .filter(|call| call.method == "sendMessage")
+        .filter_map(ApiCall::text)
+        .collect()
+}
+
+/// Id of the last sent message with an inline keyboard.
+pub fn keyboard_message_id(calls: &[ApiCall]) -> Option<i32> {
+    calls
+        .iter()
+        .rev()
+        .find(|call| call.params.contains_key("reply_markup"))
+        .and_then(|call| call.message_id)
+}
+
+/// Bot API methods of `calls`.
+pub fn methods(calls: &[ApiCall]) -> Vec<&str> {
+    calls.iter().map(|call| call.method.as_str()).collect()
+}
@@ -0,0 +1,538 @@
+//! Keyword triggers.
+//!
+//! Besides aliases marked with the delimiter, a chat can define triggers
+//! with `/trigger`: when a message contains a word or a phrase (or matches
+//! a regex), the bot sends the target of an alias. A trigger may fire only
+//! with some probability and not more often than its cooldown.
+
+use crate::alias::{Delimiter, Matching};
+use crate::db::{RedisStorageError, Storage};
+use crate::dialogue::{button, extract_targets, CallbackData};
+use crate::settings::{can_change, ChatSettings, PERMISSION_NOTICE};
+use once_cell::sync::Lazy;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use teloxide::prelude::*;
+use teloxide::types::InlineKeyboardMarkup;
+use tokio::sync::Mutex;
+
+/// Maximum number of triggers in a chat.
+const MAX_TRIGGERS: usize = 50;
+
+/// Maximum length of a trigger pattern, in characters.
+const MAX_PATTERN_LENGTH: usize = 200;
+
+/// Maximum size of a compiled regex, in bytes.
+const MAX_REGEX_SIZE: usize = 1 << 20;
+
+const USAGE: &str = "Use /trigger <word, phrase or /regex/> <alias> [chance%] [cooldown], \
+e.g. /trigger good morning :coffee: 50% 10m.";
+
+/// An error of parsing a trigger.
+#[derive(PartialEq, Debug)]
+pub enum TriggerError {
+    /// The text is not a pattern followed by an alias.
+    Invalid,
+    /// The pattern is longer than `MAX_PATTERN_LENGTH`.
+    TooLong,
+    /// The chance is not a percentage from 1 to 100.
+    Chance,
+    /// The regex doesn't compile.
+    Regex(String),
+}
+
+impl std::fmt::Display for TriggerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TriggerError::Invalid => write!(f, "Unknown trigger format."),
+            TriggerError::TooLong => write!(
+                f,
+                "The pattern is too long, it may have at most {} characters.",
+                MAX_PATTERN_LENGTH
+            ),
+            TriggerError::Chance => write!(f, "Chance must be from 1% to 100%."),
+            TriggerError::Regex(e) => write!(f, "Invalid regex: {}", e),
+        }
+    }
+}
+
+/// What a message has to contain to fire a trigger.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    /// Whole words separated by any whitespace, ignoring case.
+    Words(String),
+    /// Regular expression matching any part of the message.
+    Regex(String),
+}
+
+impl Pattern {
+    /// Parse the pattern, `/.../` is a regex.
+    fn parse(text: &str) -> Result<Pattern, TriggerError> {
+        if text.chars().count() > MAX_PATTERN_LENGTH {
+            return Err(TriggerError::TooLong);
+        }
+        let pattern = match text.strip_prefix('/').and_then(|r| r.strip_suffix('/')) {
+            Some(regex) if !regex.is_empty() => Pattern::Regex(regex.to_owned()),
+            _ => {
+                let words: Vec<String> = text.split_whitespace().map(str::to_lowercase).collect();
+                Pattern::Words(words.join(" "))
+            }
+        };
+        pattern.regex()?;
+        Ok(pattern)
+    }
+
+    /// Compiled pattern.
+    fn regex(&self) -> Result<Regex, TriggerError> {
+        let pattern = match self {
+            Pattern::Words(words) => {
+                let words: Vec<String> = words.split(' ').map(regex::escape).collect();
+                // Not `\b`, so words may start or end with punctuation.
+                format!(r"(?i)(?:^|\W){}(?:\W|$)", words.join(r"\s+"))
+            }
+            Pattern::Regex(regex) => regex.clone(),
+        };
+        RegexBuilder::new(&pattern)
+            .size_limit(MAX_REGEX_SIZE)
+            .build()
+            .map_err(|e| TriggerError::Regex(e.to_string()))
+    }
+
+    /// Pattern as it's written in `/trigger`.
+    fn describe(&self) -> String {
+        match self {
+            Pattern::Words(words) => format!("\"{}\"", words),
+            Pattern::Regex(regex) => format!("/{}/", regex),
+        }
+    }
+}
+
+/// Rule sending the target of an alias in reply to matching messages.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Trigger {
+    pub pattern: Pattern,
+    /// Alias whose target is sent.
+    pub alias: String,
+    /// Probability of firing for a matching message, in percent.
+    pub chance: u8,
+    /// Minimum time between firings, in seconds.
+    pub cooldown: i64,
+}
+
+impl Trigger {
+    /// Parse `<pattern> <alias> [chance%] [cooldown]`.
+    ///
+    /// The alias must be marked with `delimiter`. Options may go in any
+    /// order, cooldown is a number followed by `s`, `m`, `h` or `d`.
+    pub fn parse(text: &str, delimiter: Delimiter) -> Result<Trigger, TriggerError> {
+        let mut rest = text.trim();
+        let mut chance = None;
+        let mut cooldown = None;
+        let alias = loop {
+            let (head, word) = match rest.rsplit_once(char::is_whitespace) {
+                Some((head, word)) => (head.trim_end(), word),
+                None => return Err(TriggerError::Invalid),
+            };
+            rest = head;
+            if let Some(percent) = word.strip_suffix('%') {
+                match percent.parse() {
+                    Ok(percent @ 1..=100) if chance.is_none() => chance = Some(percent),
+                    _ => return Err(TriggerError::Chance),
+                }
+            } else if let Some(seconds) = parse_duration(word).filter(|_| cooldown.is_none()) {
+                cooldown = Some(seconds);
+            } else {
+                break unwrap_alias(word, delimiter).ok_or(TriggerError::Invalid)?;
+            }
+        };
+        Ok(Trigger {
+            pattern: Pattern::parse(rest)?,
+            alias: alias.to_owned(),
+            chance: chance.unwrap_or(100),
+            cooldown: cooldown.unwrap_or(0),
+        })
+    }
+
+    /// Human readable trigger.
+    fn describe(&self, delimiter: Delimiter) -> String {
+        let mut text = format!(
+            "{} -> {}",
+            self.pattern.describe(),
+            delimiter.wrap(&self.alias)
+        );
+        if self.chance < 100 {
+            text.push_str(&format!(", {}% chance", self.chance));
+        }
+        if self.cooldown > 0 {
+            text.push_str(&format!(", cooldown {}", format_duration(self.cooldown)));
+        }
+        text
+    }
+}
+
+/// Compiled patterns of triggers by chat and trigger id.
+static REGEXES: Lazy<std::sync::Mutex<HashMap<(i64, u64), Regex>>> = Lazy::new(Default::default);
+
+/// Compiled pattern of trigger `id` of the chat.
+///
+/// Patterns are compiled once and kept until triggers of the chat change.
+fn compiled(chat_id: i64, id: u64, pattern: &Pattern) -> Option<Regex> {
+    let mut regexes = REGEXES.lock().unwrap();
+    match regexes.entry((chat_id, id)) {
+        Entry::Occupied(entry) => Some(entry.get().clone()),
+        Entry::Vacant(entry) => pattern
+            .regex()
+            .ok()
+            .map(|regex| entry.insert(regex).clone()),
+    }
+}
+
+/// Forget compiled patterns of triggers of the chat.
+fn invalidate(chat_id: i64) {
+    REGEXES
+        .lock()
+        .unwrap()
+        .retain(|(chat, _), _| *chat != chat_id);
+}
+
+/// Alias marked with the delimiter in `word` (`None` if it's not one alias).
+fn unwrap_alias(word: &str, delimiter: Delimiter) -> Option<&str> {
+    let regex = Regex::new(&format!("^{}$", delimiter.pattern())).ok()?;
+    regex.captures(word)?.get(1).map(|m| m.as_str())
+}
+
+const DURATION_UNITS: [(char, i64); 4] = [('d', 24 * 60 * 60), ('h', 60 * 60), ('m', 60), ('s', 1)];
+
+/// Parse duration like "30s", "10m", "2h" or "1d" into seconds.
+fn parse_duration(text: &str) -> Option<i64> {
+    let unit = text.chars().last()?;
+    let (_, seconds) = DURATION_UNITS.iter().find(|(name, _)| *name == unit)?;
+    let number: i64 = text[..text.len() - 1].parse().ok()?;
+    (number >= 0).then_some(number)?.checked_mul(*seconds)
+}
+
+/// Format seconds in the largest unit they are a multiple of.
+fn format_duration(seconds: i64) -> String {
+    let (unit, size) = DURATION_UNITS
+        .iter()
+        .find(|(_, size)| seconds % size == 0)
+        .expect("every duration is a multiple of a second");
+    format!("{}{}", seconds / size, unit)
+}
+
+/// Add a trigger described by `arg` (`/trigger <pattern> <alias> ...`).
+pub async fn handle_trigger(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    arg: &str,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let chat_id = cx.chat_id();
+    let settings = ChatSettings::load(&mut *db.lock().await, chat_id).await;
+    let trigger = match Trigger::parse(arg, settings.delimiter) {
+        Ok(trigger) => trigger,
+        Err(e) => {
+            cx.answer(format!("{} {}", e, USAGE)).await?;
+            return Ok(());
+        }
+    };
+    let alias = settings.delimiter.wrap(&trigger.alias);
+    let (targets, _) = extract_targets(&alias, chat_id, &settings, db.clone()).await;
+    if targets.is_empty() {
+        cx.answer(format!("Unknown aliases: {}.", alias)).await?;
+        return Ok(());
+    }
+    let value = serde_json::to_string(&trigger).expect("trigger is serializable");
+
+    let mut db = db.lock().await;
+    let triggers = db
+        .get_triggers(chat_id)
+        .await
+        .map(|triggers| triggers.len());
+    let result = match triggers {
+        Ok(n) if n >= MAX_TRIGGERS => {
+            let message = format!(
+                "The chat already has {} triggers, delete some of them with /triggers.",
+                n
+            );
+            cx.answer(message).await?;
+            return Ok(());
+        }
+        Ok(_) => db.add_trigger(chat_id, &value).await,
+        Err(e) => Err(e),
+    };
+    drop(db);
+    invalidate(chat_id);
+    let message = match result {
+        Ok(id) => {
+            tracing::info!("Added trigger {}", id);
+            format!(
+                "Added trigger #{}: {}.",
+                id,
+                trigger.describe(settings.delimiter)
+            )
+        }
+        Err(_) => String::from("Failed to add the trigger, try again later."),
+    };
+    cx.answer(message).await?;
+    Ok(())
+}
+
+/// List of triggers of the chat with buttons deleting them.
+async fn triggers_message(
+    db: &mut dyn Storage,
+    chat_id: i64,
+) -> Result<(String, InlineKeyboardMarkup), RedisStorageError> {
+    let triggers = db.get_triggers(chat_id).await?;
+    let delimiter = ChatSettings::load(db, chat_id).await.delimiter;
+    let mut keyboard = InlineKeyboardMarkup::default();
+    if triggers.is_empty() {
+        return Ok((
+            String::from("There are no triggers. Add one with /trigger."),
+            keyboard,
+        ));
+    }
+    let mut text = String::from("Triggers (press a button to delete one):\n");
+    for (id, value) in triggers {
+        match serde_json::from_str::<Trigger>(&value) {
+            Ok(trigger) => text.push_str(&format!("#{} {}\n", id, trigger.describe(delimiter))),
+            Err(_) => text.push_str(&format!("#{} (unreadable)\n", id)),
+        }
+        let label = format!("Delete #{}", id);
+        keyboard = keyboard.append_row(vec![button(&label, CallbackData::Untrigger(id))]);
+    }
+    Ok((text, keyboard))
+}
+
+/// Send the list of triggers of the chat.
+pub async fn handle_triggers(
+    cx: &UpdateWithCx<AutoSend<Bot>, Message>,
+    db: &mut dyn Storage,
+) -> Result<(), teloxide::RequestError> {
+    match triggers_message(db, cx.chat_id()).await {
+        Ok((text, keyboard)) => cx.answer(text).reply_markup(keyboard).await?,
+        Err(_) => {
+            cx.answer("Failed to get triggers, try again later.")
+                .await?
+        }
+    };
+    Ok(())
+}
+
+/// Handle press of the button deleting trigger `id`.
+///
+/// Removes the trigger and updates the list.
+pub async fn handle_press(
+    bot: &AutoSend<Bot>,
+    query: &CallbackQuery,
+    id: u64,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Result<(), teloxide::RequestError> {
+    let message = match &query.message {
+        Some(message) => message,
+        None => {
+            bot.answer_callback_query(query.id.clone()).await?;
+            return Ok(());
+        }
+    };
+    if !can_change(bot, &message.chat, Some(query.from.id), db).await {
+        bot.answer_callback_query(query.id.clone())
+            .text(PERMISSION_NOTICE)
+            .show_alert(true)
+            .await?;
+        return Ok(());
+    }
+
+    let chat_id = message.chat_id();
+    let mut db = db.lock().await;
+    let notice = match db.remove_trigger(chat_id, id).await {
+        Ok(()) => {
+            tracing::info!("Deleted trigger {}", id);
+            format!("Deleted #{}.", id)
+        }
+        Err(RedisStorageError::TriggerNotFound) => format!("#{} is already deleted.", id),
+        Err(_) => String::from("Failed to delete the trigger, try again later."),
+    };
+    let list = triggers_message(&mut *db, chat_id).await;
+    drop(db);
+    invalidate(chat_id);
+
+    bot.answer_callback_query(query.id.clone())
+        .text(notice)
+        .await?;
+    if let Ok((text, keyboard)) = list {
+        bot.edit_message_text(chat_id, message.id, text)
+            .reply_markup(keyboard)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Find aliases of triggers of the chat fired by the text.
+///
+/// A matching trigger fires with its chance, unless it already fired
+/// within its cooldown before `now` or its alias doesn't exist anymore.
+/// Aliases are returned in the order of triggers, without repetitions.
+pub async fn fire(
+    text: &str,
+    chat_id: i64,
+    now: i64,
+    matching: Matching,
+    db: &Arc<Mutex<dyn Storage>>,
+) -> Vec<String> {
+    let triggers = match db.lock().await.get_triggers(chat_id).await {
+        Ok(triggers) => triggers,
+        Err(e) => {
+            tracing::error!("Failed to get triggers: {}", e);
+            return vec![];
+        }
+    };
+    // Triggers can also be removed with the chat, e.g. by /purge.
+    REGEXES
+        .lock()
+        .unwrap()
+        .retain(|(chat, id), _| *chat != chat_id || triggers.iter().any(|(t, _)| t == id));
+    let mut aliases: Vec<String> = vec![];
+    for (id, value) in triggers {
+        let trigger = match serde_json::from_str::<Trigger>(&value) {
+            Ok(trigger) => trigger,
+            Err(e) => {
+                tracing::warn!("Skipping unreadable trigger {}: {}", id, e);
+                continue;
+            }
+        };
+        if aliases.contains(&trigger.alias) {
+            continue;
+        }
+        if !compiled(chat_id, id, &trigger.pattern).is_some_and(|regex| regex.is_match(text)) {
+            continue;
+        }
+        // Rolled first, so a miss doesn't start the cooldown.
+        if fastrand::u8(0..100) >= trigger.chance {
+            continue;
+        }
+        let mut db = db.lock().await;
+        // Aliases added before matching was changed are stored as is.
+        let exists = db.get_target(chat_id, &trigger.alias).await.is_some()
+            || db
+                .get_target(chat_id, &matching.normalize(&trigger.alias))
+                .await
+                .is_some();
+        if !exists {
+            tracing::debug!("Alias of trigger {} doesn't exist", id);
+            continue;
+        }
+        match db.fire_trigger(chat_id, id, now, trigger.cooldown).await {
+            Ok(true) => {
+                tracing::info!("Trigger {} fired", id);
+                aliases.push(trigger.alias);
+            }
+            Ok(false) => tracing::debug!("Trigger {} is on cooldown", id),
+            Err(e) => tracing::error!("Failed to fire trigger {}: {}", id, e),
+        }
+    }
+    aliases
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(words: &str) -> Pattern {
+        Pattern::Words(words.to_owned())
+    }
+
+    #[test]
+    fn test_parse_trigger() {
+        let trigger = |pattern, alias: &str, chance, cooldown| Trigger {
+            pattern,
+            alias: alias.to_owned(),
+            chance,
+            cooldown,
+        };
+        let cases = vec![
+            (
+                "Good   Morning :coffee:",
+                Ok(trigger(words("good morning"), "coffee", 100, 0)),
+            ),
+            (
+                "hi :wave: 50% 10m",
+                Ok(trigger(words("hi"), "wave", 50, 600)),
+            ),
+            ("hi :wave: 2h 1%", Ok(trigger(words("hi"), "wave", 1, 7200))),
+            (
+                "/^(hello|hi)!/ :wave: 1d",
+                Ok(trigger(
+                    Pattern::Regex("^(hello|hi)!".to_owned()),
+                    "wave",
+                    100,
+                    86400,
+                )),
+            ),
+            // Only the last alias is the target.
+            (":a: :b:", Ok(trigger(words(":a:"), "b", 100, 0))),
+            ("hi", Err(TriggerError::Invalid)),
+            (":wave:", Err(TriggerError::Invalid)),
+            ("hi wave", Err(TriggerError::Invalid)),
+            ("hi :wave: 10m 20m", Err(TriggerError::Invalid)),
+            ("hi :wave: 0%", Err(TriggerError::Chance)),
+            ("hi :wave: 101%", Err(TriggerError::Chance)),
+            ("hi :wave: 5% 10%", Err(TriggerError::Chance)),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(Trigger::parse(text, Delimiter::Colon), expected, "{}", text);
+        }
+        assert!(matches!(
+            Trigger::parse("/(/ :wave:", Delimiter::Colon),
+            Err(TriggerError::Regex(_))
+        ));
+        let long = format!("{} :wave:", "a".repeat(MAX_PATTERN_LENGTH + 1));
+        assert_eq!(
+            Trigger::parse(&long, Delimiter::Colon),
+            Err(TriggerError::TooLong)
+        );
+        assert_eq!(
+            Trigger::parse("hi [wave]", Delimiter::Bracket).map(|t| t.alias),
+            Ok("wave".to_owned())
+        );
+    }
+
+    fn matches(trigger: &Trigger, text: &str) -> bool {
+        trigger.pattern.regex().unwrap().is_match(text)
+    }
+
+    #[test]
+    fn test_matches() {
+        let trigger = |text| Trigger::parse(text, Delimiter::Colon).unwrap();
+        let morning = trigger("good morning :coffee:");
+        assert!(matches(&morning, "Good morning!"));
+        assert!(matches(&morning, "well, GOOD\nmorning everyone"));
+        assert!(!matches(&morning, "goodmorning"));
+        assert!(!matches(&morning, "good mornings"));
+        let hi = trigger("hi! :wave:");
+        assert!(matches(&hi, "hi!"));
+        assert!(!matches(&hi, "hi"));
+        assert!(!matches(&hi, "chi!"));
+        assert!(matches(&trigger("привет :wave:"), "Привет, мир"));
+        let regex = trigger("/^\\d+$/ :number:");
+        assert!(matches(&regex, "42"));
+        assert!(!matches(&regex, "42 apples"));
+    }
+
+    #[test]
+    fn test_durations() {
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("10m"), Some(600));
+        assert_eq!(parse_duration("0h"), Some(0));
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("-1m"), None);
+        assert_eq!(parse_duration("10"), None);
+        assert_eq!(parse_duration("99999999999999999d"), None);
+        assert_eq!(format_duration(90), "90s");
+        assert_eq!(format_duration(7200), "2h");
+        assert_eq!(format_duration(86400 * 3), "3d");
+    }
+}
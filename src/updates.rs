@@ -0,0 +1,149 @@
+//! Update listener.
+//!
+//! Long polling like the default `teloxide` listener, except that updates
+//! `teloxide` fails to parse are fixed when possible instead of dropped.
+
+use futures::stream::{self, StreamExt};
+use serde_json::Value;
+use std::time::Duration;
+use teloxide::dispatching::update_listeners::{StatefulListener, UpdateListener};
+use teloxide::payloads::GetUpdates;
+use teloxide::prelude::*;
+use teloxide::requests::HasPayload;
+use teloxide::types::{AllowedUpdate, SemiparsedVec, Update};
+use teloxide::RequestError;
+
+/// Long polling timeout, in seconds.
+const POLLING_TIMEOUT: u32 = 10;
+
+/// Delay before polling again after a failed request.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Updates handled by the bot (must match handlers of the dispatcher).
+const ALLOWED_UPDATES: [AllowedUpdate; 4] = [
+    AllowedUpdate::Message,
+    AllowedUpdate::EditedMessage,
+    AllowedUpdate::CallbackQuery,
+    AllowedUpdate::MyChatMember,
+];
+
+/// Get listener polling updates of the bot.
+///
+/// Deletes the webhook if it was set up.
+pub async fn polling(bot: AutoSend<Bot>) -> impl UpdateListener<RequestError> {
+    if let Err(e) = bot.delete_webhook().await {
+        tracing::error!("Failed to delete a webhook: {}", e);
+    }
+    let updates = stream::unfold((bot, 0), |(bot, offset)| async move {
+        let mut request = bot.inner().get_updates_fault_tolerant();
+        request.payload_mut().0 = GetUpdates {
+            offset: Some(offset),
+            limit: None,
+            timeout: Some(POLLING_TIMEOUT),
+            allowed_updates: Some(ALLOWED_UPDATES.to_vec()),
+        };
+        let (updates, offset) = match request.send().await {
+            Ok(SemiparsedVec(updates)) => parse_updates(updates, offset),
+            Err(e) => {
+                tokio::time::sleep(RETRY_DELAY).await;
+                (vec![Err(e)], offset)
+            }
+        };
+        Some((stream::iter(updates), (bot, offset)))
+    })
+    .flatten();
+    StatefulListener::from_stream_without_graceful_shutdown(Box::pin(updates))
+}
+
+/// Fix updates `teloxide` failed to parse and get offset of the next batch.
+fn parse_updates(
+    updates: Vec<Result<Update, (Value, serde_json::Error)>>,
+    mut offset: i32,
+) -> (Vec<Result<Update, RequestError>>, i32) {
+    let mut parsed = vec![];
+    for update in updates {
+        let update = match update {
+            Ok(update) => update,
+            Err((mut value, e)) => {
+                let id = value["update_id"]
+                    .as_i64()
+                    .and_then(|id| id.try_into().ok());
+                offset = offset.max(id.map_or(offset, |id: i32| id + 1));
+                fix_migration(&mut value);
+                match serde_json::from_value(value.clone()) {
+                    Ok(update) => update,
+                    Err(_) => {
+                        tracing::error!("Cannot parse an update: {}. Value: {}", e, value);
+                        continue;
+                    }
+                }
+            }
+        };
+        offset = offset.max(update.id + 1);
+        parsed.push(Ok(update));
+    }
+    (parsed, offset)
+}
+
+/// Add the missing half of a chat migration message.
+///
+/// Telegram sends `migrate_to_chat_id` to the old group and
+/// `migrate_from_chat_id` to the new supergroup, but `teloxide` expects
+/// both of them. The missing one is the id of the chat itself.
+fn fix_migration(update: &mut Value) -> Option<()> {
+    let message = update.get_mut("message")?.as_object_mut()?;
+    let chat_id = message.get("chat")?.get("id")?.as_i64()?;
+    let fields = ["migrate_to_chat_id", "migrate_from_chat_id"];
+    match fields.map(|field| message.contains_key(field)) {
+        [true, false] => message.insert(fields[1].to_owned(), chat_id.into()),
+        [false, true] => message.insert(fields[0].to_owned(), chat_id.into()),
+        _ => None,
+    };
+    Some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn migration(chat_id: i64, field: &str, id: i64) -> Value {
+        json!({
+            "update_id": 5,
+            "message": {
+                "message_id": 1,
+                "date": 0,
+                "chat": {"id": chat_id, "type": "group", "title": "Group"},
+                "from": {"id": 2, "is_bot": false, "first_name": "User"},
+                field: id,
+            }
+        })
+    }
+
+    #[test]
+    fn test_parse_updates() {
+        let failed = |value: Value| {
+            let e = serde_json::from_value::<Update>(value.clone()).unwrap_err();
+            Err((value, e))
+        };
+        let (updates, offset) = parse_updates(
+            vec![
+                failed(migration(-1, "migrate_to_chat_id", -1001)),
+                failed(migration(-1001, "migrate_from_chat_id", -1)),
+                failed(json!({"update_id": 7, "unknown": {}})),
+            ],
+            3,
+        );
+        assert_eq!(offset, 8);
+        assert_eq!(updates.len(), 2);
+        for update in updates {
+            match update.unwrap().kind {
+                teloxide::types::UpdateKind::Message(message) => {
+                    assert_eq!(message.migrate_to_chat_id(), Some(-1001));
+                    assert_eq!(message.migrate_from_chat_id(), Some(-1));
+                }
+                kind => panic!("unexpected update {:?}", kind),
+            }
+        }
+    }
+}